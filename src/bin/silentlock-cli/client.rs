@@ -0,0 +1,506 @@
+//! Commands that work with files on a running server.
+
+use clap::ValueEnum;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+use silentlock::encryption::{decrypt_stream, encrypt_stream, FileMetadata, Identity, Recipient};
+use silentlock::models::{DecryptRequest, FileInfo, FileResponse, ListFilesResponse};
+
+use crate::offline::{file_path, read_passphrase, with_output, OutputKind};
+use crate::{AlgorithmArg, CliError, DownloadArgs, FormatArg, RemoteArgs, RemoteCommand, RemoteDecryptArgs, UploadArgs};
+
+/// Environment variable that supplies the API token
+const TOKEN_ENV: &str = "SILENTLOCK_TOKEN";
+
+/// Environment variable that points at a config file in place of the default one
+const CONFIG_ENV: &str = "SILENTLOCK_CONFIG";
+
+/// Server used when neither the command line nor the config file names one
+const DEFAULT_SERVER: &str = "http://127.0.0.1:8080";
+
+/// Layout of the progress bar for transfers of known size
+const BAR_TEMPLATE: &str = "{bar:40} {bytes}/{total_bytes} {binary_bytes_per_sec} eta {eta}";
+
+/// Layout of the progress indicator for transfers of unknown size
+const SPINNER_TEMPLATE: &str = "{spinner} {bytes} {binary_bytes_per_sec}";
+
+/// Settings read from the config file
+#[derive(Default, Deserialize)]
+struct Config {
+    /// Server URL, overridden by `--server` and `SILENTLOCK_SERVER`
+    server: Option<String>,
+    
+    /// API token, overridden by `SILENTLOCK_TOKEN`
+    token: Option<String>,
+}
+
+impl Config {
+    /// Reads the config file, if there is one
+    ///
+    /// The file is `$SILENTLOCK_CONFIG` if set, otherwise
+    /// `silentlock/config.toml` under `$XDG_CONFIG_HOME` or `~/.config`. Only
+    /// a file named explicitly has to exist.
+    fn load() -> Result<Self, CliError> {
+        let (path, explicit) = match std::env::var_os(CONFIG_ENV) {
+            Some(path) => (PathBuf::from(path), true),
+            None => {
+                let config_home = std::env::var_os("XDG_CONFIG_HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+                match config_home {
+                    Some(config_home) => (config_home.join("silentlock").join("config.toml"), false),
+                    None => return Ok(Self::default()),
+                }
+            }
+        };
+        
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !explicit => return Ok(Self::default()),
+            Err(e) => return Err(CliError::Usage(format!("{}: {}", path.display(), e))),
+        };
+        
+        toml::from_str(&contents)
+            .map_err(|e| CliError::Usage(format!("{}: {}", path.display(), e)))
+    }
+}
+
+/// HTTP client for one server
+struct Client {
+    /// Agent shared by all requests
+    agent: ureq::Agent,
+    
+    /// Base URL without a trailing slash
+    server: String,
+    
+    /// Token sent as a bearer token with every request
+    token: Option<String>,
+}
+
+impl Client {
+    /// Creates a client from the command line, the environment and the config file
+    fn new(server: Option<String>) -> Result<Self, CliError> {
+        let config = Config::load()?;
+        
+        let server = server
+            .or(config.server)
+            .unwrap_or_else(|| DEFAULT_SERVER.to_string());
+        let token = std::env::var(TOKEN_ENV)
+            .ok()
+            .filter(|token| !token.is_empty())
+            .or(config.token);
+        
+        // Transfers can take as long as they need once connected
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(10))
+            .build();
+        
+        Ok(Self {
+            agent,
+            server: server.trim_end_matches('/').to_string(),
+            token,
+        })
+    }
+    
+    /// Starts a request to an API path
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self.agent.request(method, &format!("{}{}", self.server, path));
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+    
+    /// Starts the download of a file as stored
+    fn download(&self, file_id: &str) -> Result<ureq::Response, CliError> {
+        Ok(self.request("GET", &format!("/api/files/download/{}", file_id)).call()?)
+    }
+    
+    /// Uploads a file as a multipart form and returns the stored file's info
+    fn send_file(
+        &self,
+        endpoint: &str,
+        query: &[(&str, String)],
+        path: &Path,
+        filename: &str,
+    ) -> Result<FileInfo, CliError> {
+        let file = File::open(path)
+            .map_err(|e| CliError::Usage(format!("{}: {}", path.display(), e)))?;
+        let size = file.metadata()?.len();
+        
+        // Stream the file between the form headers, so it is never held in memory
+        let boundary = format!("silentlock-{}", Uuid::new_v4().simple());
+        let filename = filename.replace(['"', '\r', '\n'], "_");
+        let head = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary, filename,
+        );
+        let tail = format!("\r\n--{}--\r\n", boundary);
+        let length = head.len() as u64 + size + tail.len() as u64;
+        
+        let mut request = self.request("POST", endpoint)
+            .set("Content-Type", &format!("multipart/form-data; boundary={}", boundary))
+            .set("Content-Length", &length.to_string());
+        for (name, value) in query {
+            request = request.query(name, value);
+        }
+        
+        let bar = progress(Some(size));
+        let body = Cursor::new(head).chain(bar.wrap_read(file)).chain(Cursor::new(tail));
+        let response = request.send(body);
+        bar.finish_and_clear();
+        
+        let response: FileResponse = read_json(response?)?;
+        response.file.ok_or(CliError::Response(response.message))
+    }
+}
+
+impl From<ureq::Error> for CliError {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(status, response) => {
+                // Error bodies are short plain-text messages from the handlers
+                let message = response.into_string().unwrap_or_default();
+                let message = message.trim();
+                if message.is_empty() {
+                    CliError::Server(status, "no details".to_string())
+                } else {
+                    CliError::Server(status, message.chars().take(500).collect())
+                }
+            },
+            ureq::Error::Transport(e) => CliError::Transport(e.to_string()),
+        }
+    }
+}
+
+/// Deletes a temporary file when dropped
+struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Creates an empty file in the system's temporary directory
+    fn create() -> Result<(Self, File), CliError> {
+        let path = std::env::temp_dir().join(format!("silentlock-{}.slck", Uuid::new_v4()));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        Ok((Self(path), file))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Progress bar on stderr, drawn only when stderr is a terminal
+fn progress(length: Option<u64>) -> ProgressBar {
+    let (bar, template) = match length {
+        Some(length) => (ProgressBar::new(length), BAR_TEMPLATE),
+        None => (ProgressBar::new_spinner(), SPINNER_TEMPLATE),
+    };
+    bar.with_style(ProgressStyle::with_template(template).expect("progress template is valid"))
+}
+
+/// Parses a JSON response body
+fn read_json<T: for<'de> Deserialize<'de>>(response: ureq::Response) -> Result<T, CliError> {
+    serde_json::from_reader(response.into_reader())
+        .map_err(|e| CliError::Response(e.to_string()))
+}
+
+/// Prints a value as pretty JSON on stdout
+fn print_json(value: &impl Serialize) -> Result<(), CliError> {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, value).map_err(io::Error::from)?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+/// Name of a command-line value, which is also its name in the API
+fn value_name(value: &impl ValueEnum) -> String {
+    value.to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+/// Checks that a file ID has the form the server gives out
+fn check_file_id(file_id: &str) -> Result<(), CliError> {
+    Uuid::parse_str(file_id)
+        .map(|_| ())
+        .map_err(|_| CliError::Usage(format!("{}: not a file ID", file_id)))
+}
+
+/// Name the server suggests in `Content-Disposition`, without any directories
+fn attachment_name(response: &ureq::Response) -> Option<String> {
+    let disposition = response.header("Content-Disposition")?;
+    let (_, rest) = disposition.split_once("filename=\"")?;
+    let (name, _) = rest.split_once('"')?;
+    local_name(name)
+}
+
+/// Reduces a name from the server or a container to a plain file name
+fn local_name(name: &str) -> Option<String> {
+    Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| !name.starts_with('.'))
+}
+
+/// Picks the output path, refusing to replace a file the user did not name
+fn output_path(output: Option<&Path>, default_name: String) -> Result<PathBuf, CliError> {
+    match output {
+        Some(path) => Ok(path.to_path_buf()),
+        None => {
+            let path = PathBuf::from(default_name);
+            if path.exists() {
+                return Err(CliError::Usage(format!("{}: already exists; choose another name with --output", path.display())));
+            }
+            Ok(path)
+        }
+    }
+}
+
+/// Reports a file written by `download` or `decrypt`
+fn report_saved(json: bool, file_id: &str, path: &Path, size: u64) -> Result<(), CliError> {
+    match file_path(Some(path)) {
+        Some(path) if json => print_json(&serde_json::json!({
+            "id": file_id,
+            "path": path,
+            "size": size,
+        })),
+        Some(path) => {
+            println!("Saved {} ({})", path.display(), HumanBytes(size));
+            Ok(())
+        },
+        // Nothing may follow data written to stdout
+        None => Ok(()),
+    }
+}
+
+/// Runs a `remote` subcommand
+pub fn run(args: RemoteArgs) -> Result<(), CliError> {
+    let client = Client::new(args.server)?;
+    
+    match args.command {
+        RemoteCommand::Upload(upload_args) => upload(&client, upload_args, args.json),
+        RemoteCommand::List => list(&client, args.json),
+        RemoteCommand::Download(download_args) => download(&client, download_args, args.json),
+        RemoteCommand::Decrypt(decrypt_args) => decrypt(&client, decrypt_args, args.json),
+    }
+}
+
+/// Upload a file, optionally encrypting it
+fn upload(client: &Client, args: UploadArgs, json: bool) -> Result<(), CliError> {
+    let filename = local_name(&args.input.to_string_lossy())
+        .ok_or_else(|| CliError::Usage(format!("{}: not a file path", args.input.display())))?;
+    
+    // Options every upload endpoint understands
+    let mut query = Vec::new();
+    if let Some(expires_in) = args.expires_in {
+        query.push(("expires_in", expires_in.to_string()));
+    }
+    if let Some(max_downloads) = args.max_downloads {
+        query.push(("max_downloads", max_downloads.to_string()));
+    }
+    
+    let file_info = if args.local {
+        // The server only accepts SilentLock containers from clients
+        if matches!(args.format, Some(FormatArg::Age)) {
+            return Err(CliError::Usage("--local uploads are always in the SilentLock format".to_string()));
+        }
+        let passphrase = read_passphrase(true)?;
+        
+        // Encrypt into a temporary file, sealing in the original name
+        let metadata = FileMetadata {
+            filename: filename.clone(),
+            content_type: None,
+            uploaded_at: chrono::Utc::now(),
+        };
+        let (encrypted, mut writer) = TempFile::create()?;
+        let mut reader = File::open(&args.input)
+            .map_err(|e| CliError::Usage(format!("{}: {}", args.input.display(), e)))?;
+        encrypt_stream(
+            &mut reader,
+            &mut writer,
+            &[Recipient::Passphrase(passphrase)],
+            args.algorithm.unwrap_or(AlgorithmArg::Aes256Gcm).into(),
+            Some(&metadata),
+        )?;
+        writer.sync_all()?;
+        drop(writer);
+        
+        query.push(("encrypted", "true".to_string()));
+        client.send_file("/api/files/upload", &query, &encrypted.0, &format!("{}.slck", filename))?
+    } else if args.encrypt {
+        // The server encrypts as it stores, so the passphrase travels with the upload
+        query.push(("passphrase", read_passphrase(true)?));
+        
+        // The server's defaults match ours, so only choices made here are sent
+        if let Some(format) = &args.format {
+            query.push(("format", value_name(format)));
+        }
+        if let Some(algorithm) = &args.algorithm {
+            query.push(("algorithm", value_name(algorithm)));
+        }
+        client.send_file("/api/files/upload-encrypt", &query, &args.input, &filename)?
+    } else {
+        client.send_file("/api/files/upload", &query, &args.input, &filename)?
+    };
+    
+    if json {
+        return print_json(&file_info);
+    }
+    
+    println!("Uploaded {} as {} ({})", filename, file_info.id, HumanBytes(file_info.size));
+    if let Some(expires_at) = file_info.expires_at {
+        println!("Expires: {}", expires_at.format("%Y-%m-%d %H:%M:%S UTC"));
+    }
+    if let Some(downloads_remaining) = file_info.downloads_remaining {
+        println!("Downloads allowed: {}", downloads_remaining);
+    }
+    
+    Ok(())
+}
+
+/// List the files on the server
+fn list(client: &Client, json: bool) -> Result<(), CliError> {
+    let response: ListFilesResponse = read_json(client.request("GET", "/api/files/list").call()?)?;
+    
+    if json {
+        return print_json(&response);
+    }
+    
+    if response.files.is_empty() {
+        println!("No files");
+        return Ok(());
+    }
+    
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{:<36}  {:>10}  {:<9}  {:<16}  NAME", "ID", "SIZE", "ENCRYPTED", "UPLOADED")?;
+    for file in &response.files {
+        writeln!(
+            stdout,
+            "{:<36}  {:>10}  {:<9}  {:<16}  {}",
+            file.id,
+            HumanBytes(file.size).to_string(),
+            if file.encrypted { "yes" } else { "no" },
+            file.uploaded_at.format("%Y-%m-%d %H:%M"),
+            file.filename,
+        )?;
+    }
+    
+    Ok(())
+}
+
+/// Download a file as stored
+fn download(client: &Client, args: DownloadArgs, json: bool) -> Result<(), CliError> {
+    check_file_id(&args.file_id)?;
+    check_json_output(json, args.output.as_deref())?;
+    
+    let response = client.download(&args.file_id)?;
+    save_response(response, args.output.as_deref(), &args.file_id, json)
+}
+
+/// Download a file and decrypt it with a passphrase
+fn decrypt(client: &Client, args: RemoteDecryptArgs, json: bool) -> Result<(), CliError> {
+    check_file_id(&args.file_id)?;
+    check_json_output(json, args.output.as_deref())?;
+    let passphrase = read_passphrase(false)?;
+    
+    if !args.local {
+        // The server decrypts and names the file from its sealed metadata
+        let request = DecryptRequest {
+            file_id: args.file_id.clone(),
+            passphrase: Some(passphrase),
+            identity: None,
+        };
+        let body = serde_json::to_string(&request).map_err(io::Error::from)?;
+        let response = client.request("POST", "/api/files/decrypt")
+            .set("Content-Type", "application/json")
+            .send_string(&body)?;
+        return save_response(response, args.output.as_deref(), &args.file_id, json);
+    }
+    
+    // The original name is only known once decrypted, so write under the ID first
+    let path = output_path(args.output.as_deref(), args.file_id.clone())?;
+    let response = client.download(&args.file_id)?;
+    let bar = progress(content_length(&response));
+    let mut reader = bar.wrap_read(response.into_reader());
+    let result = with_output(Some(&path), OutputKind::Text, |mut writer| {
+        let mut counter = CountingWriter { inner: &mut writer, count: 0 };
+        let metadata = decrypt_stream(&mut reader, &mut counter, &Identity::Passphrase(passphrase))?;
+        Ok((metadata, counter.count))
+    });
+    bar.finish_and_clear();
+    let (metadata, size) = result?;
+    
+    // Rename to the sealed name unless the user chose one or it is taken
+    let mut path = path;
+    if args.output.is_none() {
+        if let Some(name) = metadata.and_then(|metadata| local_name(&metadata.filename)) {
+            let named = PathBuf::from(name);
+            if !named.exists() {
+                fs::rename(&path, &named)?;
+                path = named;
+            }
+        }
+    }
+    
+    report_saved(json, &args.file_id, &path, size)
+}
+
+/// Rejects `--json` when file data would go to stdout
+fn check_json_output(json: bool, output: Option<&Path>) -> Result<(), CliError> {
+    if json && output.is_some() && file_path(output).is_none() {
+        return Err(CliError::Usage("--json cannot be combined with writing the file to stdout".to_string()));
+    }
+    Ok(())
+}
+
+/// Body length announced by the server, if any
+fn content_length(response: &ureq::Response) -> Option<u64> {
+    response.header("Content-Length").and_then(|length| length.parse().ok())
+}
+
+/// Writes a download to the chosen output with a progress bar
+fn save_response(
+    response: ureq::Response,
+    output: Option<&Path>,
+    file_id: &str,
+    json: bool,
+) -> Result<(), CliError> {
+    let name = attachment_name(&response).unwrap_or_else(|| file_id.to_string());
+    let path = output_path(output, name)?;
+    
+    let bar = progress(content_length(&response));
+    let mut reader = bar.wrap_read(response.into_reader());
+    let result = with_output(Some(&path), OutputKind::Text, |writer| Ok(io::copy(&mut reader, writer)?));
+    bar.finish_and_clear();
+    
+    report_saved(json, file_id, &path, result?)
+}
+
+/// Counts the bytes written through it
+struct CountingWriter<W> {
+    /// Writer the bytes go to
+    inner: W,
+    
+    /// Bytes written so far
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
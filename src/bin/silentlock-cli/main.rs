@@ -0,0 +1,277 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use thiserror::Error;
+
+use silentlock::encryption::{CipherAlgorithm, ContainerFormat, EncryptionError};
+
+mod client;
+mod offline;
+
+/// Encrypt, decrypt and inspect SilentLock files from the terminal
+#[derive(Parser)]
+#[command(name = "silentlock-cli", version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Encrypt a file for a passphrase or public keys
+    Encrypt(EncryptArgs),
+    
+    /// Decrypt a SilentLock or age file
+    Decrypt(DecryptArgs),
+    
+    /// Show the header of an encrypted file without decrypting it
+    Inspect(InspectArgs),
+    
+    /// Generate a key pair to encrypt files for
+    Keygen(KeygenArgs),
+    
+    /// Work with files on a running SilentLock server
+    Remote(RemoteArgs),
+}
+
+#[derive(clap::Args)]
+pub struct EncryptArgs {
+    /// File to encrypt; reads stdin if omitted or `-`
+    input: Option<PathBuf>,
+    
+    /// Where to write the encrypted file; writes stdout if omitted or `-`
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    
+    /// AEAD algorithm for SilentLock files
+    #[arg(short, long, value_enum, default_value_t = AlgorithmArg::Aes256Gcm)]
+    algorithm: AlgorithmArg,
+    
+    /// Container format to write
+    #[arg(short, long, value_enum, default_value_t = FormatArg::Silentlock)]
+    format: FormatArg,
+    
+    /// RSA public key (PEM file) to encrypt for; may be repeated
+    #[arg(short = 'k', long = "public-key")]
+    public_keys: Vec<PathBuf>,
+    
+    /// age recipient (`age1...`) to encrypt for; may be repeated
+    #[arg(short = 'r', long = "age-recipient")]
+    age_recipients: Vec<String>,
+    
+    /// Also encrypt for a passphrase when public keys are given
+    #[arg(short, long)]
+    passphrase: bool,
+}
+
+#[derive(clap::Args)]
+pub struct DecryptArgs {
+    /// File to decrypt; reads stdin if omitted or `-`
+    input: Option<PathBuf>,
+    
+    /// Where to write the decrypted file; writes stdout if omitted or `-`
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    
+    /// RSA private key (PEM file) to decrypt with instead of a passphrase
+    #[arg(short = 'k', long = "private-key", conflicts_with = "identity")]
+    private_key: Option<PathBuf>,
+    
+    /// age identity file (`AGE-SECRET-KEY-1...`) to decrypt with instead of a passphrase
+    #[arg(short, long)]
+    identity: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+pub struct InspectArgs {
+    /// File to inspect; reads stdin if omitted or `-`
+    input: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+pub struct KeygenArgs {
+    /// Kind of key pair to generate
+    #[arg(short = 't', long = "type", value_enum, default_value_t = KeyType::Rsa)]
+    key_type: KeyType,
+    
+    /// Where to write the private key; writes stdout if omitted or `-`
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    
+    /// Where to write the RSA public key; printed to stderr if omitted
+    #[arg(long)]
+    public_key: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+pub struct RemoteArgs {
+    /// Server URL; read from the config file if not given [default: http://127.0.0.1:8080]
+    #[arg(long, env = "SILENTLOCK_SERVER", global = true)]
+    server: Option<String>,
+    
+    /// Print results as JSON for scripts
+    #[arg(long, global = true)]
+    json: bool,
+    
+    #[command(subcommand)]
+    command: RemoteCommand,
+}
+
+#[derive(Subcommand)]
+pub enum RemoteCommand {
+    /// Upload a file, optionally encrypting it
+    Upload(UploadArgs),
+    
+    /// List the files on the server
+    List,
+    
+    /// Download a file as stored
+    Download(DownloadArgs),
+    
+    /// Download a file and decrypt it with a passphrase
+    Decrypt(RemoteDecryptArgs),
+}
+
+#[derive(clap::Args)]
+pub struct UploadArgs {
+    /// File to upload
+    input: PathBuf,
+    
+    /// Encrypt the file with a passphrase
+    #[arg(short, long)]
+    encrypt: bool,
+    
+    /// Encrypt before uploading, so the server never sees the passphrase
+    #[arg(short, long, requires = "encrypt")]
+    local: bool,
+    
+    /// AEAD algorithm for SilentLock files [default: aes-256-gcm]
+    #[arg(short, long, value_enum, requires = "encrypt")]
+    algorithm: Option<AlgorithmArg>,
+    
+    /// Container format to encrypt into [default: silentlock]
+    #[arg(short, long, value_enum, requires = "encrypt")]
+    format: Option<FormatArg>,
+    
+    /// Delete the file this many seconds after upload
+    #[arg(long, value_name = "SECONDS")]
+    expires_in: Option<u64>,
+    
+    /// Delete the file after this many downloads
+    #[arg(long, value_name = "COUNT")]
+    max_downloads: Option<u32>,
+}
+
+#[derive(clap::Args)]
+pub struct DownloadArgs {
+    /// ID of the file to download
+    file_id: String,
+    
+    /// Where to write the file; defaults to its name on the server, `-` writes stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+pub struct RemoteDecryptArgs {
+    /// ID of the file to decrypt
+    file_id: String,
+    
+    /// Where to write the file; defaults to its original name, `-` writes stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    
+    /// Download the encrypted file and decrypt it here, so the server never sees the passphrase
+    #[arg(short, long)]
+    local: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum AlgorithmArg {
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+    
+    #[value(name = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    
+    #[value(name = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl From<AlgorithmArg> for CipherAlgorithm {
+    fn from(algorithm: AlgorithmArg) -> Self {
+        match algorithm {
+            AlgorithmArg::Aes256Gcm => CipherAlgorithm::Aes256Gcm,
+            AlgorithmArg::ChaCha20Poly1305 => CipherAlgorithm::ChaCha20Poly1305,
+            AlgorithmArg::XChaCha20Poly1305 => CipherAlgorithm::XChaCha20Poly1305,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FormatArg {
+    Silentlock,
+    Age,
+}
+
+impl From<FormatArg> for ContainerFormat {
+    fn from(format: FormatArg) -> Self {
+        match format {
+            FormatArg::Silentlock => ContainerFormat::SilentLock,
+            FormatArg::Age => ContainerFormat::Age,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KeyType {
+    /// RSA-2048, usable with the SilentLock format
+    Rsa,
+    
+    /// age X25519, usable with the age format
+    Age,
+}
+
+#[derive(Error, Debug)]
+pub enum CliError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    
+    #[error("{0}")]
+    Encryption(#[from] EncryptionError),
+    
+    #[error("{0}")]
+    Usage(String),
+    
+    #[error("Server responded with {0}: {1}")]
+    Server(u16, String),
+    
+    #[error("Cannot reach the server: {0}")]
+    Transport(String),
+    
+    #[error("Unexpected response from the server: {0}")]
+    Response(String),
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    
+    let result = match cli.command {
+        Command::Encrypt(args) => offline::encrypt(args),
+        Command::Decrypt(args) => offline::decrypt(args),
+        Command::Inspect(args) => offline::inspect(args),
+        Command::Keygen(args) => offline::keygen(args),
+        Command::Remote(args) => client::run(args),
+    };
+    
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        // A reader like `head` closing the pipe early is not a failure
+        Err(CliError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
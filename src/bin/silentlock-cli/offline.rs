@@ -0,0 +1,302 @@
+//! Commands that work on local files without a server.
+
+use age::secrecy::ExposeSecret;
+use chrono::Utc;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Cursor, IsTerminal, Read, Write};
+use std::path::Path;
+
+use silentlock::encryption::age_format::AGE_MAGIC;
+use silentlock::encryption::{
+    decrypt_stream, encrypt_age_stream, encrypt_stream, export_private_key, export_public_key, generate_rsa_keypair,
+    import_private_key, import_public_key, parse_age_identity, parse_age_recipient, ContainerFormat, FileMetadata,
+    Header, Identity, KeySlot, Recipient,
+};
+
+use crate::{CliError, DecryptArgs, EncryptArgs, InspectArgs, KeyType, KeygenArgs};
+
+/// Environment variable that supplies the passphrase instead of a prompt
+const PASSPHRASE_ENV: &str = "SILENTLOCK_PASSPHRASE";
+
+/// What a command writes, which decides how the output is created
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputKind {
+    /// Plaintext or text that may go to a terminal
+    Text,
+    
+    /// Ciphertext, which is never written to a terminal
+    Ciphertext,
+    
+    /// Private key material, created readable by the owner only
+    Secret,
+}
+
+/// Treats a missing path or `-` as stdin or stdout
+pub(crate) fn file_path(path: Option<&Path>) -> Option<&Path> {
+    path.filter(|path| *path != Path::new("-"))
+}
+
+/// Opens the input named on the command line, or stdin
+fn open_input(path: Option<&Path>) -> Result<Box<dyn Read>, CliError> {
+    match file_path(path) {
+        Some(path) => {
+            let file = File::open(path)
+                .map_err(|e| CliError::Usage(format!("{}: {}", path.display(), e)))?;
+            Ok(Box::new(BufReader::new(file)))
+        },
+        None => Ok(Box::new(io::stdin().lock())),
+    }
+}
+
+/// Runs `write` against the output named on the command line, or stdout
+///
+/// Files are written next to their final path and only renamed into place
+/// once `write` succeeds, so a failed command never leaves partial output
+/// behind.
+pub(crate) fn with_output<T>(
+    path: Option<&Path>,
+    kind: OutputKind,
+    write: impl FnOnce(&mut dyn Write) -> Result<T, CliError>,
+) -> Result<T, CliError> {
+    let path = match file_path(path) {
+        Some(path) => path,
+        None => {
+            let stdout = io::stdout();
+            if kind == OutputKind::Ciphertext && stdout.is_terminal() {
+                return Err(CliError::Usage("Refusing to write encrypted data to a terminal; use --output or redirect stdout".to_string()));
+            }
+            
+            let mut writer = BufWriter::new(stdout.lock());
+            let value = write(&mut writer)?;
+            writer.flush()?;
+            return Ok(value);
+        }
+    };
+    
+    // Write to a hidden partial file beside the target
+    let file_name = path.file_name()
+        .ok_or_else(|| CliError::Usage(format!("{}: not a file path", path.display())))?;
+    let partial = path.with_file_name(format!(".{}.partial", file_name.to_string_lossy()));
+    let result = create_file(&partial, kind).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        let value = write(&mut writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(value)
+    });
+    
+    match result.and_then(|value| fs::rename(&partial, path).map(|_| value).map_err(CliError::from)) {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Creates an output file, restricting access to secrets on Unix
+fn create_file(path: &Path, kind: OutputKind) -> Result<File, CliError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    
+    #[cfg(unix)]
+    if kind == OutputKind::Secret {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = kind;
+    
+    options.open(path)
+        .map_err(|e| CliError::Usage(format!("{}: {}", path.display(), e)))
+}
+
+/// Reads a key file named on the command line
+fn read_key_file(path: &Path) -> Result<String, CliError> {
+    fs::read_to_string(path)
+        .map_err(|e| CliError::Usage(format!("{}: {}", path.display(), e)))
+}
+
+/// Gets the passphrase from `SILENTLOCK_PASSPHRASE`, or prompts for it without echo
+pub(crate) fn read_passphrase(confirm: bool) -> Result<String, CliError> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        if !passphrase.is_empty() {
+            return Ok(passphrase);
+        }
+    }
+    
+    let prompt = |prompt: &str| rpassword::prompt_password(prompt).map_err(|e| {
+        CliError::Usage(format!("Cannot prompt for a passphrase ({}); set {}", e, PASSPHRASE_ENV))
+    });
+    
+    let passphrase = prompt("Passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(CliError::Usage("Passphrase must not be empty".to_string()));
+    }
+    
+    if confirm && prompt("Confirm passphrase: ")? != passphrase {
+        return Err(CliError::Usage("Passphrases do not match".to_string()));
+    }
+    
+    Ok(passphrase)
+}
+
+/// Lowercase hex encoding, as the server shows fingerprints
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Encrypt a file for a passphrase or public keys
+pub fn encrypt(args: EncryptArgs) -> Result<(), CliError> {
+    let format = ContainerFormat::from(args.format);
+    
+    // Collect the recipients; a passphrase is used unless only keys were given
+    let mut recipients = Vec::new();
+    for path in &args.public_keys {
+        let public_key = import_public_key(&read_key_file(path)?)?;
+        recipients.push(Recipient::RsaPublicKey(Box::new(public_key)));
+    }
+    for recipient in &args.age_recipients {
+        recipients.push(parse_age_recipient(recipient)?);
+    }
+    if args.passphrase || recipients.is_empty() {
+        recipients.push(Recipient::Passphrase(read_passphrase(true)?));
+    }
+    
+    // Seal the original name into SilentLock files read from disk
+    let metadata = file_path(args.input.as_deref())
+        .and_then(|path| path.file_name())
+        .map(|name| FileMetadata {
+            filename: name.to_string_lossy().into_owned(),
+            content_type: None,
+            uploaded_at: Utc::now(),
+        });
+    
+    let mut reader = open_input(args.input.as_deref())?;
+    with_output(args.output.as_deref(), OutputKind::Ciphertext, |mut writer| {
+        match format {
+            ContainerFormat::SilentLock => encrypt_stream(&mut reader, &mut writer, &recipients, args.algorithm.into(), metadata.as_ref())?,
+            ContainerFormat::Age => encrypt_age_stream(&mut reader, &mut writer, &recipients)?,
+        };
+        Ok(())
+    })
+}
+
+/// Decrypt a SilentLock or age file
+///
+/// Output written to stdout before an error must be discarded.
+pub fn decrypt(args: DecryptArgs) -> Result<(), CliError> {
+    // Build the credential that opens the file
+    let identity = match (&args.private_key, &args.identity) {
+        (Some(path), _) => Identity::RsaPrivateKey(Box::new(import_private_key(&read_key_file(path)?)?)),
+        (None, Some(path)) => {
+            // Identity files may carry comments, as written by age-keygen
+            let contents = read_key_file(path)?;
+            let key = contents
+                .lines()
+                .find(|line| line.starts_with("AGE-SECRET-KEY-"))
+                .ok_or_else(|| CliError::Usage(format!("{}: no age identity found", path.display())))?;
+            parse_age_identity(key)?
+        },
+        (None, None) => Identity::Passphrase(read_passphrase(false)?),
+    };
+    
+    let mut reader = open_input(args.input.as_deref())?;
+    let metadata = with_output(args.output.as_deref(), OutputKind::Text, |mut writer| {
+        Ok(decrypt_stream(&mut reader, &mut writer, &identity)?)
+    })?;
+    
+    if let Some(metadata) = metadata {
+        if io::stderr().is_terminal() {
+            eprintln!("Original file: {}", metadata.filename);
+        }
+    }
+    
+    Ok(())
+}
+
+/// Show the header of an encrypted file without decrypting it
+pub fn inspect(args: InspectArgs) -> Result<(), CliError> {
+    let mut reader = open_input(args.input.as_deref())?;
+    
+    // Peek at the magic bytes, then put them back in front of the stream
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)
+        .map_err(|_| CliError::Usage("File too short".to_string()))?;
+    let mut reader = Cursor::new(magic).chain(reader);
+    
+    if magic[..] == AGE_MAGIC[..4] {
+        let decryptor = age::Decryptor::new(reader)
+            .map_err(|e| CliError::Usage(format!("Invalid age file: {}", e)))?;
+        println!("Format: age v1");
+        println!("Recipients: {}", if decryptor.is_scrypt() { "passphrase (scrypt)" } else { "X25519 public keys" });
+        return Ok(());
+    }
+    
+    let (header, _) = Header::read_from(&mut reader)?;
+    let algorithm = serde_json::to_value(header.cipher)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default();
+    
+    println!("Format: SilentLock v{}", header.version);
+    println!("Algorithm: {}", algorithm);
+    match header.chunk_size {
+        Some(chunk_size) => println!("Chunk size: {} bytes", chunk_size),
+        None => println!("Chunk size: single message"),
+    }
+    println!("Key slots: {}", header.key_slots.len());
+    for (index, slot) in header.key_slots.iter().enumerate() {
+        match slot {
+            KeySlot::Passphrase { params, .. } => println!(
+                "  {}: passphrase (Argon2id, {} KiB, {} passes, {} lanes)",
+                index, params.m_cost, params.t_cost, params.p_cost,
+            ),
+            KeySlot::Rsa { fingerprint, .. } => println!("  {}: RSA key {}", index, hex(fingerprint)),
+        }
+    }
+    println!("Metadata: {}", if header.metadata.is_empty() { "none" } else { "sealed" });
+    
+    Ok(())
+}
+
+/// Generate a key pair to encrypt files for
+pub fn keygen(args: KeygenArgs) -> Result<(), CliError> {
+    match args.key_type {
+        KeyType::Rsa => {
+            let (private_key, public_key) = generate_rsa_keypair()?;
+            let private_pem = export_private_key(&private_key)?;
+            let public_pem = export_public_key(&public_key)?;
+            
+            with_output(args.output.as_deref(), OutputKind::Secret, |writer| {
+                Ok(writer.write_all(private_pem.as_bytes())?)
+            })?;
+            
+            // The public key is what gets registered with the server or handed out
+            match &args.public_key {
+                Some(path) => with_output(Some(path), OutputKind::Text, |writer| {
+                    Ok(writer.write_all(public_pem.as_bytes())?)
+                })?,
+                None => eprint!("{}", public_pem),
+            }
+        },
+        KeyType::Age => {
+            if args.public_key.is_some() {
+                return Err(CliError::Usage("--public-key only applies to RSA keys".to_string()));
+            }
+            
+            let identity = age::x25519::Identity::generate();
+            let recipient = identity.to_public();
+            
+            // Same layout as age-keygen, so the file works with the age tool
+            with_output(args.output.as_deref(), OutputKind::Secret, |writer| {
+                writeln!(writer, "# created: {}", Utc::now().to_rfc3339())?;
+                writeln!(writer, "# public key: {}", recipient)?;
+                writeln!(writer, "{}", identity.to_string().expose_secret())?;
+                Ok(())
+            })?;
+            eprintln!("Public key: {}", recipient);
+        }
+    }
+    
+    Ok(())
+}
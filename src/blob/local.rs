@@ -0,0 +1,206 @@
+use chrono::DateTime;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use super::{validate_key, BlobError, BlobInfo, BlobStore};
+
+/// Size of the buffer used to overwrite blobs on secure deletion
+const OVERWRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Blobs kept as files under a root directory
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+        }
+    }
+    
+    fn path_for(&self, key: &str) -> Result<PathBuf, BlobError> {
+        validate_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+/// Maps a missing file to `BlobError::NotFound`
+fn not_found(key: &str) -> impl FnOnce(io::Error) -> BlobError + '_ {
+    move |e| match e.kind() {
+        io::ErrorKind::NotFound => BlobError::NotFound(key.to_string()),
+        _ => BlobError::Io(e),
+    }
+}
+
+impl BlobStore for LocalBlobStore {
+    fn put(&self, key: &str, reader: &mut dyn Read, _len: u64) -> Result<(), BlobError> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        
+        // Write next to the target and rename, so readers never see a partial blob
+        let temp_path = path.with_file_name(format!(".{}.partial", Uuid::new_v4()));
+        let result = File::create(&temp_path)
+            .and_then(|mut file| {
+                io::copy(reader, &mut file)?;
+                file.flush()?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&temp_path, &path));
+        
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+    
+    fn get(&self, key: &str) -> Result<Box<dyn Read + Send>, BlobError> {
+        let path = self.path_for(key)?;
+        let file = File::open(&path).map_err(not_found(key))?;
+        Ok(Box::new(BufReader::new(file)))
+    }
+    
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Box<dyn Read + Send>, BlobError> {
+        let path = self.path_for(key)?;
+        let mut file = File::open(&path).map_err(not_found(key))?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(BufReader::new(file).take(len)))
+    }
+    
+    fn delete(&self, key: &str) -> Result<(), BlobError> {
+        let path = self.path_for(key)?;
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+    
+    fn secure_delete(&self, key: &str) -> Result<(), BlobError> {
+        let path = self.path_for(key)?;
+        let mut file = match OpenOptions::new().write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        
+        // Overwrite the contents in place with random data and make sure it reaches the disk
+        let len = file.metadata()?.len();
+        let mut buf = vec![0u8; OVERWRITE_BUFFER_SIZE];
+        let mut written = 0;
+        while written < len {
+            let n = (len - written).min(buf.len() as u64) as usize;
+            OsRng.fill_bytes(&mut buf[..n]);
+            file.write_all(&buf[..n])?;
+            written += n as u64;
+        }
+        file.sync_all()?;
+        drop(file);
+        
+        self.delete(key)
+    }
+    
+    fn stat(&self, key: &str) -> Result<Option<BlobInfo>, BlobError> {
+        let path = self.path_for(key)?;
+        match fs::metadata(&path) {
+            Ok(metadata) => Ok(Some(BlobInfo {
+                size: metadata.len(),
+                modified: metadata.modified().ok().map(DateTime::from),
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// A store over a fresh directory, removed when dropped
+    struct TempStore {
+        root: PathBuf,
+        store: LocalBlobStore,
+    }
+    
+    impl TempStore {
+        fn new() -> Self {
+            let root = std::env::temp_dir().join(format!("silentlock-local-{}", Uuid::new_v4()));
+            let store = LocalBlobStore::new(&root);
+            Self {
+                root,
+                store,
+            }
+        }
+    }
+    
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+    
+    fn read_all(mut reader: Box<dyn Read + Send>) -> Vec<u8> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        data
+    }
+    
+    #[test]
+    fn put_replaces_blobs_without_leaving_partial_files() {
+        let temp = TempStore::new();
+        temp.store.put("files/a", &mut &b"first"[..], 5).unwrap();
+        temp.store.put("files/a", &mut &b"second"[..], 6).unwrap();
+        
+        assert_eq!(read_all(temp.store.get("files/a").unwrap()), b"second");
+        assert_eq!(temp.store.stat("files/a").unwrap().map(|info| info.size), Some(6));
+        let entries: Vec<_> = fs::read_dir(temp.root.join("files")).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
+    
+    #[test]
+    fn get_range_reads_only_the_range() {
+        let temp = TempStore::new();
+        temp.store.put("files/a", &mut &b"0123456789"[..], 10).unwrap();
+        
+        assert_eq!(read_all(temp.store.get_range("files/a", 2, 5).unwrap()), b"23456");
+        assert_eq!(read_all(temp.store.get_range("files/a", 8, 5).unwrap()), b"89");
+        assert_eq!(read_all(temp.store.get_range("files/a", 12, 5).unwrap()), b"");
+        assert!(matches!(temp.store.get_range("files/missing", 0, 1), Err(BlobError::NotFound(_))));
+    }
+    
+    #[test]
+    fn secure_delete_removes_the_blob() {
+        let temp = TempStore::new();
+        let data = vec![7u8; OVERWRITE_BUFFER_SIZE * 2 + 1];
+        temp.store.put("files/a", &mut &data[..], data.len() as u64).unwrap();
+        
+        // A reader opened before the overwrite sees the random data, not the contents
+        let mut open = File::open(temp.root.join("files/a")).unwrap();
+        temp.store.secure_delete("files/a").unwrap();
+        let mut overwritten = Vec::new();
+        open.read_to_end(&mut overwritten).unwrap();
+        assert_eq!(overwritten.len(), data.len());
+        assert_ne!(overwritten, data);
+        
+        assert_eq!(temp.store.stat("files/a").unwrap(), None);
+        temp.store.secure_delete("files/a").unwrap();
+        temp.store.delete("files/a").unwrap();
+    }
+    
+    #[test]
+    fn keys_cannot_leave_the_root() {
+        let temp = TempStore::new();
+        for key in ["../a", "/etc/passwd", "files//a", "files/./a", "files\\a", ""] {
+            assert!(matches!(temp.store.get(key), Err(BlobError::InvalidKey(_))), "{}", key);
+            assert!(matches!(temp.store.put(key, &mut &b""[..], 0), Err(BlobError::InvalidKey(_))), "{}", key);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Storage for file contents.
+//!
+//! Uploaded and encrypted files are kept as blobs under string keys such as
+//! `encrypted/<uuid>.encrypted`. The local filesystem is used by default; an
+//! S3-compatible object store can be used instead, see `S3BlobStore`. The
+//! server wraps either in a `TrackedBlobStore`, so blobs are not deleted
+//! while downloads still read them.
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use log::error;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use thiserror::Error;
+
+pub mod local;
+pub mod s3;
+pub mod tracked;
+
+pub use local::LocalBlobStore;
+pub use s3::{S3BlobStore, S3Config};
+pub use tracked::TrackedBlobStore;
+
+#[derive(Error, Debug)]
+pub enum BlobError {
+    #[error("Blob not found: {0}")]
+    NotFound(String),
+    
+    #[error("Invalid blob key: {0}")]
+    InvalidKey(String),
+    
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    
+    #[error("Object store error: {0}")]
+    Backend(String),
+}
+
+// A record whose blob has gone missing reads as a missing file; anything else
+// is the server's problem
+impl ResponseError for BlobError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            BlobError::NotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            BlobError::NotFound(_) => HttpResponse::NotFound().body("File data not found"),
+            _ => {
+                error!("Blob store error: {}", self);
+                HttpResponse::InternalServerError().body("Storage error")
+            }
+        }
+    }
+}
+
+/// Size and other attributes of a stored blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobInfo {
+    /// Length of the blob in bytes
+    pub size: u64,
+    
+    /// When the blob was last written, if the backend reports it
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// Storage for file contents, addressed by key
+pub trait BlobStore: Send + Sync {
+    /// Stores `len` bytes read from `reader` under `key`, replacing any existing blob
+    fn put(&self, key: &str, reader: &mut dyn Read, len: u64) -> Result<(), BlobError>;
+    
+    /// Opens a blob for streaming reads
+    fn get(&self, key: &str) -> Result<Box<dyn Read + Send>, BlobError>;
+    
+    /// Opens `len` bytes of a blob starting at `offset` for streaming reads
+    ///
+    /// The default implementation reads and discards everything before `offset`.
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Box<dyn Read + Send>, BlobError> {
+        let mut reader = self.get(key)?;
+        io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+        Ok(Box::new(reader.take(len)))
+    }
+    
+    /// Deletes a blob; deleting a missing blob is not an error
+    fn delete(&self, key: &str) -> Result<(), BlobError>;
+    
+    /// Overwrites a blob's contents before deleting it, where the backend allows
+    ///
+    /// Object stores give no control over where data lands, so the default
+    /// implementation is a plain delete.
+    fn secure_delete(&self, key: &str) -> Result<(), BlobError> {
+        self.delete(key)
+    }
+    
+    /// Returns a blob's attributes, or `None` if it does not exist
+    fn stat(&self, key: &str) -> Result<Option<BlobInfo>, BlobError>;
+    
+    /// Stores the contents of a local file under `key`
+    fn put_file(&self, key: &str, path: &Path) -> Result<u64, BlobError> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        self.put(key, &mut file, len)?;
+        Ok(len)
+    }
+}
+
+/// Checks that a key is a plain relative path with no `..` or empty segments
+///
+/// Keys come from our own naming functions, but they end up in filesystem
+/// paths and URLs, so they are checked anyway.
+pub fn validate_key(key: &str) -> Result<(), BlobError> {
+    let valid = !key.is_empty()
+        && !key.starts_with('/')
+        && !key.contains('\\')
+        && key.split('/').all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+    if valid {
+        Ok(())
+    } else {
+        Err(BlobError::InvalidKey(key.to_string()))
+    }
+}
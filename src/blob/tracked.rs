@@ -0,0 +1,285 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+
+use super::{BlobError, BlobInfo, BlobStore};
+
+/// How a blob is to be deleted once nobody reads it, weakest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Deletion {
+    Plain,
+    Secure,
+}
+
+/// Readers and any pending deletion of one blob
+#[derive(Debug, Default)]
+struct OpenBlob {
+    readers: usize,
+    deletion: Option<Deletion>,
+}
+
+/// State shared between the store and the readers it hands out
+struct Shared {
+    inner: Arc<dyn BlobStore>,
+    open: Mutex<HashMap<String, OpenBlob>>,
+}
+
+impl Shared {
+    /// Counts a new reader of `key`, unless the blob is already being deleted
+    fn acquire(&self, key: &str) -> Result<(), BlobError> {
+        let mut open = self.open.lock().unwrap();
+        let blob = open.entry(key.to_string()).or_default();
+        if blob.deletion.is_some() {
+            return Err(BlobError::NotFound(key.to_string()));
+        }
+        blob.readers += 1;
+        Ok(())
+    }
+    
+    /// Ends a reader of `key`, running a pending deletion if it was the last one
+    fn release(&self, key: &str) {
+        let deletion = {
+            let mut open = self.open.lock().unwrap();
+            let Some(blob) = open.get_mut(key) else {
+                return;
+            };
+            blob.readers -= 1;
+            if blob.readers > 0 {
+                return;
+            }
+            open.remove(key).and_then(|blob| blob.deletion)
+        };
+        
+        if let Some(deletion) = deletion {
+            match self.delete_now(key, deletion) {
+                Ok(()) => info!("Deleted blob {} after its last reader closed", key),
+                Err(e) => warn!("Failed to delete blob {} after its last reader closed: {}", key, e),
+            }
+        }
+    }
+    
+    fn delete_now(&self, key: &str, deletion: Deletion) -> Result<(), BlobError> {
+        match deletion {
+            Deletion::Plain => self.inner.delete(key),
+            Deletion::Secure => self.inner.secure_delete(key),
+        }
+    }
+    
+    /// Deletes `key` now, or once its last reader closes
+    ///
+    /// A secure deletion requested while a plain one is pending wins.
+    fn delete(&self, key: &str, deletion: Deletion) -> Result<(), BlobError> {
+        {
+            let mut open = self.open.lock().unwrap();
+            if let Some(blob) = open.get_mut(key) {
+                blob.deletion = blob.deletion.max(Some(deletion));
+                return Ok(());
+            }
+        }
+        self.delete_now(key, deletion)
+    }
+}
+
+/// Blob store wrapper that holds back deletions while a blob is being read
+///
+/// A plaintext file's last download overwrites its blob in place, which
+/// would corrupt downloads of the same file that are still streaming. Through
+/// this wrapper, deleting a blob with open readers only marks it: it reads as
+/// missing from then on, and is deleted once the last reader is dropped.
+pub struct TrackedBlobStore {
+    shared: Arc<Shared>,
+}
+
+impl TrackedBlobStore {
+    pub fn new(inner: Arc<dyn BlobStore>) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                inner,
+                open: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+    
+    /// Opens a blob through `open`, counting it as read until the reader is dropped
+    fn track<F>(&self, key: &str, open: F) -> Result<Box<dyn Read + Send>, BlobError>
+    where
+        F: FnOnce(&dyn BlobStore) -> Result<Box<dyn Read + Send>, BlobError>,
+    {
+        self.shared.acquire(key)?;
+        match open(self.shared.inner.as_ref()) {
+            Ok(reader) => Ok(Box::new(TrackedReader {
+                reader,
+                key: key.to_string(),
+                shared: self.shared.clone(),
+            })),
+            Err(e) => {
+                self.shared.release(key);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl BlobStore for TrackedBlobStore {
+    fn put(&self, key: &str, reader: &mut dyn Read, len: u64) -> Result<(), BlobError> {
+        self.shared.inner.put(key, reader, len)
+    }
+    
+    fn get(&self, key: &str) -> Result<Box<dyn Read + Send>, BlobError> {
+        self.track(key, |inner| inner.get(key))
+    }
+    
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Box<dyn Read + Send>, BlobError> {
+        self.track(key, |inner| inner.get_range(key, offset, len))
+    }
+    
+    fn delete(&self, key: &str) -> Result<(), BlobError> {
+        self.shared.delete(key, Deletion::Plain)
+    }
+    
+    fn secure_delete(&self, key: &str) -> Result<(), BlobError> {
+        self.shared.delete(key, Deletion::Secure)
+    }
+    
+    fn stat(&self, key: &str) -> Result<Option<BlobInfo>, BlobError> {
+        let deleting = self.shared.open.lock().unwrap()
+            .get(key)
+            .is_some_and(|blob| blob.deletion.is_some());
+        if deleting {
+            return Ok(None);
+        }
+        self.shared.inner.stat(key)
+    }
+}
+
+/// Reader handed out by `TrackedBlobStore`
+struct TrackedReader {
+    reader: Box<dyn Read + Send>,
+    key: String,
+    shared: Arc<Shared>,
+}
+
+impl Read for TrackedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Drop for TrackedReader {
+    fn drop(&mut self) {
+        // Close the underlying reader before the blob may be deleted
+        self.reader = Box::new(io::empty());
+        self.shared.release(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::LocalBlobStore;
+    use std::sync::Barrier;
+    use std::thread;
+    use uuid::Uuid;
+    
+    /// A tracked store over a fresh local directory, removed when dropped
+    struct TempStore {
+        root: std::path::PathBuf,
+        inner: Arc<dyn BlobStore>,
+        store: Arc<TrackedBlobStore>,
+    }
+    
+    impl TempStore {
+        fn new() -> Self {
+            let root = std::env::temp_dir().join(format!("silentlock-tracked-{}", Uuid::new_v4()));
+            let inner: Arc<dyn BlobStore> = Arc::new(LocalBlobStore::new(&root));
+            let store = Arc::new(TrackedBlobStore::new(inner.clone()));
+            Self {
+                root,
+                inner,
+                store,
+            }
+        }
+    }
+    
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+    
+    fn contents() -> Vec<u8> {
+        (0..256 * 1024).map(|i| (i % 251) as u8).collect()
+    }
+    
+    #[test]
+    fn concurrent_downloads_survive_a_secure_delete() {
+        let temp = TempStore::new();
+        let data = contents();
+        temp.store.put("files/a", &mut &data[..], data.len() as u64).unwrap();
+        
+        // Both downloads have started before the last one deletes the blob
+        let started = Arc::new(Barrier::new(3));
+        let downloads: Vec<_> = (0..2)
+            .map(|i| {
+                let mut reader = match i {
+                    0 => temp.store.get("files/a").unwrap(),
+                    _ => temp.store.get_range("files/a", 0, data.len() as u64).unwrap(),
+                };
+                let started = started.clone();
+                thread::spawn(move || {
+                    let mut received = vec![0u8; 1024];
+                    reader.read_exact(&mut received).unwrap();
+                    started.wait();
+                    thread::sleep(std::time::Duration::from_millis(50));
+                    reader.read_to_end(&mut received).unwrap();
+                    received
+                })
+            })
+            .collect();
+        started.wait();
+        temp.store.secure_delete("files/a").unwrap();
+        
+        // Marked for deletion, but not yet touched
+        assert_eq!(temp.store.stat("files/a").unwrap(), None);
+        assert!(matches!(temp.store.get("files/a"), Err(BlobError::NotFound(_))));
+        for download in downloads {
+            assert_eq!(download.join().unwrap(), data);
+        }
+        assert_eq!(temp.inner.stat("files/a").unwrap(), None);
+    }
+    
+    #[test]
+    fn deletion_waits_for_the_last_reader() {
+        let temp = TempStore::new();
+        temp.store.put("files/a", &mut &b"contents"[..], 8).unwrap();
+        
+        let first = temp.store.get("files/a").unwrap();
+        let second = temp.store.get("files/a").unwrap();
+        temp.store.delete("files/a").unwrap();
+        temp.store.secure_delete("files/a").unwrap();
+        
+        drop(first);
+        assert!(temp.inner.stat("files/a").unwrap().is_some());
+        drop(second);
+        assert_eq!(temp.inner.stat("files/a").unwrap(), None);
+    }
+    
+    #[test]
+    fn unread_blobs_are_deleted_at_once() {
+        let temp = TempStore::new();
+        temp.store.put("files/a", &mut &b"contents"[..], 8).unwrap();
+        drop(temp.store.get("files/a").unwrap());
+        
+        temp.store.secure_delete("files/a").unwrap();
+        assert_eq!(temp.inner.stat("files/a").unwrap(), None);
+        assert!(temp.store.shared.open.lock().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn failed_opens_are_not_counted() {
+        let temp = TempStore::new();
+        assert!(matches!(temp.store.get("files/missing"), Err(BlobError::NotFound(_))));
+        assert!(temp.store.shared.open.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+//! Reading and writing age v1 files (<https://age-encryption.org/v1>).
+//!
+//! Files written here can be opened with the standard `age` tool and the
+//! other way round. X25519 recipients and scrypt passphrase recipients are
+//! supported; RSA keys have no age equivalent and are rejected.
+
+use age::secrecy::SecretString;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::str::FromStr;
+
+use super::keyslot::{Identity, Recipient};
+use super::EncryptionError;
+
+/// First line of every age v1 file
+pub const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+
+/// Parses an age X25519 recipient string (`age1...`)
+pub fn parse_age_recipient(s: &str) -> Result<Recipient, EncryptionError> {
+    age::x25519::Recipient::from_str(s.trim())
+        .map(|r| Recipient::AgeX25519(Box::new(r)))
+        .map_err(|e| EncryptionError::KeyGeneration(format!("Invalid age recipient: {}", e)))
+}
+
+/// Parses an age X25519 identity string (`AGE-SECRET-KEY-1...`)
+pub fn parse_age_identity(s: &str) -> Result<Identity, EncryptionError> {
+    age::x25519::Identity::from_str(s.trim())
+        .map(|i| Identity::AgeX25519(Box::new(i)))
+        .map_err(|e| EncryptionError::KeyGeneration(format!("Invalid age identity: {}", e)))
+}
+
+/// Encrypts a stream into an age v1 file
+///
+/// A passphrase becomes an scrypt recipient, which age requires to be the
+/// only recipient of a file. Any number of X25519 recipients can be combined.
+pub fn encrypt_age_stream<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    recipients: &[Recipient],
+) -> Result<u64, EncryptionError> {
+    let mut age_recipients: Vec<Box<dyn age::Recipient>> = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        match recipient {
+            Recipient::Passphrase(passphrase) => {
+                let secret = SecretString::from(passphrase.clone());
+                age_recipients.push(Box::new(age::scrypt::Recipient::new(secret)));
+            }
+            Recipient::AgeX25519(r) => age_recipients.push(Box::new(r.as_ref().clone())),
+            Recipient::RsaPublicKey(_) => {
+                return Err(EncryptionError::Encryption("RSA keys cannot be used with the age format".to_string()));
+            }
+        }
+    }
+
+    let encryptor = age::Encryptor::with_recipients(age_recipients.iter().map(|r| r.as_ref()))
+        .map_err(|e| EncryptionError::Encryption(e.to_string()))?;
+
+    let mut output = encryptor.wrap_output(writer)?;
+    let total = io::copy(reader, &mut output)?;
+    output.finish()?.flush()?;
+
+    Ok(total)
+}
+
+/// Decrypts an age v1 file with `identity`
+///
+/// As with `decrypt_stream`, output written before an error must be discarded.
+pub fn decrypt_age_stream<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    identity: &Identity,
+) -> Result<u64, EncryptionError> {
+    let mut plaintext = open_age_stream(reader, identity)?;
+    io::copy(&mut plaintext, writer).map_err(map_payload_error)
+}
+
+/// Unwraps the file key of an age v1 file with `identity`
+///
+/// Returns a reader of the plaintext, which fails with `InvalidData` once it
+/// reaches a chunk that does not authenticate.
+pub fn open_age_stream<R: Read>(reader: R, identity: &Identity) -> Result<age::stream::StreamReader<R>, EncryptionError> {
+    let decryptor = age::Decryptor::new(reader).map_err(map_decrypt_error)?;
+
+    let scrypt_identity;
+    let age_identity: &dyn age::Identity = match identity {
+        Identity::Passphrase(passphrase) => {
+            scrypt_identity = age::scrypt::Identity::new(SecretString::from(passphrase.clone()));
+            &scrypt_identity
+        }
+        Identity::AgeX25519(i) => i.as_ref(),
+        Identity::RsaPrivateKey(_) => {
+            return Err(EncryptionError::Decryption("RSA keys cannot be used with the age format".to_string()));
+        }
+    };
+
+    decryptor.decrypt(iter::once(age_identity)).map_err(map_decrypt_error)
+}
+
+/// Maps an error reading the plaintext of an age file
+pub(super) fn map_payload_error(e: io::Error) -> EncryptionError {
+    match e.kind() {
+        // The payload stream reports authentication failures as invalid data
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => EncryptionError::Decryption(e.to_string()),
+        _ => EncryptionError::Io(e),
+    }
+}
+
+fn map_decrypt_error(e: age::DecryptError) -> EncryptionError {
+    match e {
+        age::DecryptError::Io(e) => EncryptionError::Io(e),
+        age::DecryptError::InvalidHeader | age::DecryptError::UnknownFormat => EncryptionError::Format(e.to_string()),
+        e => EncryptionError::Decryption(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::open_stream;
+
+    /// Spans several of age's 64 KiB chunks
+    fn plaintext() -> Vec<u8> {
+        (0..200_000).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Decrypts with the age crate alone
+    fn age_decrypt(file: &[u8], identity: &dyn age::Identity) -> Result<Vec<u8>, age::DecryptError> {
+        let mut reader = age::Decryptor::new(file)?.decrypt(iter::once(identity))?;
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    /// Encrypts with the age crate alone
+    fn age_encrypt(plaintext: &[u8], recipient: &dyn age::Recipient) -> Vec<u8> {
+        let encryptor = age::Encryptor::with_recipients(iter::once(recipient)).unwrap();
+        let mut file = Vec::new();
+        let mut writer = encryptor.wrap_output(&mut file).unwrap();
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap();
+        file
+    }
+
+    fn open(file: &[u8], identity: &Identity) -> Result<Vec<u8>, EncryptionError> {
+        let mut plaintext = Vec::new();
+        open_stream(file, identity)?.decrypt_to(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn age_opens_our_files() {
+        let identity = age::x25519::Identity::generate();
+        let other = age::x25519::Identity::generate();
+        let recipients = [
+            Recipient::AgeX25519(Box::new(other.to_public())),
+            Recipient::AgeX25519(Box::new(identity.to_public())),
+        ];
+        let mut file = Vec::new();
+        encrypt_age_stream(&mut &plaintext()[..], &mut file, &recipients).unwrap();
+
+        assert!(file.starts_with(AGE_MAGIC));
+        assert_eq!(age_decrypt(&file, &identity).unwrap(), plaintext());
+        assert_eq!(age_decrypt(&file, &other).unwrap(), plaintext());
+        let stranger = age::x25519::Identity::generate();
+        assert!(age_decrypt(&file, &stranger).is_err());
+    }
+
+    #[test]
+    fn age_opens_our_passphrase_files() {
+        let mut file = Vec::new();
+        encrypt_age_stream(&mut &b"secret"[..], &mut file, &[Recipient::Passphrase("pw".to_string())]).unwrap();
+
+        let identity = age::scrypt::Identity::new(SecretString::from("pw".to_string()));
+        assert_eq!(age_decrypt(&file, &identity).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn we_open_age_files() {
+        let identity = age::x25519::Identity::generate();
+        let file = age_encrypt(&plaintext(), &identity.to_public());
+
+        assert_eq!(open(&file, &Identity::AgeX25519(Box::new(identity))).unwrap(), plaintext());
+        let stranger = Identity::AgeX25519(Box::new(age::x25519::Identity::generate()));
+        assert!(matches!(open(&file, &stranger), Err(EncryptionError::Decryption(_))));
+        assert!(open(&file, &Identity::Passphrase("pw".to_string())).is_err());
+    }
+
+    #[test]
+    fn we_open_age_passphrase_files() {
+        let mut recipient = age::scrypt::Recipient::new(SecretString::from("pw".to_string()));
+        recipient.set_work_factor(10);
+        let file = age_encrypt(b"secret", &recipient);
+
+        assert_eq!(open(&file, &Identity::Passphrase("pw".to_string())).unwrap(), b"secret");
+        assert!(matches!(open(&file, &Identity::Passphrase("wrong".to_string())), Err(EncryptionError::Decryption(_))));
+    }
+
+    #[test]
+    fn tampered_age_files_are_rejected() {
+        let identity = age::x25519::Identity::generate();
+        let mut file = age_encrypt(&plaintext(), &identity.to_public());
+        let last = file.len() - 1;
+        file[last] ^= 1;
+
+        let result = open(&file, &Identity::AgeX25519(Box::new(identity)));
+        assert!(matches!(result, Err(EncryptionError::Decryption(_))));
+    }
+}
@@ -0,0 +1,90 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm,
+};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use serde::{Deserialize, Serialize};
+
+use super::EncryptionError;
+
+/// AEAD algorithm used for the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CipherAlgorithm {
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl CipherAlgorithm {
+    /// Identifier stored in the container header
+    pub fn id(self) -> u8 {
+        match self {
+            CipherAlgorithm::Aes256Gcm => 1,
+            CipherAlgorithm::ChaCha20Poly1305 => 2,
+            CipherAlgorithm::XChaCha20Poly1305 => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, EncryptionError> {
+        match id {
+            1 => Ok(CipherAlgorithm::Aes256Gcm),
+            2 => Ok(CipherAlgorithm::ChaCha20Poly1305),
+            3 => Ok(CipherAlgorithm::XChaCha20Poly1305),
+            _ => Err(EncryptionError::Format(format!("Unknown cipher algorithm {}", id))),
+        }
+    }
+
+    /// Length of the full AEAD nonce in bytes
+    pub fn nonce_len(self) -> usize {
+        match self {
+            CipherAlgorithm::Aes256Gcm | CipherAlgorithm::ChaCha20Poly1305 => 12,
+            CipherAlgorithm::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+/// A keyed AEAD instance for one of the supported algorithms
+pub enum FileCipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
+
+impl FileCipher {
+    /// Creates a cipher for `algorithm` keyed with a 256-bit key
+    pub fn new(algorithm: CipherAlgorithm, key: &[u8; 32]) -> Self {
+        match algorithm {
+            CipherAlgorithm::Aes256Gcm => FileCipher::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            CipherAlgorithm::ChaCha20Poly1305 => FileCipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into())),
+            CipherAlgorithm::XChaCha20Poly1305 => FileCipher::XChaCha20Poly1305(XChaCha20Poly1305::new(key.into())),
+        }
+    }
+
+    /// Encrypts `msg` under `nonce`, which must be `nonce_len()` bytes long
+    pub fn encrypt(&self, nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let payload = Payload { msg, aad };
+        let result = match self {
+            FileCipher::Aes256Gcm(c) => c.encrypt(nonce.into(), payload),
+            FileCipher::ChaCha20Poly1305(c) => c.encrypt(nonce.into(), payload),
+            FileCipher::XChaCha20Poly1305(c) => c.encrypt(nonce.into(), payload),
+        };
+        result.map_err(|e| EncryptionError::Encryption(e.to_string()))
+    }
+
+    /// Decrypts and authenticates `msg` under `nonce`
+    pub fn decrypt(&self, nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let payload = Payload { msg, aad };
+        let result = match self {
+            FileCipher::Aes256Gcm(c) => c.decrypt(nonce.into(), payload),
+            FileCipher::ChaCha20Poly1305(c) => c.decrypt(nonce.into(), payload),
+            FileCipher::XChaCha20Poly1305(c) => c.decrypt(nonce.into(), payload),
+        };
+        result.map_err(|e| EncryptionError::Decryption(e.to_string()))
+    }
+}
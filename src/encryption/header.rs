@@ -0,0 +1,456 @@
+use std::io::Read;
+
+use super::cipher::CipherAlgorithm;
+use super::kdf::{KdfAlgorithm, KdfParams, SALT_LEN};
+use super::keyslot::KeySlot;
+use super::stream::{MAX_CHUNK_SIZE, NONCE_SUFFIX_LEN};
+use super::EncryptionError;
+
+/// Magic bytes identifying a SilentLock container
+pub const MAGIC: [u8; 4] = *b"SLCK";
+
+/// Current container format version
+pub const VERSION: u8 = 4;
+
+/// Length of the fixed prefix shared by every version: magic, version, header length
+const PREFIX_LEN: usize = MAGIC.len() + 1 + 2;
+
+/// Length of the single-message nonce in version 1 files
+const V1_NONCE_LEN: usize = 12;
+
+/// Header written at the start of every encrypted file
+///
+/// Layout (version 4):
+///
+/// ```text
+/// magic "SLCK" | version u8 | header length u16 LE |
+/// cipher u8 | chunk size u32 LE | nonce prefix [nonce length - 5] |
+/// slot count u8 | { slot type u8 | slot length u16 LE | slot body }* |
+/// metadata length u16 LE | sealed metadata
+/// ```
+///
+/// The nonce prefix is 7 bytes for AES-256-GCM and ChaCha20-Poly1305, and
+/// 19 bytes for XChaCha20-Poly1305. Each key slot wraps the random data key
+/// the payload is encrypted under, see `KeySlot`. The metadata block holds the
+/// original filename and content type sealed under the data key, see
+/// `FileMetadata`; it is empty when the file has none.
+///
+/// Magic, version, cipher, chunk size and nonce prefix are passed as
+/// associated data to every chunk and to the metadata block. Key slots are
+/// left out: each one is sealed on its own, and only the right data key will
+/// authenticate the payload.
+///
+/// Version 3 is the same without the metadata block. Versions 1 and 2
+/// derived the payload key straight from the passphrase and stored the KDF
+/// salt and parameters in place of key slots. Version 1 also held a full
+/// nonce and a single AEAD message instead of chunks. Both can still be read
+/// but are no longer written, as can files from before there was a header at
+/// all (see `open_stream`).
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub version: u8,
+    pub cipher: CipherAlgorithm,
+
+    /// Plaintext chunk size, or `None` for single-message version 1 files
+    pub chunk_size: Option<u32>,
+
+    /// STREAM nonce prefix, or the full nonce for version 1 files
+    pub nonce: Vec<u8>,
+
+    /// Wrapped copies of the data key
+    pub key_slots: Vec<KeySlot>,
+
+    /// Sealed `FileMetadata`, empty if the file has none
+    pub metadata: Vec<u8>,
+}
+
+impl Header {
+    /// Creates a header for the current format version
+    pub fn new(
+        cipher: CipherAlgorithm,
+        chunk_size: u32,
+        nonce_prefix: Vec<u8>,
+        key_slots: Vec<KeySlot>,
+    ) -> Self {
+        debug_assert_eq!(nonce_prefix.len(), Self::nonce_prefix_len(cipher));
+        Self {
+            version: VERSION,
+            cipher,
+            chunk_size: Some(chunk_size),
+            nonce: nonce_prefix,
+            key_slots,
+            metadata: Vec::new(),
+        }
+    }
+
+    /// Whether the payload key is a random data key wrapped in key slots
+    ///
+    /// Only then can slots be added, removed or replaced without touching the
+    /// payload; older versions derive the payload key from the passphrase.
+    pub fn has_key_slots(&self) -> bool {
+        self.version >= 3
+    }
+
+    /// Length of the STREAM nonce prefix for a given cipher
+    pub fn nonce_prefix_len(cipher: CipherAlgorithm) -> usize {
+        cipher.nonce_len() - NONCE_SUFFIX_LEN
+    }
+
+    /// Serializes the header
+    ///
+    /// Headers read from version 3 files are written back as version 3, so
+    /// their payload stays valid. Versions 1 and 2 cannot be written.
+    pub fn encode(&self) -> Result<Vec<u8>, EncryptionError> {
+        if !self.has_key_slots() {
+            return Err(EncryptionError::Format(format!("Version {} headers cannot be written", self.version)));
+        }
+
+        let mut body = Vec::new();
+        body.push(self.cipher.id());
+        body.extend_from_slice(&self.chunk_size.unwrap_or(0).to_le_bytes());
+        body.extend_from_slice(&self.nonce);
+
+        let slot_count = u8::try_from(self.key_slots.len())
+            .map_err(|_| EncryptionError::Format("Too many key slots".to_string()))?;
+        body.push(slot_count);
+        for slot in &self.key_slots {
+            let slot_body = slot.encode_body();
+            let slot_len = u16::try_from(slot_body.len())
+                .map_err(|_| EncryptionError::Format("Key slot too large".to_string()))?;
+            body.push(slot.type_id());
+            body.extend_from_slice(&slot_len.to_le_bytes());
+            body.extend_from_slice(&slot_body);
+        }
+
+        if self.version >= 4 {
+            let metadata_len = u16::try_from(self.metadata.len())
+                .map_err(|_| EncryptionError::Format("Metadata too large".to_string()))?;
+            body.extend_from_slice(&metadata_len.to_le_bytes());
+            body.extend_from_slice(&self.metadata);
+        } else if !self.metadata.is_empty() {
+            return Err(EncryptionError::Format("Version 3 headers cannot hold metadata".to_string()));
+        }
+
+        let header_len = u16::try_from(PREFIX_LEN + body.len())
+            .map_err(|_| EncryptionError::Format("Header too large".to_string()))?;
+        let mut out = Vec::with_capacity(header_len as usize);
+        out.extend_from_slice(&MAGIC);
+        out.push(self.version);
+        out.extend_from_slice(&header_len.to_le_bytes());
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+
+    /// Associated data bound to every chunk of a version 3 or later file
+    pub fn associated_data(&self) -> Vec<u8> {
+        let mut aad = Vec::new();
+        aad.extend_from_slice(&MAGIC);
+        aad.push(self.version);
+        aad.push(self.cipher.id());
+        aad.extend_from_slice(&self.chunk_size.unwrap_or(0).to_le_bytes());
+        aad.extend_from_slice(&self.nonce);
+        aad
+    }
+
+    /// Reads a header from the start of a stream
+    ///
+    /// Returns the parsed header together with the associated data the
+    /// payload was sealed with.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<(Self, Vec<u8>), EncryptionError> {
+        let mut raw = vec![0u8; PREFIX_LEN];
+        reader.read_exact(&mut raw)
+            .map_err(|_| EncryptionError::Format("File too short".to_string()))?;
+
+        if raw[0..4] != MAGIC {
+            return Err(EncryptionError::Format("Not a SilentLock file".to_string()));
+        }
+        let version = raw[4];
+        let header_len = u16::from_le_bytes([raw[5], raw[6]]) as usize;
+        if header_len < PREFIX_LEN {
+            return Err(EncryptionError::Format("Invalid header length".to_string()));
+        }
+
+        raw.resize(header_len, 0);
+        reader.read_exact(&mut raw[PREFIX_LEN..])
+            .map_err(|_| EncryptionError::Format("Truncated header".to_string()))?;
+
+        // Versions 1 and 2 authenticated the whole header
+        let header = match version {
+            1 => return Ok((Self::parse_v1(&raw[PREFIX_LEN..])?, raw)),
+            2 => return Ok((Self::parse_v2(&raw[PREFIX_LEN..])?, raw)),
+            3 | 4 => Self::parse_slotted(version, &raw[PREFIX_LEN..])?,
+            v => return Err(EncryptionError::Format(format!("Unsupported format version {}", v))),
+        };
+        let aad = header.associated_data();
+        Ok((header, aad))
+    }
+
+    /// Parses the cipher and passphrase parameters shared by versions 1 and 2
+    ///
+    /// Returns a header with the chunking fields left empty, along with the
+    /// remaining unparsed bytes.
+    fn parse_legacy(version: u8, body: &[u8]) -> Result<(Self, &[u8]), EncryptionError> {
+        if body.len() < 2 + SALT_LEN + KdfParams::ENCODED_LEN {
+            return Err(EncryptionError::Format("Truncated header".to_string()));
+        }
+
+        let cipher = CipherAlgorithm::from_id(body[0])?;
+        let kdf = KdfAlgorithm::from_id(body[1])?;
+        let (salt, rest) = body[2..].split_at(SALT_LEN);
+        let (params, rest) = rest.split_at(KdfParams::ENCODED_LEN);
+
+        let header = Self {
+            version,
+            cipher,
+            chunk_size: None,
+            nonce: Vec::new(),
+            key_slots: vec![KeySlot::Passphrase {
+                kdf,
+                salt: salt.try_into().unwrap(),
+                params: KdfParams::from_bytes(params.try_into().unwrap())?,
+                wrapped_key: None,
+            }],
+            metadata: Vec::new(),
+        };
+        Ok((header, rest))
+    }
+
+    fn parse_v1(body: &[u8]) -> Result<Self, EncryptionError> {
+        let (mut header, rest) = Self::parse_legacy(1, body)?;
+        if header.cipher != CipherAlgorithm::Aes256Gcm || rest.len() < V1_NONCE_LEN {
+            return Err(EncryptionError::Format("Invalid version 1 header".to_string()));
+        }
+
+        header.nonce = rest[..V1_NONCE_LEN].to_vec();
+        Ok(header)
+    }
+
+    fn parse_v2(body: &[u8]) -> Result<Self, EncryptionError> {
+        let (mut header, rest) = Self::parse_legacy(2, body)?;
+        let (chunk_size, nonce, _) = Self::parse_chunking(header.cipher, rest)?;
+
+        header.chunk_size = Some(chunk_size);
+        header.nonce = nonce;
+        Ok(header)
+    }
+
+    /// Parses a version 3 or 4 header
+    fn parse_slotted(version: u8, body: &[u8]) -> Result<Self, EncryptionError> {
+        let truncated = || EncryptionError::Format("Truncated header".to_string());
+        let (&cipher_id, rest) = body.split_first().ok_or_else(truncated)?;
+        let cipher = CipherAlgorithm::from_id(cipher_id)?;
+        let (chunk_size, nonce, rest) = Self::parse_chunking(cipher, rest)?;
+
+        let (&slot_count, mut rest) = rest.split_first().ok_or_else(truncated)?;
+        let mut key_slots = Vec::with_capacity(slot_count as usize);
+        for _ in 0..slot_count {
+            if rest.len() < 3 {
+                return Err(truncated());
+            }
+            let slot_type = rest[0];
+            let slot_len = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+            if rest.len() < 3 + slot_len {
+                return Err(truncated());
+            }
+            key_slots.push(KeySlot::parse(slot_type, &rest[3..3 + slot_len])?);
+            rest = &rest[3 + slot_len..];
+        }
+        if key_slots.is_empty() {
+            return Err(EncryptionError::Format("No key slots".to_string()));
+        }
+
+        let mut metadata = Vec::new();
+        if version >= 4 {
+            if rest.len() < 2 {
+                return Err(truncated());
+            }
+            let metadata_len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+            if rest.len() < 2 + metadata_len {
+                return Err(truncated());
+            }
+            metadata = rest[2..2 + metadata_len].to_vec();
+        }
+
+        Ok(Self {
+            version,
+            cipher,
+            chunk_size: Some(chunk_size),
+            nonce,
+            key_slots,
+            metadata,
+        })
+    }
+
+    /// Parses the chunk size and nonce prefix, returning the remaining bytes
+    fn parse_chunking(cipher: CipherAlgorithm, body: &[u8]) -> Result<(u32, Vec<u8>, &[u8]), EncryptionError> {
+        let prefix_len = Self::nonce_prefix_len(cipher);
+        if body.len() < 4 + prefix_len {
+            return Err(EncryptionError::Format("Truncated header".to_string()));
+        }
+
+        let chunk_size = u32::from_le_bytes(body[0..4].try_into().unwrap());
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(EncryptionError::Format("Invalid chunk size".to_string()));
+        }
+
+        Ok((chunk_size, body[4..4 + prefix_len].to_vec(), &body[4 + prefix_len..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::kdf::{MAX_M_COST, MAX_T_COST};
+
+    /// Prefixes `body` with the magic, `version` and the total length
+    fn with_prefix(version: u8, body: &[u8]) -> Vec<u8> {
+        let mut raw = MAGIC.to_vec();
+        raw.push(version);
+        raw.extend_from_slice(&((PREFIX_LEN + body.len()) as u16).to_le_bytes());
+        raw.extend_from_slice(body);
+        raw
+    }
+
+    /// Cipher, KDF, salt and parameters as written by versions 1 and 2
+    fn legacy_body(cipher: CipherAlgorithm, params: KdfParams) -> Vec<u8> {
+        let mut body = vec![cipher.id(), KdfAlgorithm::Argon2id.id()];
+        body.extend_from_slice(&[5; SALT_LEN]);
+        body.extend_from_slice(&params.to_bytes());
+        body
+    }
+
+    fn passphrase_slot(params: KdfParams) -> KeySlot {
+        KeySlot::Passphrase {
+            kdf: KdfAlgorithm::Argon2id,
+            salt: [5; SALT_LEN],
+            params,
+            wrapped_key: Some(vec![9; 60]),
+        }
+    }
+
+    fn slotted(version: u8) -> Header {
+        let mut header = Header::new(CipherAlgorithm::ChaCha20Poly1305, 4096, vec![1; 7], vec![passphrase_slot(KdfParams::default())]);
+        header.version = version;
+        header
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let mut body = legacy_body(CipherAlgorithm::Aes256Gcm, KdfParams::default());
+        body.extend_from_slice(&[2; V1_NONCE_LEN]);
+        let raw = with_prefix(1, &body);
+
+        let (header, aad) = Header::read_from(&mut &raw[..]).unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.chunk_size, None);
+        assert_eq!(header.nonce, vec![2; V1_NONCE_LEN]);
+        assert!(!header.has_key_slots());
+        assert_eq!(aad, raw);
+        assert!(header.encode().is_err());
+    }
+
+    #[test]
+    fn rejects_version_1_with_other_ciphers() {
+        let mut body = legacy_body(CipherAlgorithm::ChaCha20Poly1305, KdfParams::default());
+        body.extend_from_slice(&[2; V1_NONCE_LEN]);
+        assert!(Header::read_from(&mut &with_prefix(1, &body)[..]).is_err());
+    }
+
+    #[test]
+    fn reads_version_2() {
+        let mut body = legacy_body(CipherAlgorithm::XChaCha20Poly1305, KdfParams::default());
+        body.extend_from_slice(&1024u32.to_le_bytes());
+        body.extend_from_slice(&[4; 19]);
+        let raw = with_prefix(2, &body);
+
+        let (header, aad) = Header::read_from(&mut &raw[..]).unwrap();
+        assert_eq!(header.version, 2);
+        assert_eq!(header.cipher, CipherAlgorithm::XChaCha20Poly1305);
+        assert_eq!(header.chunk_size, Some(1024));
+        assert_eq!(header.nonce, vec![4; 19]);
+        assert_eq!(header.key_slots, vec![KeySlot::Passphrase {
+            kdf: KdfAlgorithm::Argon2id,
+            salt: [5; SALT_LEN],
+            params: KdfParams::default(),
+            wrapped_key: None,
+        }]);
+        assert_eq!(aad, raw);
+    }
+
+    #[test]
+    fn round_trips_versions_3_and_4() {
+        for version in [3, 4] {
+            let mut header = slotted(version);
+            if version == 4 {
+                header.metadata = vec![8; 40];
+            }
+            let raw = header.encode().unwrap();
+
+            // Trailing payload bytes are left in the reader
+            let mut file = raw.clone();
+            file.extend_from_slice(b"payload");
+            let mut reader = &file[..];
+            let (parsed, aad) = Header::read_from(&mut reader).unwrap();
+            assert_eq!(reader, b"payload");
+
+            assert_eq!(parsed.version, version);
+            assert_eq!(parsed.cipher, header.cipher);
+            assert_eq!(parsed.chunk_size, header.chunk_size);
+            assert_eq!(parsed.nonce, header.nonce);
+            assert_eq!(parsed.key_slots, header.key_slots);
+            assert_eq!(parsed.metadata, header.metadata);
+            assert_eq!(aad, header.associated_data());
+            assert_eq!(parsed.encode().unwrap(), raw);
+        }
+    }
+
+    #[test]
+    fn version_3_cannot_hold_metadata() {
+        let mut header = slotted(3);
+        header.metadata = vec![8; 40];
+        assert!(header.encode().is_err());
+    }
+
+    #[test]
+    fn rejects_excessive_kdf_parameters() {
+        let too_much_memory = KdfParams { m_cost: MAX_M_COST + 1, ..KdfParams::default() };
+        let too_many_passes = KdfParams { t_cost: MAX_T_COST + 1, ..KdfParams::default() };
+
+        for params in [too_much_memory, too_many_passes] {
+            let mut body = legacy_body(CipherAlgorithm::Aes256Gcm, params);
+            body.extend_from_slice(&[2; V1_NONCE_LEN]);
+            let result = Header::read_from(&mut &with_prefix(1, &body)[..]);
+            assert!(matches!(result, Err(EncryptionError::Format(_))));
+
+            let mut header = slotted(4);
+            header.key_slots = vec![passphrase_slot(params)];
+            let raw = header.encode().unwrap();
+            let result = Header::read_from(&mut &raw[..]);
+            assert!(matches!(result, Err(EncryptionError::Format(_))));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let raw = slotted(4).encode().unwrap();
+
+        let mut bad_magic = raw.clone();
+        bad_magic[0] = b'X';
+        assert!(Header::read_from(&mut &bad_magic[..]).is_err());
+
+        let mut bad_version = raw.clone();
+        bad_version[4] = 5;
+        assert!(Header::read_from(&mut &bad_version[..]).is_err());
+
+        assert!(Header::read_from(&mut &raw[..raw.len() - 1]).is_err());
+        assert!(Header::read_from(&mut &raw[..3]).is_err());
+
+        let mut no_slots = slotted(4);
+        no_slots.key_slots.clear();
+        assert!(Header::read_from(&mut &no_slots.encode().unwrap()[..]).is_err());
+
+        let mut huge_chunks = slotted(4);
+        huge_chunks.chunk_size = Some(MAX_CHUNK_SIZE + 1);
+        assert!(Header::read_from(&mut &huge_chunks.encode().unwrap()[..]).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+use super::EncryptionError;
+
+/// Length of the random salt fed to the passphrase KDF
+pub const SALT_LEN: usize = 16;
+
+/// Largest memory cost accepted from a file header, in KiB (1 GiB)
+pub const MAX_M_COST: u32 = 1024 * 1024;
+
+/// Largest number of iterations accepted from a file header
+pub const MAX_T_COST: u32 = 10;
+
+/// Largest degree of parallelism accepted from a file header
+pub const MAX_P_COST: u32 = 8;
+
+/// Key derivation function used to turn a passphrase into a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfAlgorithm {
+    Argon2id,
+}
+
+impl KdfAlgorithm {
+    pub fn id(self) -> u8 {
+        match self {
+            KdfAlgorithm::Argon2id => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, EncryptionError> {
+        match id {
+            1 => Ok(KdfAlgorithm::Argon2id),
+            _ => Err(EncryptionError::Format(format!("Unknown KDF {}", id))),
+        }
+    }
+}
+
+/// Cost parameters for the Argon2id passphrase KDF
+///
+/// These are stored alongside every encrypted file so the defaults can be
+/// raised later without breaking files that were written with older values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory cost in KiB
+    pub m_cost: u32,
+
+    /// Number of iterations
+    pub t_cost: u32,
+
+    /// Degree of parallelism
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    /// Size of the serialized parameters in bytes
+    pub const ENCODED_LEN: usize = 12;
+
+    /// Serializes the parameters as three little-endian u32 values
+    pub fn to_bytes(self) -> [u8; Self::ENCODED_LEN] {
+        let mut out = [0u8; Self::ENCODED_LEN];
+        out[0..4].copy_from_slice(&self.m_cost.to_le_bytes());
+        out[4..8].copy_from_slice(&self.t_cost.to_le_bytes());
+        out[8..12].copy_from_slice(&self.p_cost.to_le_bytes());
+        out
+    }
+
+    /// Parses parameters previously written by `to_bytes`
+    ///
+    /// Headers are untrusted, so costs above `MAX_M_COST`, `MAX_T_COST` or
+    /// `MAX_P_COST` are rejected before anything tries to allocate for them.
+    pub fn from_bytes(bytes: &[u8; Self::ENCODED_LEN]) -> Result<Self, EncryptionError> {
+        let word = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let params = Self {
+            m_cost: word(0),
+            t_cost: word(4),
+            p_cost: word(8),
+        };
+
+        if params.m_cost > MAX_M_COST || params.t_cost > MAX_T_COST || params.p_cost > MAX_P_COST {
+            return Err(EncryptionError::Format(format!(
+                "KDF parameters exceed the limits ({} KiB, {} passes, {} lanes)",
+                params.m_cost, params.t_cost, params.p_cost,
+            )));
+        }
+        Ok(params)
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        // 64 MiB, 3 passes, single lane
+        Self {
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+}
+
+/// Generates a random salt for the passphrase KDF
+pub fn generate_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Derives a key from a passphrase using Argon2id
+pub fn generate_key_from_passphrase(
+    passphrase: &str,
+    salt: &[u8],
+    params: &KdfParams,
+) -> Result<[u8; 32], EncryptionError> {
+    let argon_params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| EncryptionError::KeyGeneration(e.to_string()))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params);
+
+    let mut key = [0u8; 32];
+    argon2.hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| EncryptionError::KeyGeneration(e.to_string()))?;
+    Ok(key)
+}
+
+/// Hashes a password for storage as an Argon2id PHC string
+pub fn hash_password(password: &str) -> Result<String, EncryptionError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| EncryptionError::KeyGeneration(e.to_string()))
+}
+
+/// Checks a password against a PHC string written by `hash_password`
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap parameters, so tests do not spend seconds in Argon2
+    const FAST: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn params_round_trip() {
+        let params = KdfParams {
+            m_cost: MAX_M_COST,
+            t_cost: MAX_T_COST,
+            p_cost: MAX_P_COST,
+        };
+        assert_eq!(KdfParams::from_bytes(&params.to_bytes()).unwrap(), params);
+        assert_eq!(KdfParams::from_bytes(&KdfParams::default().to_bytes()).unwrap(), KdfParams::default());
+    }
+
+    #[test]
+    fn params_above_the_limits_are_rejected() {
+        let too_high = [
+            KdfParams { m_cost: MAX_M_COST + 1, ..KdfParams::default() },
+            KdfParams { t_cost: MAX_T_COST + 1, ..KdfParams::default() },
+            KdfParams { p_cost: MAX_P_COST + 1, ..KdfParams::default() },
+            KdfParams { m_cost: u32::MAX, t_cost: u32::MAX, p_cost: u32::MAX },
+        ];
+        for params in too_high {
+            let result = KdfParams::from_bytes(&params.to_bytes());
+            assert!(matches!(result, Err(EncryptionError::Format(_))), "{:?} accepted", params);
+        }
+    }
+
+    #[test]
+    fn key_depends_on_passphrase_salt_and_params() {
+        let salt = [1; SALT_LEN];
+        let key = generate_key_from_passphrase("secret", &salt, &FAST).unwrap();
+        assert_eq!(generate_key_from_passphrase("secret", &salt, &FAST).unwrap(), key);
+
+        assert_ne!(generate_key_from_passphrase("Secret", &salt, &FAST).unwrap(), key);
+        assert_ne!(generate_key_from_passphrase("secret", &[2; SALT_LEN], &FAST).unwrap(), key);
+        let slower = KdfParams { t_cost: 2, ..FAST };
+        assert_ne!(generate_key_from_passphrase("secret", &salt, &slower).unwrap(), key);
+    }
+
+    #[test]
+    fn invalid_params_fail_key_generation() {
+        let too_little_memory = KdfParams { m_cost: 1, ..FAST };
+        let result = generate_key_from_passphrase("secret", &[1; SALT_LEN], &too_little_memory);
+        assert!(matches!(result, Err(EncryptionError::KeyGeneration(_))));
+    }
+
+    #[test]
+    fn salts_are_random() {
+        assert_ne!(generate_salt(), generate_salt());
+    }
+
+    #[test]
+    fn password_hashes_verify() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("correct horse ", &hash));
+        assert!(!verify_password("", &hash));
+
+        // Every hash gets its own salt
+        assert_ne!(hash_password("correct horse").unwrap(), hash);
+    }
+
+    #[test]
+    fn malformed_hashes_never_verify() {
+        assert!(!verify_password("anything", ""));
+        assert!(!verify_password("anything", "not a hash"));
+        assert!(!verify_password("anything", "$argon2id$v=19$m=19456,t=2,p=1$bad"));
+    }
+}
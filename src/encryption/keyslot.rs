@@ -0,0 +1,236 @@
+//! Key slots hold the per-file data key wrapped for one recipient.
+//!
+//! Every version 3 file is encrypted under a random data key. The header
+//! carries that key wrapped for whoever may open the file: either under a
+//! key derived from a passphrase, or under an RSA public key with OAEP.
+
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use std::fmt;
+
+use super::cipher::{CipherAlgorithm, FileCipher};
+use super::kdf::{generate_key_from_passphrase, generate_salt, KdfAlgorithm, KdfParams, SALT_LEN};
+use super::{decrypt_key_with_rsa, encrypt_key_with_rsa, public_key_fingerprint, EncryptionError};
+
+/// Length of the random per-file data key
+pub const DATA_KEY_LEN: usize = 32;
+
+/// Length of an RSA public key fingerprint (SHA-256 of the SPKI DER)
+pub const FINGERPRINT_LEN: usize = 32;
+
+/// Who a file is being encrypted for
+#[derive(Clone)]
+pub enum Recipient {
+    Passphrase(String),
+    RsaPublicKey(Box<RsaPublicKey>),
+
+    /// age X25519 public key, only usable with the age format
+    AgeX25519(Box<age::x25519::Recipient>),
+}
+
+/// Credential used to open a file
+#[derive(Clone)]
+pub enum Identity {
+    Passphrase(String),
+    RsaPrivateKey(Box<RsaPrivateKey>),
+
+    /// age X25519 secret key, only usable with the age format
+    AgeX25519(Box<age::x25519::Identity>),
+}
+
+// Credentials are kept out of debug output so they never end up in logs
+impl fmt::Debug for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recipient::Passphrase(_) => f.write_str("Recipient::Passphrase"),
+            Recipient::RsaPublicKey(_) => f.write_str("Recipient::RsaPublicKey"),
+            Recipient::AgeX25519(r) => write!(f, "Recipient::AgeX25519({})", r),
+        }
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identity::Passphrase(_) => f.write_str("Identity::Passphrase"),
+            Identity::RsaPrivateKey(_) => f.write_str("Identity::RsaPrivateKey"),
+            Identity::AgeX25519(_) => f.write_str("Identity::AgeX25519"),
+        }
+    }
+}
+
+/// A data key wrapped for a single recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySlot {
+    /// Data key sealed under a passphrase-derived key
+    ///
+    /// `wrapped_key` is `None` for version 1 and 2 files, where the KDF
+    /// output is used directly as the data key.
+    Passphrase {
+        kdf: KdfAlgorithm,
+        salt: [u8; SALT_LEN],
+        params: KdfParams,
+        wrapped_key: Option<Vec<u8>>,
+    },
+
+    /// Data key encrypted with RSA-OAEP-SHA256
+    Rsa {
+        fingerprint: [u8; FINGERPRINT_LEN],
+        wrapped_key: Vec<u8>,
+    },
+}
+
+impl KeySlot {
+    /// Identifier stored in the header ahead of each slot
+    pub fn type_id(&self) -> u8 {
+        match self {
+            KeySlot::Passphrase { .. } => 1,
+            KeySlot::Rsa { .. } => 2,
+        }
+    }
+
+    /// Wraps `data_key` for `recipient`
+    ///
+    /// Passphrase slots are sealed with `cipher`, the same AEAD used for the
+    /// payload, under a fresh nonce.
+    pub fn wrap(
+        data_key: &[u8; DATA_KEY_LEN],
+        recipient: &Recipient,
+        cipher: CipherAlgorithm,
+    ) -> Result<Self, EncryptionError> {
+        match recipient {
+            Recipient::Passphrase(passphrase) => {
+                let salt = generate_salt();
+                let params = KdfParams::default();
+                let kek = generate_key_from_passphrase(passphrase, &salt, &params)?;
+
+                let mut nonce = vec![0u8; cipher.nonce_len()];
+                OsRng.fill_bytes(&mut nonce);
+                let sealed = FileCipher::new(cipher, &kek).encrypt(&nonce, data_key, &[])?;
+
+                let mut wrapped_key = nonce;
+                wrapped_key.extend_from_slice(&sealed);
+                Ok(KeySlot::Passphrase {
+                    kdf: KdfAlgorithm::Argon2id,
+                    salt,
+                    params,
+                    wrapped_key: Some(wrapped_key),
+                })
+            }
+            Recipient::RsaPublicKey(public_key) => {
+                let wrapped_key = encrypt_key_with_rsa(data_key, public_key)?;
+                Ok(KeySlot::Rsa {
+                    fingerprint: public_key_fingerprint(public_key)?,
+                    wrapped_key,
+                })
+            }
+            Recipient::AgeX25519(_) => Err(EncryptionError::Encryption(
+                "age recipients can only be used with the age format".to_string(),
+            )),
+        }
+    }
+
+    /// Attempts to recover the data key with `identity`
+    ///
+    /// Returns `Ok(None)` when the slot was not made for this kind of
+    /// identity or for this particular key, so callers can try the next slot.
+    pub fn unwrap_key(
+        &self,
+        identity: &Identity,
+        cipher: CipherAlgorithm,
+    ) -> Result<Option<[u8; DATA_KEY_LEN]>, EncryptionError> {
+        match (self, identity) {
+            (KeySlot::Passphrase { kdf, salt, params, wrapped_key }, Identity::Passphrase(passphrase)) => {
+                let derived = match kdf {
+                    KdfAlgorithm::Argon2id => generate_key_from_passphrase(passphrase, salt, params)?,
+                };
+                let Some(wrapped_key) = wrapped_key else {
+                    return Ok(Some(derived));
+                };
+
+                let nonce_len = cipher.nonce_len();
+                if wrapped_key.len() < nonce_len {
+                    return Err(EncryptionError::Format("Truncated key slot".to_string()));
+                }
+                let (nonce, sealed) = wrapped_key.split_at(nonce_len);
+                match FileCipher::new(cipher, &derived).decrypt(nonce, sealed, &[]) {
+                    Ok(key) => Ok(Some(to_data_key(&key)?)),
+                    Err(_) => Ok(None),
+                }
+            }
+            (KeySlot::Rsa { fingerprint, wrapped_key }, Identity::RsaPrivateKey(private_key)) => {
+                let public_key = RsaPublicKey::from(private_key.as_ref());
+                if public_key_fingerprint(&public_key)? != *fingerprint {
+                    return Ok(None);
+                }
+                let key = decrypt_key_with_rsa(wrapped_key, private_key)?;
+                Ok(Some(to_data_key(&key)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Serializes the slot body, without the type and length prefix
+    pub fn encode_body(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            KeySlot::Passphrase { kdf, salt, params, wrapped_key } => {
+                body.push(kdf.id());
+                body.extend_from_slice(salt);
+                body.extend_from_slice(&params.to_bytes());
+                if let Some(wrapped_key) = wrapped_key {
+                    body.extend_from_slice(wrapped_key);
+                }
+            }
+            KeySlot::Rsa { fingerprint, wrapped_key } => {
+                body.extend_from_slice(fingerprint);
+                body.extend_from_slice(wrapped_key);
+            }
+        }
+        body
+    }
+
+    /// Parses a slot body previously written by `encode_body`
+    pub fn parse(type_id: u8, body: &[u8]) -> Result<Self, EncryptionError> {
+        let truncated = || EncryptionError::Format("Truncated key slot".to_string());
+        match type_id {
+            1 => {
+                if body.len() <= 1 + SALT_LEN + KdfParams::ENCODED_LEN {
+                    return Err(truncated());
+                }
+                let kdf = KdfAlgorithm::from_id(body[0])?;
+                let (salt, rest) = body[1..].split_at(SALT_LEN);
+                let (params, wrapped_key) = rest.split_at(KdfParams::ENCODED_LEN);
+                Ok(KeySlot::Passphrase {
+                    kdf,
+                    salt: salt.try_into().unwrap(),
+                    params: KdfParams::from_bytes(params.try_into().unwrap())?,
+                    wrapped_key: Some(wrapped_key.to_vec()),
+                })
+            }
+            2 => {
+                if body.len() <= FINGERPRINT_LEN {
+                    return Err(truncated());
+                }
+                let (fingerprint, wrapped_key) = body.split_at(FINGERPRINT_LEN);
+                Ok(KeySlot::Rsa {
+                    fingerprint: fingerprint.try_into().unwrap(),
+                    wrapped_key: wrapped_key.to_vec(),
+                })
+            }
+            t => Err(EncryptionError::Format(format!("Unknown key slot type {}", t))),
+        }
+    }
+}
+
+/// Generates a random data key
+pub fn generate_data_key() -> [u8; DATA_KEY_LEN] {
+    let mut key = [0u8; DATA_KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+fn to_data_key(bytes: &[u8]) -> Result<[u8; DATA_KEY_LEN], EncryptionError> {
+    bytes.try_into()
+        .map_err(|_| EncryptionError::Decryption("Unwrapped key has the wrong length".to_string()))
+}
@@ -0,0 +1,135 @@
+//! Attributes of the original file, sealed into the header under the data key.
+//!
+//! Without this the stored file would have to carry the original name and
+//! content type in the clear for them to survive a round trip.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::cipher::FileCipher;
+use super::header::Header;
+use super::stream::metadata_nonce;
+use super::EncryptionError;
+
+/// Original attributes of an encrypted file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// Original filename
+    pub filename: String,
+
+    /// MIME type of the original file
+    pub content_type: Option<String>,
+
+    /// Timestamp when the original file was uploaded
+    pub uploaded_at: DateTime<Utc>,
+}
+
+impl FileMetadata {
+    /// Seals the metadata for `header` with the file's data key
+    ///
+    /// The header's associated data is bound in, so the block cannot be moved
+    /// to another file.
+    pub fn seal(&self, cipher: &FileCipher, header: &Header) -> Result<Vec<u8>, EncryptionError> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| EncryptionError::Encryption(e.to_string()))?;
+        cipher.encrypt(&metadata_nonce(&header.nonce), &json, &header.associated_data())
+    }
+
+    /// Opens the sealed metadata block of `header`, if it has one
+    pub fn open(cipher: &FileCipher, header: &Header) -> Result<Option<Self>, EncryptionError> {
+        if header.metadata.is_empty() {
+            return Ok(None);
+        }
+
+        let json = cipher.decrypt(&metadata_nonce(&header.nonce), &header.metadata, &header.associated_data())?;
+        serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|e| EncryptionError::Format(format!("Invalid metadata: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::cipher::CipherAlgorithm;
+    use crate::encryption::kdf::{KdfAlgorithm, KdfParams, SALT_LEN};
+    use crate::encryption::keyslot::KeySlot;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn metadata() -> FileMetadata {
+        FileMetadata {
+            filename: "report.pdf".to_string(),
+            content_type: Some("application/pdf".to_string()),
+            uploaded_at: Utc::now(),
+        }
+    }
+
+    /// A current header with `metadata` sealed into it
+    ///
+    /// The key slot is never opened, so it holds filler rather than running Argon2.
+    fn sealed_header(algorithm: CipherAlgorithm) -> Header {
+        let slot = KeySlot::Passphrase {
+            kdf: KdfAlgorithm::Argon2id,
+            salt: [5; SALT_LEN],
+            params: KdfParams::default(),
+            wrapped_key: Some(vec![9; 60]),
+        };
+        let nonce_prefix = vec![3; Header::nonce_prefix_len(algorithm)];
+        let mut header = Header::new(algorithm, 64 * 1024, nonce_prefix, vec![slot]);
+        header.metadata = metadata().seal(&FileCipher::new(algorithm, &KEY), &header).unwrap();
+        header
+    }
+
+    #[test]
+    fn sealed_metadata_round_trips_through_the_header() {
+        for algorithm in [CipherAlgorithm::Aes256Gcm, CipherAlgorithm::ChaCha20Poly1305, CipherAlgorithm::XChaCha20Poly1305] {
+            let header = sealed_header(algorithm);
+            let encoded = header.encode().unwrap();
+            let (parsed, _) = Header::read_from(&mut &encoded[..]).unwrap();
+
+            assert_eq!(parsed.version, 4);
+            let opened = FileMetadata::open(&FileCipher::new(algorithm, &KEY), &parsed).unwrap();
+            assert_eq!(opened.map(|m| m.filename), Some("report.pdf".to_string()));
+        }
+    }
+
+    #[test]
+    fn missing_metadata_opens_as_none() {
+        let mut header = sealed_header(CipherAlgorithm::Aes256Gcm);
+        header.metadata.clear();
+        assert_eq!(FileMetadata::open(&FileCipher::new(CipherAlgorithm::Aes256Gcm, &KEY), &header).unwrap(), None);
+    }
+
+    #[test]
+    fn wrong_keys_are_rejected() {
+        let header = sealed_header(CipherAlgorithm::Aes256Gcm);
+        let result = FileMetadata::open(&FileCipher::new(CipherAlgorithm::Aes256Gcm, &[8; 32]), &header);
+        assert!(matches!(result, Err(EncryptionError::Decryption(_))));
+    }
+
+    #[test]
+    fn tampering_is_rejected() {
+        let cipher = FileCipher::new(CipherAlgorithm::Aes256Gcm, &KEY);
+
+        // A flipped bit in the sealed block
+        let mut header = sealed_header(CipherAlgorithm::Aes256Gcm);
+        header.metadata[0] ^= 1;
+        assert!(FileMetadata::open(&cipher, &header).is_err());
+
+        // A different nonce prefix, as if the block were moved to another file
+        let mut header = sealed_header(CipherAlgorithm::Aes256Gcm);
+        header.nonce[0] ^= 1;
+        assert!(FileMetadata::open(&cipher, &header).is_err());
+
+        // A block sealed under a chunk nonce rather than the metadata flag 2
+        let mut header = sealed_header(CipherAlgorithm::Aes256Gcm);
+        let json = serde_json::to_vec(&metadata()).unwrap();
+        let mut nonce = metadata_nonce(&header.nonce);
+        for flag in [0, 1] {
+            *nonce.last_mut().unwrap() = flag;
+            header.metadata = cipher.encrypt(&nonce, &json, &header.associated_data()).unwrap();
+            assert!(matches!(FileMetadata::open(&cipher, &header), Err(EncryptionError::Decryption(_))), "flag {}", flag);
+        }
+    }
+}
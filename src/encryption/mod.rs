@@ -0,0 +1,846 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use rsa::{
+    RsaPrivateKey, RsaPublicKey, Oaep,
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePublicKey, EncodePrivateKey, LineEnding},
+};
+use sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Chain, Cursor, Read, Write};
+use std::thread;
+use thiserror::Error;
+
+pub mod age_format;
+pub mod cipher;
+pub mod header;
+pub mod kdf;
+pub mod keyslot;
+pub mod metadata;
+pub mod stream;
+
+pub use age_format::{decrypt_age_stream, encrypt_age_stream, open_age_stream, parse_age_identity, parse_age_recipient};
+pub use cipher::{CipherAlgorithm, FileCipher};
+pub use header::Header;
+pub use keyslot::{generate_data_key, Identity, KeySlot, Recipient, DATA_KEY_LEN};
+pub use metadata::FileMetadata;
+pub use stream::DEFAULT_CHUNK_SIZE;
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+    
+    #[error("Decryption error: {0}")]
+    Decryption(String),
+    
+    #[error("Key generation error: {0}")]
+    KeyGeneration(String),
+    
+    #[error("Format error: {0}")]
+    Format(String),
+    
+    #[error("Key slot error: {0}")]
+    KeySlot(String),
+}
+
+/// Encrypts a stream for `recipients` using the chosen chunked AEAD
+///
+/// A random data key encrypts the payload and is wrapped for each recipient
+/// in its own key slot of the versioned `Header`, which is followed by the
+/// sealed chunks. Any one recipient can decrypt the file. `metadata`, if
+/// given, is sealed into the header. Runs in constant memory.
+pub fn encrypt_stream<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    recipients: &[Recipient],
+    algorithm: CipherAlgorithm,
+    metadata: Option<&FileMetadata>,
+) -> Result<u64, EncryptionError> {
+    if recipients.is_empty() {
+        return Err(EncryptionError::KeySlot("At least one recipient is required".to_string()));
+    }
+    
+    // Generate the data key and wrap it for every recipient
+    let data_key = generate_data_key();
+    let key_slots = recipients
+        .iter()
+        .map(|recipient| KeySlot::wrap(&data_key, recipient, algorithm))
+        .collect::<Result<Vec<_>, _>>()?;
+    
+    // Create cipher
+    let cipher = FileCipher::new(algorithm, &data_key);
+    
+    // Generate a random nonce prefix and build the header
+    let mut nonce_prefix = vec![0u8; Header::nonce_prefix_len(algorithm)];
+    OsRng.fill_bytes(&mut nonce_prefix);
+    let mut header = Header::new(algorithm, DEFAULT_CHUNK_SIZE, nonce_prefix, key_slots);
+    if let Some(metadata) = metadata {
+        header.metadata = metadata.seal(&cipher, &header)?;
+    }
+    
+    // Write the header followed by the encrypted chunks
+    writer.write_all(&header.encode()?)?;
+    stream::encrypt_chunks(
+        &cipher,
+        &header.nonce,
+        DEFAULT_CHUNK_SIZE as usize,
+        &header.associated_data(),
+        reader,
+        writer,
+    )
+}
+
+/// Recovers the data key from the first key slot that `identity` opens
+pub fn unwrap_data_key(
+    header: &Header,
+    identity: &Identity,
+) -> Result<[u8; DATA_KEY_LEN], EncryptionError> {
+    open_key_slot(header, identity).map(|(_, key)| key)
+}
+
+/// Finds the first key slot that `identity` opens, returning its index and the data key
+fn open_key_slot(
+    header: &Header,
+    identity: &Identity,
+) -> Result<(usize, [u8; DATA_KEY_LEN]), EncryptionError> {
+    for (index, slot) in header.key_slots.iter().enumerate() {
+        if let Some(key) = slot.unwrap_key(identity, header.cipher)? {
+            return Ok((index, key));
+        }
+    }
+    Err(EncryptionError::Decryption("No key slot matches the supplied credential".to_string()))
+}
+
+/// Adds a key slot for `recipient` to an encrypted stream
+///
+/// `identity` must open one of the existing slots. The payload is copied
+/// through unchanged, since key slots are not part of its associated data.
+pub fn add_recipient_stream<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    identity: &Identity,
+    recipient: &Recipient,
+) -> Result<(), EncryptionError> {
+    let (header, _) = Header::read_from(reader)?;
+    rewrite_key_slots(header, reader, writer, identity, |header, _, data_key| {
+        let slot = KeySlot::wrap(data_key, recipient, header.cipher)?;
+        header.key_slots.push(slot);
+        Ok(())
+    })
+}
+
+/// Removes the key slot at `index` from an encrypted stream
+///
+/// `identity` must open one of the existing slots. The last remaining slot
+/// cannot be removed.
+pub fn remove_key_slot_stream<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    identity: &Identity,
+    index: usize,
+) -> Result<(), EncryptionError> {
+    let (header, _) = Header::read_from(reader)?;
+    rewrite_key_slots(header, reader, writer, identity, |header, _, _| {
+        if index >= header.key_slots.len() {
+            return Err(EncryptionError::KeySlot(format!("No key slot at index {}", index)));
+        }
+        if header.key_slots.len() == 1 {
+            return Err(EncryptionError::KeySlot("Cannot remove the only key slot".to_string()));
+        }
+        header.key_slots.remove(index);
+        Ok(())
+    })
+}
+
+/// Replaces the key slot that `old` opens with one for `new`
+///
+/// Only the header is rewritten for files with key slots (version 3 and
+/// later). Version 1 and 2 files derive the payload key from the passphrase
+/// itself, and files from before the header existed hash it, so they are
+/// decrypted and re-encrypted as `Header::VERSION` in a single pass; the
+/// plaintext only ever passes through memory. As with `decrypt_stream`,
+/// output written before an error must be discarded.
+pub fn rekey_stream<R: Read + Send, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    old: &Identity,
+    new: &Recipient,
+) -> Result<(), EncryptionError> {
+    // Peek at the magic bytes, then put them back in front of the stream
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)
+        .map_err(|_| EncryptionError::Format("File too short".to_string()))?;
+    let mut reader = Cursor::new(magic).chain(reader);
+    
+    if magic[..] == age_format::AGE_MAGIC[..4] {
+        return Err(EncryptionError::Format("age files cannot be rekeyed".to_string()));
+    }
+    
+    // Headerless files are a single message, decrypted in memory anyway
+    if magic != header::MAGIC {
+        let plaintext = decrypt_headerless(&mut reader, old)?;
+        let algorithm = CipherAlgorithm::Aes256Gcm;
+        return encrypt_stream(&mut &plaintext[..], writer, std::slice::from_ref(new), algorithm, None).map(|_| ());
+    }
+    
+    let (header, aad) = Header::read_from(&mut reader)?;
+    
+    if header.has_key_slots() {
+        return rewrite_key_slots(header, &mut reader, writer, old, |header, index, data_key| {
+            header.key_slots[index] = KeySlot::wrap(data_key, new, header.cipher)?;
+            Ok(())
+        });
+    }
+    
+    // Feed the decrypted payload straight into a fresh encryption
+    let (mut pipe_reader, mut pipe_writer) = io::pipe()?;
+    let algorithm = header.cipher;
+    thread::scope(|scope| {
+        let decryptor = scope.spawn(move || {
+            decrypt_payload(&header, &aad, old, &mut reader, &mut pipe_writer)
+        });
+        
+        let encrypted = encrypt_stream(&mut pipe_reader, writer, std::slice::from_ref(new), algorithm, None);
+        
+        // Unblock the decryptor if encryption stopped early
+        drop(pipe_reader);
+        let decrypted = match decryptor.join() {
+            Ok(decrypted) => decrypted,
+            Err(panic) => std::panic::resume_unwind(panic),
+        };
+        
+        // A failed encryption leaves the decryptor with only a broken pipe to report
+        encrypted?;
+        decrypted?;
+        Ok(())
+    })
+}
+
+/// Rewrites the header of a current-version stream after `edit` changes its key slots
+///
+/// `edit` is given the index of the slot `identity` opened and the data key.
+fn rewrite_key_slots<R, W, F>(
+    mut header: Header,
+    reader: &mut R,
+    writer: &mut W,
+    identity: &Identity,
+    edit: F,
+) -> Result<(), EncryptionError>
+where
+    R: Read,
+    W: Write,
+    F: FnOnce(&mut Header, usize, &[u8; DATA_KEY_LEN]) -> Result<(), EncryptionError>,
+{
+    // Older versions authenticate the whole header, so it cannot change
+    if !header.has_key_slots() {
+        return Err(EncryptionError::Format(format!(
+            "Key slots of version {} files cannot be changed",
+            header.version
+        )));
+    }
+    
+    // Only someone who can already open the file may change its slots
+    let (index, data_key) = open_key_slot(&header, identity)?;
+    edit(&mut header, index, &data_key)?;
+    
+    writer.write_all(&header.encode()?)?;
+    io::copy(reader, writer)?;
+    
+    Ok(())
+}
+
+/// Container format an encrypted file is written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerFormat {
+    /// SilentLock's own `SLCK` container
+    #[default]
+    SilentLock,
+    
+    /// age v1, interoperable with the `age` command line tool
+    Age,
+}
+
+/// Decrypts a stream written by `encrypt_stream` or `encrypt_age_stream`
+///
+/// The container format is detected from the leading magic bytes. Returns the
+/// original file's metadata if the container carries any. Plaintext is
+/// written as each chunk authenticates; if an error is returned the output
+/// must be discarded.
+pub fn decrypt_stream<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    identity: &Identity,
+) -> Result<Option<FileMetadata>, EncryptionError> {
+    let mut opened = open_stream(reader, identity)?;
+    let metadata = opened.metadata.take();
+    opened.decrypt_to(writer)?;
+    
+    Ok(metadata)
+}
+
+/// Opens a stream written by `encrypt_stream` or `encrypt_age_stream`
+///
+/// Reads the header and recovers the data key with `identity`, so a wrong
+/// credential fails here, before any payload is read. The payload itself is
+/// decrypted by `OpenedStream::decrypt_to`. Version 2 files have nothing to
+/// check a passphrase against but the payload, so their first chunk is
+/// authenticated here. Version 1 and headerless files are a single AEAD
+/// message, which is decrypted into memory here instead; they are capped at
+/// `MAX_SINGLE_MESSAGE_LEN`.
+pub fn open_stream<R: Read>(mut reader: R, identity: &Identity) -> Result<OpenedStream<R>, EncryptionError> {
+    // Peek at the magic bytes, then put them back in front of the stream
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)
+        .map_err(|_| EncryptionError::Format("File too short".to_string()))?;
+    let mut reader = Cursor::new(magic).chain(reader);
+    
+    if magic[..] == age_format::AGE_MAGIC[..4] {
+        return Ok(OpenedStream {
+            metadata: None,
+            payload: Payload::Age(open_age_stream(reader, identity)?),
+        });
+    }
+    
+    // Files from before the container had a header start with a bare nonce
+    if magic != header::MAGIC {
+        let plaintext = decrypt_headerless(&mut reader, identity)?;
+        return Ok(OpenedStream {
+            metadata: None,
+            payload: Payload::Decrypted(Cursor::new(plaintext)),
+        });
+    }
+    
+    // Parse the header; this rejects unknown versions
+    let (header, aad) = Header::read_from(&mut reader)?;
+    
+    if header.version == 1 {
+        let mut plaintext = Vec::new();
+        decrypt_payload(&header, &aad, identity, &mut reader, &mut plaintext)?;
+        return Ok(OpenedStream {
+            metadata: None,
+            payload: Payload::Decrypted(Cursor::new(plaintext)),
+        });
+    }
+    
+    let data_key = unwrap_data_key(&header, identity)?;
+    let cipher = FileCipher::new(header.cipher, &data_key);
+    let metadata = FileMetadata::open(&cipher, &header)?;
+    
+    // Without a key slot only the payload tells whether the key is right
+    let peeked = match header.chunk_size {
+        Some(chunk_size) if !header.has_key_slots() => {
+            stream::check_first_chunk(&cipher, &header.nonce, chunk_size as usize, &aad, &mut reader)?
+        },
+        _ => Vec::new(),
+    };
+    
+    Ok(OpenedStream {
+        metadata,
+        payload: Payload::SilentLock {
+            header,
+            aad,
+            cipher,
+            peeked,
+            reader,
+        },
+    })
+}
+
+/// An encrypted stream whose data key has been recovered by `open_stream`
+pub struct OpenedStream<R: Read> {
+    metadata: Option<FileMetadata>,
+    payload: Payload<R>,
+}
+
+/// Payload of an opened stream, by container format
+enum Payload<R: Read> {
+    SilentLock {
+        header: Header,
+        aad: Vec<u8>,
+        cipher: FileCipher,
+        
+        /// Sealed bytes already read from `reader` to check the key
+        peeked: Vec<u8>,
+        reader: Chain<Cursor<[u8; 4]>, R>,
+    },
+    Age(age::stream::StreamReader<Chain<Cursor<[u8; 4]>, R>>),
+    
+    /// Plaintext of a single-message file, already decrypted and authenticated
+    Decrypted(Cursor<Vec<u8>>),
+}
+
+impl<R: Read> OpenedStream<R> {
+    /// The original file's metadata, if the container carries any
+    pub fn metadata(&self) -> Option<&FileMetadata> {
+        self.metadata.as_ref()
+    }
+    
+    /// Decrypts the payload into `writer`, returning the plaintext length
+    ///
+    /// Plaintext is written as each chunk authenticates; if an error is
+    /// returned the output must be discarded.
+    pub fn decrypt_to<W: Write>(self, writer: &mut W) -> Result<u64, EncryptionError> {
+        match self.payload {
+            Payload::SilentLock { header, aad, cipher, peeked, reader } => {
+                decrypt_body(&cipher, &header, &aad, &mut Cursor::new(peeked).chain(reader), writer)
+            },
+            Payload::Age(mut plaintext) => {
+                io::copy(&mut plaintext, writer).map_err(age_format::map_payload_error)
+            },
+            Payload::Decrypted(mut plaintext) => Ok(io::copy(&mut plaintext, writer)?),
+        }
+    }
+}
+
+/// Decrypts the payload following an already parsed SilentLock header
+fn decrypt_payload<R: Read, W: Write>(
+    header: &Header,
+    aad: &[u8],
+    identity: &Identity,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<Option<FileMetadata>, EncryptionError> {
+    let data_key = unwrap_data_key(header, identity)?;
+    let cipher = FileCipher::new(header.cipher, &data_key);
+    let metadata = FileMetadata::open(&cipher, header)?;
+    
+    decrypt_body(&cipher, header, aad, reader, writer)?;
+    
+    Ok(metadata)
+}
+
+/// Decrypts a SilentLock payload with the cipher of its recovered data key
+fn decrypt_body<R: Read, W: Write>(
+    cipher: &FileCipher,
+    header: &Header,
+    aad: &[u8],
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<u64, EncryptionError> {
+    match (header.version, header.chunk_size) {
+        (1, _) => decrypt_v1(cipher, header, aad, reader, writer),
+        (2..=4, Some(chunk_size)) => stream::decrypt_chunks(
+            cipher,
+            &header.nonce,
+            chunk_size as usize,
+            aad,
+            reader,
+            writer,
+        ),
+        (v, _) => Err(EncryptionError::Format(format!("Unsupported format version {}", v))),
+    }
+}
+
+/// Nonce length of files written before the container had a header
+const HEADERLESS_NONCE_LEN: usize = 12;
+
+/// Largest single-message payload that is read, in bytes
+///
+/// Headerless and version 1 files hold one AEAD message that has to be
+/// decrypted in memory. Neither was ever written for files over 100 MiB.
+pub const MAX_SINGLE_MESSAGE_LEN: u64 = 100 * 1024 * 1024 + 64;
+
+/// Reads a whole single-message payload, refusing ones over `limit` bytes
+fn read_single_message<R: Read>(reader: &mut R, limit: u64) -> Result<Vec<u8>, EncryptionError> {
+    let mut buffer = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut buffer)?;
+    if buffer.len() as u64 > limit {
+        return Err(EncryptionError::Format("Single-message payload too large".to_string()));
+    }
+    Ok(buffer)
+}
+
+/// Decrypts a file written before the container had a header
+///
+/// These are a 12-byte nonce followed by a single AES-256-GCM message, under
+/// the SHA-256 hash of the passphrase and without associated data. They can
+/// still be read but are no longer written.
+fn decrypt_headerless<R: Read>(reader: &mut R, identity: &Identity) -> Result<Vec<u8>, EncryptionError> {
+    let Identity::Passphrase(passphrase) = identity else {
+        return Err(EncryptionError::Format("Not a SilentLock container".to_string()));
+    };
+    
+    let buffer = read_single_message(reader, MAX_SINGLE_MESSAGE_LEN)?;
+    if buffer.len() < HEADERLESS_NONCE_LEN {
+        return Err(EncryptionError::Format("File too short".to_string()));
+    }
+    let (nonce, ciphertext) = buffer.split_at(HEADERLESS_NONCE_LEN);
+    
+    let key: [u8; 32] = Sha256::digest(passphrase.as_bytes()).into();
+    FileCipher::new(CipherAlgorithm::Aes256Gcm, &key).decrypt(nonce, ciphertext, &[])
+}
+
+/// Decrypts the single-message payload of a version 1 container
+fn decrypt_v1<R: Read, W: Write>(
+    cipher: &FileCipher,
+    header: &Header,
+    aad: &[u8],
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<u64, EncryptionError> {
+    let buffer = read_single_message(reader, MAX_SINGLE_MESSAGE_LEN)?;
+    
+    let decrypted_data = cipher.decrypt(&header.nonce, &buffer, aad)?;
+    writer.write_all(&decrypted_data)?;
+    
+    Ok(decrypted_data.len() as u64)
+}
+
+/// Generates an RSA key pair
+pub fn generate_rsa_keypair() -> Result<(RsaPrivateKey, RsaPublicKey), EncryptionError> {
+    let mut rng = rand::thread_rng();
+    let private_key = RsaPrivateKey::new(&mut rng, 2048)
+        .map_err(|e| EncryptionError::KeyGeneration(e.to_string()))?;
+    let public_key = RsaPublicKey::from(&private_key);
+    
+    Ok((private_key, public_key))
+}
+
+/// Exports an RSA public key to PEM format
+pub fn export_public_key(public_key: &RsaPublicKey) -> Result<String, EncryptionError> {
+    public_key.to_public_key_pem(LineEnding::LF)
+        .map_err(|e| EncryptionError::KeyGeneration(e.to_string()))
+}
+
+/// Exports an RSA private key to PEM format
+pub fn export_private_key(private_key: &RsaPrivateKey) -> Result<String, EncryptionError> {
+    private_key.to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| EncryptionError::KeyGeneration(e.to_string()))
+        .map(|pem| pem.to_string())
+}
+
+/// Parses an RSA public key from PEM, accepting SPKI and PKCS#1 encodings
+pub fn import_public_key(pem: &str) -> Result<RsaPublicKey, EncryptionError> {
+    RsaPublicKey::from_public_key_pem(pem)
+        .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+        .map_err(|e| EncryptionError::KeyGeneration(e.to_string()))
+}
+
+/// Parses an RSA private key from PEM, accepting PKCS#8 and PKCS#1 encodings
+pub fn import_private_key(pem: &str) -> Result<RsaPrivateKey, EncryptionError> {
+    RsaPrivateKey::from_pkcs8_pem(pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+        .map_err(|e| EncryptionError::KeyGeneration(e.to_string()))
+}
+
+/// Computes the SHA-256 fingerprint of an RSA public key's SPKI DER encoding
+pub fn public_key_fingerprint(public_key: &RsaPublicKey) -> Result<[u8; 32], EncryptionError> {
+    let der = public_key.to_public_key_der()
+        .map_err(|e| EncryptionError::KeyGeneration(e.to_string()))?;
+    Ok(Sha256::digest(der.as_bytes()).into())
+}
+
+/// Encrypts a symmetric key using RSA-OAEP with SHA-256
+pub fn encrypt_key_with_rsa(
+    symmetric_key: &[u8],
+    public_key: &RsaPublicKey,
+) -> Result<Vec<u8>, EncryptionError> {
+    let mut rng = rand::thread_rng();
+    public_key.encrypt(&mut rng, Oaep::new::<Sha256>(), symmetric_key)
+        .map_err(|e| EncryptionError::Encryption(e.to_string()))
+}
+
+/// Decrypts a symmetric key using RSA-OAEP with SHA-256
+pub fn decrypt_key_with_rsa(
+    encrypted_key: &[u8],
+    private_key: &RsaPrivateKey,
+) -> Result<Vec<u8>, EncryptionError> {
+    private_key.decrypt(Oaep::new::<Sha256>(), encrypted_key)
+        .map_err(|e| EncryptionError::Decryption(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::kdf::{generate_key_from_passphrase, KdfAlgorithm, KdfParams, SALT_LEN};
+    
+    /// Cheap KDF parameters for hand-built legacy files
+    const FAST: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+    
+    /// Chunk size of hand-built version 2 files, small enough to need several chunks
+    const LEGACY_CHUNK: u32 = 16;
+    
+    fn passphrase(passphrase: &str) -> Identity {
+        Identity::Passphrase(passphrase.to_string())
+    }
+    
+    fn decrypt(file: &[u8], identity: &Identity) -> Result<Vec<u8>, EncryptionError> {
+        let mut plaintext = Vec::new();
+        decrypt_stream(&mut &file[..], &mut plaintext, identity)?;
+        Ok(plaintext)
+    }
+    
+    /// A file as written before the container had a header
+    fn headerless_file(passphrase: &str, plaintext: &[u8]) -> Vec<u8> {
+        let key: [u8; 32] = Sha256::digest(passphrase.as_bytes()).into();
+        let nonce = [6u8; HEADERLESS_NONCE_LEN];
+        let mut file = nonce.to_vec();
+        file.extend(FileCipher::new(CipherAlgorithm::Aes256Gcm, &key).encrypt(&nonce, plaintext, &[]).unwrap());
+        file
+    }
+    
+    /// A version 1 or 2 file, whose payload key is derived from the passphrase
+    fn legacy_file(version: u8, passphrase: &str, plaintext: &[u8]) -> Vec<u8> {
+        let salt = [4u8; SALT_LEN];
+        let mut body = vec![CipherAlgorithm::Aes256Gcm.id(), KdfAlgorithm::Argon2id.id()];
+        body.extend_from_slice(&salt);
+        body.extend_from_slice(&FAST.to_bytes());
+        let nonce = match version {
+            1 => vec![2u8; 12],
+            _ => {
+                body.extend_from_slice(&LEGACY_CHUNK.to_le_bytes());
+                vec![2u8; Header::nonce_prefix_len(CipherAlgorithm::Aes256Gcm)]
+            },
+        };
+        body.extend_from_slice(&nonce);
+        
+        let mut file = header::MAGIC.to_vec();
+        file.push(version);
+        file.extend_from_slice(&(7 + body.len() as u16).to_le_bytes());
+        file.extend_from_slice(&body);
+        
+        // The whole header is the associated data
+        let key = generate_key_from_passphrase(passphrase, &salt, &FAST).unwrap();
+        let cipher = FileCipher::new(CipherAlgorithm::Aes256Gcm, &key);
+        let aad = file.clone();
+        match version {
+            1 => file.extend(cipher.encrypt(&nonce, plaintext, &aad).unwrap()),
+            _ => {
+                stream::encrypt_chunks(&cipher, &nonce, LEGACY_CHUNK as usize, &aad, &mut &plaintext[..], &mut file).unwrap();
+            },
+        }
+        file
+    }
+    
+    #[test]
+    fn reads_current_files() {
+        let plaintext = b"current format".repeat(100);
+        let metadata = FileMetadata {
+            filename: "notes.txt".to_string(),
+            content_type: Some("text/plain".to_string()),
+            uploaded_at: chrono::Utc::now(),
+        };
+        let mut file = Vec::new();
+        encrypt_stream(&mut &plaintext[..], &mut file, &[Recipient::Passphrase("pw".to_string())], CipherAlgorithm::Aes256Gcm, Some(&metadata)).unwrap();
+        assert_eq!(&file[..4], &header::MAGIC);
+        
+        let mut decrypted = Vec::new();
+        let opened = decrypt_stream(&mut &file[..], &mut decrypted, &passphrase("pw")).unwrap();
+        assert_eq!(decrypted, plaintext);
+        assert_eq!(opened, Some(metadata));
+        assert!(decrypt(&file, &passphrase("wrong")).is_err());
+    }
+    
+    #[test]
+    fn reads_legacy_versions() {
+        let plaintext = b"legacy format".repeat(10);
+        for version in [1, 2] {
+            let file = legacy_file(version, "pw", &plaintext);
+            assert_eq!(decrypt(&file, &passphrase("pw")).unwrap(), plaintext);
+            assert!(decrypt(&file, &passphrase("wrong")).is_err());
+        }
+    }
+    
+    #[test]
+    fn legacy_headers_are_authenticated() {
+        let file = legacy_file(2, "pw", b"legacy format");
+        
+        // Salt byte: the key changes. Chunk size: only the associated data does
+        for position in [9, 7 + 2 + SALT_LEN + KdfParams::ENCODED_LEN] {
+            let mut tampered = file.clone();
+            tampered[position] ^= 1;
+            assert!(decrypt(&tampered, &passphrase("pw")).is_err());
+        }
+    }
+    
+    #[test]
+    fn reads_headerless_files() {
+        let file = headerless_file("pw", b"from before the header");
+        assert_eq!(decrypt(&file, &passphrase("pw")).unwrap(), b"from before the header");
+        assert!(decrypt(&file, &passphrase("wrong")).is_err());
+        
+        let mut tampered = file.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&tampered, &passphrase("pw")).is_err());
+        assert!(decrypt(&file[..HEADERLESS_NONCE_LEN - 1], &passphrase("pw")).is_err());
+    }
+    
+    /// Counts the bytes read through it
+    struct Counting<'a> {
+        inner: &'a [u8],
+        read: std::rc::Rc<std::cell::Cell<usize>>,
+    }
+    
+    impl Read for Counting<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.read.set(self.read.get() + n);
+            Ok(n)
+        }
+    }
+    
+    #[test]
+    fn version_2_is_checked_then_streamed() {
+        let plaintext = b"legacy format".repeat(10);
+        let file = legacy_file(2, "pw", &plaintext);
+        let header_len = file.len() - (plaintext.len() + plaintext.len().div_ceil(LEGACY_CHUNK as usize) * stream::TAG_LEN);
+        
+        let read = std::rc::Rc::default();
+        let reader = Counting {
+            inner: &file,
+            read: std::rc::Rc::clone(&read),
+        };
+        let opened = open_stream(reader, &passphrase("pw")).unwrap();
+        assert_eq!(read.get(), header_len + LEGACY_CHUNK as usize + stream::TAG_LEN + 1);
+        
+        let mut decrypted = Vec::new();
+        opened.decrypt_to(&mut decrypted).unwrap();
+        assert_eq!(decrypted, plaintext);
+        assert_eq!(read.get(), file.len());
+    }
+    
+    #[test]
+    fn wrong_passphrases_fail_before_any_plaintext() {
+        let files = [
+            legacy_file(1, "pw", b"legacy format"),
+            legacy_file(2, "pw", &b"legacy format".repeat(10)),
+            headerless_file("pw", b"from before the header"),
+        ];
+        for file in files {
+            assert!(open_stream(&file[..], &passphrase("wrong")).is_err());
+            assert!(open_stream(&file[..], &passphrase("pw")).is_ok());
+        }
+    }
+    
+    #[test]
+    fn single_messages_are_capped() {
+        assert_eq!(read_single_message(&mut &[1u8; 10][..], 10).unwrap(), vec![1; 10]);
+        assert!(matches!(
+            read_single_message(&mut &[1u8; 11][..], 10),
+            Err(EncryptionError::Format(_))
+        ));
+    }
+    
+    fn rekey(file: &[u8], old: &str, new: &str) -> Result<Vec<u8>, EncryptionError> {
+        let mut rekeyed = Vec::new();
+        rekey_stream(&mut &file[..], &mut rekeyed, &passphrase(old), &Recipient::Passphrase(new.to_string()))?;
+        Ok(rekeyed)
+    }
+    
+    #[test]
+    fn rekeys_headerless_files() {
+        let file = headerless_file("old", b"from before the header");
+        assert!(rekey(&file, "wrong", "new").is_err());
+        
+        let rekeyed = rekey(&file, "old", "new").unwrap();
+        let (header, _) = Header::read_from(&mut &rekeyed[..]).unwrap();
+        assert_eq!(header.version, header::VERSION);
+        assert_eq!(decrypt(&rekeyed, &passphrase("new")).unwrap(), b"from before the header");
+        assert!(decrypt(&rekeyed, &passphrase("old")).is_err());
+    }
+    
+    #[test]
+    fn rekeys_legacy_versions() {
+        let plaintext = b"legacy format".repeat(10);
+        for version in [1, 2] {
+            let file = legacy_file(version, "old", &plaintext);
+            assert!(rekey(&file, "wrong", "new").is_err());
+            
+            let rekeyed = rekey(&file, "old", "new").unwrap();
+            let (header, _) = Header::read_from(&mut &rekeyed[..]).unwrap();
+            assert_eq!(header.version, header::VERSION);
+            assert_eq!(decrypt(&rekeyed, &passphrase("new")).unwrap(), plaintext);
+            assert!(decrypt(&rekeyed, &passphrase("old")).is_err());
+        }
+    }
+    
+    #[test]
+    fn rekeys_current_files_in_place() {
+        let mut file = Vec::new();
+        encrypt_stream(&mut &b"current"[..], &mut file, &[Recipient::Passphrase("old".to_string())], CipherAlgorithm::ChaCha20Poly1305, None).unwrap();
+        let rekeyed = rekey(&file, "old", "new").unwrap();
+        
+        // Only the key slot changes
+        let (old_header, _) = Header::read_from(&mut &file[..]).unwrap();
+        let (new_header, _) = Header::read_from(&mut &rekeyed[..]).unwrap();
+        assert_eq!(new_header.nonce, old_header.nonce);
+        assert_ne!(new_header.key_slots, old_header.key_slots);
+        assert_eq!(rekeyed[rekeyed.len() - 20..], file[file.len() - 20..]);
+        assert_eq!(decrypt(&rekeyed, &passphrase("new")).unwrap(), b"current");
+    }
+    
+    /// Accepts nothing, like a full disk
+    struct FullDisk;
+    
+    impl Write for FullDisk {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+        
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    
+    #[test]
+    fn rekey_reports_the_encryption_error() {
+        // Larger than the pipe buffer, so the decryptor is still writing when encryption fails
+        let file = legacy_file(2, "old", &vec![7; 1024 * 1024]);
+        let result = rekey_stream(&mut &file[..], &mut FullDisk, &passphrase("old"), &Recipient::Passphrase("new".to_string()));
+        match result {
+            Err(EncryptionError::Io(e)) => assert_eq!(e.to_string(), "disk full"),
+            other => panic!("unexpected result {:?}", other.err()),
+        }
+    }    
+    fn slots(file: &[u8]) -> usize {
+        Header::read_from(&mut &file[..]).unwrap().0.key_slots.len()
+    }
+    
+    #[test]
+    fn passphrase_and_rsa_slots_open_the_same_file() {
+        let (private_key, public_key) = generate_rsa_keypair().unwrap();
+        let rsa = Identity::RsaPrivateKey(Box::new(private_key));
+        let mut file = Vec::new();
+        encrypt_stream(&mut &b"shared"[..], &mut file, &[Recipient::Passphrase("pw".to_string())], CipherAlgorithm::Aes256Gcm, None).unwrap();
+        
+        // Adding the RSA slot needs the passphrase
+        let mut added = Vec::new();
+        let recipient = Recipient::RsaPublicKey(Box::new(public_key));
+        assert!(matches!(
+            add_recipient_stream(&mut &file[..], &mut added, &passphrase("wrong"), &recipient),
+            Err(EncryptionError::Decryption(_))
+        ));
+        added.clear();
+        add_recipient_stream(&mut &file[..], &mut added, &passphrase("pw"), &recipient).unwrap();
+        assert_eq!(slots(&added), 2);
+        assert_eq!(decrypt(&added, &passphrase("pw")).unwrap(), b"shared");
+        assert_eq!(decrypt(&added, &rsa).unwrap(), b"shared");
+        
+        // The key alone can then take the passphrase away
+        let mut removed = Vec::new();
+        remove_key_slot_stream(&mut &added[..], &mut removed, &rsa, 0).unwrap();
+        assert_eq!(slots(&removed), 1);
+        assert_eq!(decrypt(&removed, &rsa).unwrap(), b"shared");
+        assert!(decrypt(&removed, &passphrase("pw")).is_err());
+    }
+    
+    #[test]
+    fn the_last_key_slot_cannot_be_removed() {
+        let mut file = Vec::new();
+        encrypt_stream(&mut &b"only"[..], &mut file, &[Recipient::Passphrase("pw".to_string())], CipherAlgorithm::ChaCha20Poly1305, None).unwrap();
+        
+        for index in [0, 1] {
+            let result = remove_key_slot_stream(&mut &file[..], &mut Vec::new(), &passphrase("pw"), index);
+            assert!(matches!(result, Err(EncryptionError::KeySlot(_))), "index {}", index);
+        }
+        assert_eq!(decrypt(&file, &passphrase("pw")).unwrap(), b"only");
+    }
+}
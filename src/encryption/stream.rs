@@ -0,0 +1,278 @@
+//! Online segmented AEAD in the style of STREAM (Hoang, Reyhanitabar, Rogaway, Vizár).
+//!
+//! The plaintext is split into fixed-size chunks which are sealed
+//! independently. Each chunk nonce is built from a random per-file prefix, a
+//! big-endian chunk counter and a final-chunk flag:
+//!
+//! ```text
+//! nonce = prefix [n - 5] | counter u32 BE | last u8
+//! ```
+//!
+//! Reordering, dropping or duplicating chunks changes the nonce and makes
+//! authentication fail. Truncating the file after a full chunk is caught
+//! because that chunk was not sealed with the final flag set.
+//!
+//! The flag value 2 is reserved for the header's metadata block, so its nonce
+//! never collides with that of a chunk.
+
+use std::io::{self, Read, Write};
+
+use super::cipher::FileCipher;
+use super::EncryptionError;
+
+/// Default plaintext size of each chunk
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Largest chunk size accepted when reading a header
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Length of the authentication tag appended to each chunk
+pub const TAG_LEN: usize = 16;
+
+/// Bytes of the nonce taken up by the counter and the final-chunk flag
+pub const NONCE_SUFFIX_LEN: usize = 5;
+
+/// Builds the nonce for a given chunk
+fn chunk_nonce(prefix: &[u8], counter: u32, last: bool) -> Vec<u8> {
+    let mut nonce = Vec::with_capacity(prefix.len() + NONCE_SUFFIX_LEN);
+    nonce.extend_from_slice(prefix);
+    nonce.extend_from_slice(&counter.to_be_bytes());
+    nonce.push(last as u8);
+    nonce
+}
+
+/// Builds the nonce for the sealed metadata block
+pub fn metadata_nonce(prefix: &[u8]) -> Vec<u8> {
+    let mut nonce = Vec::with_capacity(prefix.len() + NONCE_SUFFIX_LEN);
+    nonce.extend_from_slice(prefix);
+    nonce.extend_from_slice(&0u32.to_be_bytes());
+    nonce.push(2);
+    nonce
+}
+
+/// Fills `buf` from `reader`, stopping early only at end of stream
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Encrypts `reader` into `writer` chunk by chunk
+///
+/// Memory use is bounded by the chunk size regardless of the input length.
+/// `aad` is bound to every chunk. Returns the number of plaintext bytes read.
+pub fn encrypt_chunks<R: Read, W: Write>(
+    cipher: &FileCipher,
+    nonce_prefix: &[u8],
+    chunk_size: usize,
+    aad: &[u8],
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<u64, EncryptionError> {
+    // One byte of look-ahead tells us whether the current chunk is the last
+    let mut buf = vec![0u8; chunk_size + 1];
+    let mut filled = read_full(reader, &mut buf)?;
+    let mut counter: u32 = 0;
+    let mut total: u64 = 0;
+
+    loop {
+        let last = filled <= chunk_size;
+        let take = filled.min(chunk_size);
+
+        let nonce = chunk_nonce(nonce_prefix, counter, last);
+        let sealed = cipher.encrypt(&nonce, &buf[..take], aad)?;
+        writer.write_all(&sealed)?;
+        total += take as u64;
+
+        if last {
+            break;
+        }
+
+        // Carry the look-ahead byte over and refill
+        buf.copy_within(chunk_size..filled, 0);
+        filled -= chunk_size;
+        filled += read_full(reader, &mut buf[filled..])?;
+
+        counter = counter.checked_add(1)
+            .ok_or_else(|| EncryptionError::Encryption("Input too large".to_string()))?;
+    }
+
+    writer.flush()?;
+    Ok(total)
+}
+
+/// Reads and authenticates the first chunk of a stream without releasing it
+///
+/// Lets a caller reject a wrong key before any plaintext is written. Returns
+/// the sealed bytes that were read, which must be put back in front of the
+/// stream before it is passed to `decrypt_chunks`.
+pub fn check_first_chunk<R: Read>(
+    cipher: &FileCipher,
+    nonce_prefix: &[u8],
+    chunk_size: usize,
+    aad: &[u8],
+    reader: &mut R,
+) -> Result<Vec<u8>, EncryptionError> {
+    let sealed_size = chunk_size + TAG_LEN;
+    let mut buf = vec![0u8; sealed_size + 1];
+    let filled = read_full(reader, &mut buf)?;
+    buf.truncate(filled);
+
+    let last = filled <= sealed_size;
+    let take = filled.min(sealed_size);
+    if take < TAG_LEN {
+        return Err(EncryptionError::Decryption("Truncated chunk".to_string()));
+    }
+    cipher.decrypt(&chunk_nonce(nonce_prefix, 0, last), &buf[..take], aad)?;
+    Ok(buf)
+}
+
+/// Decrypts a chunked stream written by `encrypt_chunks`
+///
+/// Plaintext is written as soon as each chunk authenticates, so on error the
+/// caller must discard whatever has already been written.
+pub fn decrypt_chunks<R: Read, W: Write>(
+    cipher: &FileCipher,
+    nonce_prefix: &[u8],
+    chunk_size: usize,
+    aad: &[u8],
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<u64, EncryptionError> {
+    let sealed_size = chunk_size + TAG_LEN;
+    let mut buf = vec![0u8; sealed_size + 1];
+    let mut filled = read_full(reader, &mut buf)?;
+    let mut counter: u32 = 0;
+    let mut total: u64 = 0;
+
+    loop {
+        let last = filled <= sealed_size;
+        let take = filled.min(sealed_size);
+        if take < TAG_LEN {
+            return Err(EncryptionError::Decryption("Truncated chunk".to_string()));
+        }
+
+        let nonce = chunk_nonce(nonce_prefix, counter, last);
+        let plain = cipher.decrypt(&nonce, &buf[..take], aad)?;
+        writer.write_all(&plain)?;
+        total += plain.len() as u64;
+
+        if last {
+            break;
+        }
+
+        buf.copy_within(sealed_size..filled, 0);
+        filled -= sealed_size;
+        filled += read_full(reader, &mut buf[filled..])?;
+
+        counter = counter.checked_add(1)
+            .ok_or_else(|| EncryptionError::Decryption("Too many chunks".to_string()))?;
+    }
+
+    writer.flush()?;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::cipher::CipherAlgorithm;
+
+    const CHUNK: usize = 16;
+    const AAD: &[u8] = b"header";
+    const PREFIX: [u8; 7] = [3; 7];
+
+    fn cipher() -> FileCipher {
+        FileCipher::new(CipherAlgorithm::Aes256Gcm, &[7; 32])
+    }
+
+    fn seal(plain: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::new();
+        encrypt_chunks(&cipher(), &PREFIX, CHUNK, AAD, &mut &plain[..], &mut sealed).unwrap();
+        sealed
+    }
+
+    fn open(sealed: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let mut plain = Vec::new();
+        decrypt_chunks(&cipher(), &PREFIX, CHUNK, AAD, &mut &sealed[..], &mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn round_trips_chunk_boundaries() {
+        for len in [0, 1, CHUNK - 1, CHUNK, CHUNK + 1, 3 * CHUNK, 3 * CHUNK + 5] {
+            let plain: Vec<u8> = (0..len as u8).collect();
+            let sealed = seal(&plain);
+            assert_eq!(sealed.len(), len + len.div_ceil(CHUNK).max(1) * TAG_LEN);
+            assert_eq!(open(&sealed).unwrap(), plain);
+        }
+    }
+
+    #[test]
+    fn rejects_dropped_last_chunk() {
+        let sealed = seal(&[1; 3 * CHUNK + 5]);
+        let sealed_chunk = CHUNK + TAG_LEN;
+
+        // Cut exactly on a chunk boundary, so the remaining chunks are whole
+        assert!(open(&sealed[..3 * sealed_chunk]).is_err());
+        assert!(open(&sealed[..sealed_chunk]).is_err());
+        assert!(open(&sealed[..sealed.len() - 1]).is_err());
+        assert!(open(&[]).is_err());
+    }
+
+    #[test]
+    fn rejects_reordered_chunks() {
+        let sealed = seal(&[1; 3 * CHUNK + 5]);
+        let sealed_chunk = CHUNK + TAG_LEN;
+
+        let mut swapped = sealed.clone();
+        swapped[..sealed_chunk].copy_from_slice(&sealed[sealed_chunk..2 * sealed_chunk]);
+        swapped[sealed_chunk..2 * sealed_chunk].copy_from_slice(&sealed[..sealed_chunk]);
+        assert!(open(&swapped).is_err());
+    }
+
+    #[test]
+    fn first_chunk_is_checked_without_consuming_the_rest() {
+        let plain = [1; 3 * CHUNK + 5];
+        let sealed = seal(&plain);
+
+        let mut reader = &sealed[..];
+        let peeked = check_first_chunk(&cipher(), &PREFIX, CHUNK, AAD, &mut reader).unwrap();
+        assert_eq!(peeked.len(), CHUNK + TAG_LEN + 1);
+        assert_eq!(reader.len(), sealed.len() - peeked.len());
+
+        let mut rest = io::Cursor::new(peeked).chain(reader);
+        let mut decrypted = Vec::new();
+        decrypt_chunks(&cipher(), &PREFIX, CHUNK, AAD, &mut rest, &mut decrypted).unwrap();
+        assert_eq!(decrypted, plain);
+
+        let wrong_key = FileCipher::new(CipherAlgorithm::Aes256Gcm, &[8; 32]);
+        assert!(check_first_chunk(&wrong_key, &PREFIX, CHUNK, AAD, &mut &sealed[..]).is_err());
+        assert!(check_first_chunk(&cipher(), &PREFIX, CHUNK, AAD, &mut &sealed[..TAG_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn first_chunk_of_a_single_chunk_stream() {
+        let sealed = seal(b"short");
+        let peeked = check_first_chunk(&cipher(), &PREFIX, CHUNK, AAD, &mut &sealed[..]).unwrap();
+        assert_eq!(peeked, sealed);
+
+        // A lone chunk must carry the final flag
+        let longer = seal(&[1; 2 * CHUNK]);
+        assert!(check_first_chunk(&cipher(), &PREFIX, CHUNK, AAD, &mut &longer[..CHUNK + TAG_LEN]).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_associated_data() {
+        let sealed = seal(b"hello");
+        let mut plain = Vec::new();
+        assert!(decrypt_chunks(&cipher(), &PREFIX, CHUNK, b"other", &mut &sealed[..], &mut plain).is_err());
+    }
+}
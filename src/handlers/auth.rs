@@ -0,0 +1,423 @@
+//! User accounts and the sessions that sign them in.
+//!
+//! Logging in sets a session cookie, which handlers resolve by taking an
+//! `AuthUser`. Machine clients send an API token as `Authorization: Bearer`
+//! instead; `bearer_auth` checks it before any handler runs, and the token's
+//! scopes then limit what the request may do. Files belong to the user who
+//! uploaded them; other users get 404 for them, as if they did not exist.
+
+use actix_web::{web, dev::{Payload, ServiceRequest, ServiceResponse}, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Error, error, Result};
+use actix_web::body::MessageBody;
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use chrono::Duration;
+use futures::future::{ready, Ready};
+use log::{info, error, warn};
+use std::ops::Deref;
+use std::sync::OnceLock;
+
+use crate::models::{ApiToken, CredentialsRequest, Session, TokenScope, User, UserResponse};
+use crate::store::UserStore;
+use crate::utils::{generate_token, hash_token};
+use crate::encryption::kdf::{hash_password, verify_password};
+
+/// Name of the cookie that carries the session token
+pub const SESSION_COOKIE: &str = "silentlock_session";
+
+/// Number of days a session lasts after signing in
+const SESSION_DAYS: i64 = 7;
+
+/// Shortest password accepted at registration
+const MIN_PASSWORD_LEN: usize = 8;
+
+/// Longest username accepted at registration
+const MAX_USERNAME_LEN: usize = 64;
+
+/// The signed-in user of a request
+///
+/// Extracting it fails with 401 unless the request carries a valid session or
+/// API token.
+pub struct AuthUser {
+    /// The user the request acts as
+    pub user: User,
+    
+    /// The API token the request was made with; None for browser sessions
+    pub token: Option<ApiToken>,
+}
+
+impl AuthUser {
+    /// Fails with 403 if the request was made with a token that lacks `scope`
+    ///
+    /// Browser sessions may do everything their user can.
+    pub fn require(&self, scope: TokenScope) -> Result<(), Error> {
+        match &self.token {
+            Some(token) if !token.allows(scope) => {
+                Err(error::ErrorForbidden(format!("API token lacks the {} scope", scope.as_str())))
+            },
+            _ => Ok(()),
+        }
+    }
+    
+    /// Fails with 403 if the request was made with a token rather than a session
+    pub fn require_session(&self) -> Result<(), Error> {
+        match self.token {
+            Some(_) => Err(error::ErrorForbidden("Sign in to do this; API tokens cannot")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Deref for AuthUser {
+    type Target = User;
+    
+    fn deref(&self) -> &User {
+        &self.user
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // A token checked by `bearer_auth` takes precedence over any cookie
+        if let Some(token_user) = req.extensions().get::<TokenUser>() {
+            return ready(Ok(AuthUser {
+                user: token_user.user.clone(),
+                token: Some(token_user.token.clone()),
+            }));
+        }
+        
+        ready(session_user(req).map(|user| AuthUser {
+            user,
+            token: None,
+        }))
+    }
+}
+
+/// A user and the API token a request authenticated with, kept in the request extensions
+#[derive(Clone)]
+struct TokenUser {
+    user: User,
+    token: ApiToken,
+}
+
+/// Middleware that authenticates requests sending `Authorization: Bearer`
+///
+/// Requests without a bearer token pass through to the session cookie check;
+/// requests with an unknown, revoked or expired one are turned away with 401.
+pub async fn bearer_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let token = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+        .map(|(_, token)| token.trim().to_string());
+    
+    if let Some(token) = token {
+        let token_user = token_user(req.request(), &token)?;
+        req.extensions_mut().insert(token_user);
+    }
+    
+    next.call(req).await
+}
+
+/// Gets the user store registered as app data
+fn user_store(req: &HttpRequest) -> Result<&dyn UserStore, Error> {
+    req.app_data::<web::Data<dyn UserStore>>()
+        .map(|user_store| user_store.get_ref())
+        .ok_or_else(|| {
+            error!("No user store registered");
+            error::ErrorInternalServerError("Accounts are not available")
+        })
+}
+
+/// Resolves the session cookie of a request to its user
+fn session_user(req: &HttpRequest) -> Result<User, Error> {
+    let cookie = req.cookie(SESSION_COOKIE)
+        .ok_or_else(|| error::ErrorUnauthorized("Sign in required"))?;
+    
+    let user_store = user_store(req)?;
+    let session = match user_store.find_session(&hash_token(cookie.value()))? {
+        Some(session) if !session.is_expired() => session,
+        _ => return Err(error::ErrorUnauthorized("Session has expired, sign in again")),
+    };
+    
+    user_store.get_user(&session.user_id)?
+        .ok_or_else(|| error::ErrorUnauthorized("Sign in required"))
+}
+
+/// Resolves an API token to the user it acts as
+fn token_user(req: &HttpRequest, token: &str) -> Result<TokenUser, Error> {
+    let user_store = user_store(req)?;
+    let token = match user_store.find_api_token(&hash_token(token))? {
+        Some(token) if !token.is_expired() => token,
+        Some(token) => {
+            warn!("Expired API token {} used", token.id);
+            return Err(error::ErrorUnauthorized("API token has expired"));
+        },
+        None => return Err(error::ErrorUnauthorized("Invalid API token")),
+    };
+    
+    let user = user_store.get_user(&token.user_id)?
+        .ok_or_else(|| error::ErrorUnauthorized("Invalid API token"))?;
+    
+    Ok(TokenUser {
+        user,
+        token,
+    })
+}
+
+/// Checks a requested username and brings it into its stored lowercase form
+fn normalize_username(username: &str) -> Result<String, Error> {
+    let username = username.trim().to_lowercase();
+    
+    if username.is_empty() || username.len() > MAX_USERNAME_LEN {
+        return Err(error::ErrorBadRequest("Username must be between 1 and 64 characters"));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        return Err(error::ErrorBadRequest("Username may only contain letters, digits, '_', '-' and '.'"));
+    }
+    
+    Ok(username)
+}
+
+/// Hash checked when signing in to an unknown user, so that takes as long as a wrong password
+fn dummy_password_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash_password("silentlock").unwrap_or_default())
+}
+
+/// Starts a session for `user` and sets its cookie on the response
+fn start_session(
+    req: &HttpRequest,
+    user_store: &dyn UserStore,
+    user: User,
+    mut response: HttpResponseBuilder,
+    message: &str,
+) -> Result<HttpResponse, Error> {
+    let (token, token_hash) = generate_token();
+    user_store.add_session(Session::new(token_hash, user.id.clone(), Duration::days(SESSION_DAYS)))?;
+    
+    // Strict same-site cookies are never sent with requests from other sites
+    let cookie = Cookie::build(SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(req.connection_info().scheme() == "https")
+        .max_age(time::Duration::days(SESSION_DAYS))
+        .finish();
+    
+    Ok(response.cookie(cookie).json(UserResponse {
+        success: true,
+        message: message.to_string(),
+        user: Some(user),
+    }))
+}
+
+/// Register a new user and sign them in
+pub async fn register(
+    req: HttpRequest,
+    body: web::Json<CredentialsRequest>,
+    user_store: web::Data<dyn UserStore>,
+) -> Result<HttpResponse, Error> {
+    let request = body.into_inner();
+    
+    // Check the credentials
+    let username = normalize_username(&request.username)?;
+    if request.password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(error::ErrorBadRequest("Password must be at least 8 characters"));
+    }
+    
+    // Store the user with a hash of the password, which is computed on a blocking thread
+    let password = request.password;
+    let password_hash = web::block(move || hash_password(&password))
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .map_err(|e| {
+            error!("Error hashing password: {:?}", e);
+            error::ErrorInternalServerError("Error creating account")
+        })?;
+    let user = User::new(username, password_hash);
+    if !user_store.add_user(user.clone())? {
+        return Err(error::ErrorConflict("Username is already taken"));
+    }
+    
+    info!("User registered: {}", user.id);
+    
+    start_session(&req, user_store.get_ref(), user, HttpResponse::Created(), "Account created successfully")
+}
+
+/// Sign in with a username and password
+pub async fn login(
+    req: HttpRequest,
+    body: web::Json<CredentialsRequest>,
+    user_store: web::Data<dyn UserStore>,
+) -> Result<HttpResponse, Error> {
+    let request = body.into_inner();
+    let username = request.username.trim().to_lowercase();
+    
+    // Unknown users and wrong passwords get the same answer in the same time;
+    // the hash is checked on a blocking thread
+    let user = web::block({
+        let user = user_store.find_user(&username)?;
+        move || match user {
+            Some(user) if verify_password(&request.password, &user.password_hash) => Some(user),
+            Some(_) => None,
+            None => {
+                verify_password(&request.password, dummy_password_hash());
+                None
+            }
+        }
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)?
+    .ok_or_else(|| {
+        warn!("Failed sign-in for {:?}", username);
+        error::ErrorUnauthorized("Wrong username or password")
+    })?;
+    
+    info!("User signed in: {}", user.id);
+    
+    start_session(&req, user_store.get_ref(), user, HttpResponse::Ok(), "Signed in successfully")
+}
+
+/// Sign out, ending the current session
+pub async fn logout(
+    req: HttpRequest,
+    user_store: web::Data<dyn UserStore>,
+) -> Result<HttpResponse, Error> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        user_store.remove_session(&hash_token(cookie.value()))?;
+    }
+    
+    // Tell the browser to drop the cookie as well
+    let mut cookie = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    cookie.make_removal();
+    
+    Ok(HttpResponse::Ok().cookie(cookie).json(UserResponse {
+        success: true,
+        message: "Signed out successfully".to_string(),
+        user: None,
+    }))
+}
+
+/// Get the signed-in user
+pub async fn current_user(
+    user: AuthUser,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(UserResponse {
+        success: true,
+        message: "Signed in".to_string(),
+        user: Some(user.user),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use serde_json::{json, Value};
+    use std::time::{Duration as StdDuration, Instant};
+    use crate::handlers::testing::TestApp;
+    
+    fn credentials(uri: &str, username: &str, password: &str) -> TestRequest {
+        TestRequest::post()
+            .uri(uri)
+            .set_json(json!({ "username": username, "password": password }))
+    }
+    
+    /// Signs in, returning the status, the message and how long it took
+    async fn timed_login(app: &TestApp, username: &str, password: &str) -> (StatusCode, String, StdDuration) {
+        let started = Instant::now();
+        let response = app.call(credentials("/api/auth/login", username, password)).await;
+        let elapsed = started.elapsed();
+        let status = response.status();
+        let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        (status, body, elapsed)
+    }
+    
+    #[actix_web::test]
+    async fn sessions_sign_in_until_signed_out() {
+        let app = TestApp::new();
+        let response = app.call(credentials("/api/auth/register", "Alice", "correct horse")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let cookie = response.response().cookies().find(|c| c.name() == SESSION_COOKIE).unwrap().into_owned();
+        assert!(cookie.http_only().unwrap_or(false));
+        
+        let response = app.call(credentials("/api/auth/register", "alice", "another one")).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        
+        let response = app.call(TestRequest::get().uri("/api/auth/me").cookie(cookie.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["user"]["username"], "alice");
+        assert!(body["user"].get("password_hash").is_none());
+        
+        let response = app.call(TestRequest::post().uri("/api/auth/logout").cookie(cookie.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.call(TestRequest::get().uri("/api/auth/me").cookie(cookie)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.call(TestRequest::get().uri("/api/auth/me")).await.status(), StatusCode::UNAUTHORIZED);
+        
+        let response = app.call(credentials("/api/auth/login", " ALICE ", "correct horse")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.response().cookies().any(|c| c.name() == SESSION_COOKIE));
+    }
+    
+    #[actix_web::test]
+    async fn unknown_users_look_like_wrong_passwords() {
+        let app = TestApp::new();
+        let response = app.call(credentials("/api/auth/register", "alice", "correct horse")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        
+        // The first unknown user also computes the dummy hash, so it is not timed
+        let (status, unknown, _) = timed_login(&app, "nobody", "correct horse").await;
+        let (wrong_status, wrong, wrong_elapsed) = timed_login(&app, "alice", "wrong horse").await;
+        let (_, _, unknown_elapsed) = timed_login(&app, "somebody", "correct horse").await;
+        
+        assert_eq!((status, wrong_status), (StatusCode::UNAUTHORIZED, StatusCode::UNAUTHORIZED));
+        assert_eq!(unknown, wrong);
+        
+        // Both verify a password hash, so neither answers much sooner
+        assert!(unknown_elapsed * 2 > wrong_elapsed, "{:?} vs {:?}", unknown_elapsed, wrong_elapsed);
+        assert!(wrong_elapsed * 2 > unknown_elapsed, "{:?} vs {:?}", wrong_elapsed, unknown_elapsed);
+    }
+    
+    #[actix_web::test]
+    async fn expired_sessions_are_turned_away() {
+        let app = TestApp::new();
+        let (user, _) = app.sign_in("alice");
+        let (token, token_hash) = generate_token();
+        app.store.add_session(Session::new(token_hash, user.id, Duration::seconds(-1))).unwrap();
+        
+        let response = app.call(TestRequest::get().uri("/api/auth/me").cookie(Cookie::new(SESSION_COOKIE, token))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.call(TestRequest::get().uri("/api/files/list").cookie(Cookie::new(SESSION_COOKIE, "made up"))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    
+    #[actix_web::test]
+    async fn other_users_files_are_not_found() {
+        let app = TestApp::new();
+        let (alice, alice_cookie) = app.sign_in("alice");
+        let (_, bob) = app.sign_in("bob");
+        let file_info = app.add_file(&alice, b"contents");
+        let download = format!("/api/files/download/{}", file_info.id);
+        
+        assert_eq!(app.call(TestRequest::get().uri(&download).cookie(bob.clone())).await.status(), StatusCode::NOT_FOUND);
+        let response = app.call(TestRequest::delete().uri(&format!("/api/files/{}", file_info.id)).cookie(bob.clone())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app.call(TestRequest::get().uri("/api/files/list").cookie(bob)).await;
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["files"].as_array().map(Vec::len), Some(0));
+        
+        assert_eq!(app.call(TestRequest::get().uri(&download)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.call(TestRequest::get().uri(&download).cookie(alice_cookie)).await.status(), StatusCode::OK);
+    }
+}
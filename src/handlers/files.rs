@@ -1,23 +1,136 @@
-use actix_web::{web, HttpResponse, Error, error, Result, http::header};
-use actix_multipart::Multipart;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Error, error, Result, http::{header, Method}};
+use actix_web::body::SizedStream;
+use actix_web::web::Bytes;
+use actix_multipart::{Field, Multipart};
 use futures::{StreamExt, TryStreamExt};
-use std::io::{Read, Write};
+use std::io::{self, BufWriter, Read, Write};
+use std::fs::{self, File};
 use std::path::Path;
-use std::fs;
+use std::time::SystemTime;
 use log::{info, error, warn};
-use uuid::Uuid;
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
 
-use crate::models::{FileInfo, FileResponse, ListFilesResponse, EncryptRequest, DecryptRequest, UploadEncryptRequest};
-use crate::utils::{FileStore, get_upload_path, get_encrypted_path, validate_file_size, validate_content_type, save_file_to_disk};
-use crate::encryption::{encrypt_file as encrypt_file_util, decrypt_file as decrypt_file_util, EncryptionError};
+use crate::models::{User, FileInfo, FileResponse, DeleteFileRequest, ListFilesResponse, UploadRequest, EncryptRequest, DecryptRequest, UploadEncryptRequest, EncryptToKeyRequest, DecryptWithKeyRequest, TokenScope};
+use crate::blob::{BlobError, BlobInfo, BlobStore};
+use crate::handlers::auth::AuthUser;
+use crate::handlers::keys::registered_recipient;
+use crate::store::{DownloadClaim, FileStore, KeyStore};
+use crate::throttle::{client_id, DecryptThrottle};
+use crate::utils::{ChunkReader, discard_file_contents, reader_stream, writer_stream, to_hex, sanitize_filename, upload_key, encrypted_key, get_temp_path, validate_file_size, validate_content_type};
+use crate::encryption::{
+    encrypt_stream, encrypt_age_stream, open_stream,
+    import_private_key, parse_age_identity, parse_age_recipient,
+    CipherAlgorithm, ContainerFormat, EncryptionError, FileMetadata, Header, Identity, Recipient,
+};
+
+/// Number of multipart chunks buffered between an upload and its encryption thread
+const UPLOAD_CHANNEL_CHUNKS: usize = 16;
+
+/// Looks up a file that can still be used
+pub(crate) fn stored_file(file_store: &dyn FileStore, file_id: &str) -> Result<FileInfo, Error> {
+    usable_file(file_store.get_file(file_id)?)
+}
+
+/// Looks up a file of `user` that can still be used
+///
+/// Other users' files answer 404, as if they did not exist.
+pub(crate) fn owned_file(file_store: &dyn FileStore, user: &User, file_id: &str) -> Result<FileInfo, Error> {
+    usable_file(file_store.get_file(file_id)?.filter(|file| file.is_owned_by(&user.id)))
+}
+
+/// Checks that a looked-up file can still be used
+///
+/// Expired and used-up files answer 410 Gone until the reaper has deleted them.
+fn usable_file(file: Option<FileInfo>) -> Result<FileInfo, Error> {
+    match file {
+        Some(file) if file.is_expired() => Err(error::ErrorGone("File has expired")),
+        Some(file) if file.downloads_remaining == Some(0) => Err(error::ErrorGone("Download limit reached")),
+        Some(file) => Ok(file),
+        None => Err(error::ErrorNotFound("File not found")),
+    }
+}
+
+/// Checks a requested download limit
+pub(crate) fn requested_max_downloads(max_downloads: Option<u32>) -> Result<Option<u32>, Error> {
+    match max_downloads {
+        Some(0) => Err(error::ErrorBadRequest("max_downloads must be at least 1")),
+        max_downloads => Ok(max_downloads),
+    }
+}
+
+/// Takes one download from a file's limit, if it has one
+///
+/// Returns whether that was the last download. Its record is then already
+/// removed, and the caller must discard the contents with `discard_last_download`.
+fn claim_download(file_info: &FileInfo, file_store: &dyn FileStore) -> Result<bool, Error> {
+    match file_store.claim_download(&file_info.id)? {
+        DownloadClaim::Unlimited => Ok(false),
+        DownloadClaim::Claimed(0) => {
+            file_store.remove_file(&file_info.id)?;
+            info!("Last download claimed: {}", file_info.id);
+            Ok(true)
+        },
+        DownloadClaim::Claimed(_) => Ok(false),
+        DownloadClaim::Refused => Err(error::ErrorGone("Download limit reached")),
+    }
+}
+
+/// Discards the contents of a file whose last download was just claimed
+///
+/// The blob store holds the deletion back until every download still reading
+/// the blob, this one included, is done with it.
+fn discard_last_download(blob_store: &dyn BlobStore, file_info: &FileInfo) {
+    match discard_file_contents(blob_store, file_info) {
+        Ok(()) => info!("File contents discarded after its last download: {}", file_info.id),
+        Err(e) => warn!("Failed to delete file after its last download: {}", e),
+    }
+}
+
+/// Works out when a new file should expire from an absolute time or a number of seconds
+pub(crate) fn requested_expiry(
+    expires_at: Option<DateTime<Utc>>,
+    expires_in: Option<u64>,
+) -> Result<Option<DateTime<Utc>>, Error> {
+    let now = Utc::now();
+    let expiry = match (expires_at, expires_in) {
+        (Some(_), Some(_)) => return Err(error::ErrorBadRequest("Give either expires_at or expires_in, not both")),
+        (Some(expires_at), None) => Some(expires_at),
+        (None, Some(expires_in)) => {
+            let expires_at = i64::try_from(expires_in)
+                .ok()
+                .and_then(Duration::try_seconds)
+                .and_then(|duration| now.checked_add_signed(duration))
+                .ok_or_else(|| error::ErrorBadRequest("Invalid expires_in"))?;
+            Some(expires_at)
+        },
+        (None, None) => None,
+    };
+    
+    if expiry.is_some_and(|expires_at| expires_at <= now) {
+        return Err(error::ErrorBadRequest("Expiry must be in the future"));
+    }
+    Ok(expiry)
+}
 
 /// Handle file upload
+///
+/// With `encrypted=true` the file must be a SilentLock container sealed by
+/// the client. It is stored as is, and the server never holds its key.
 pub async fn upload_file(
+    user: AuthUser,
     mut payload: Multipart,
-    file_store: web::Data<FileStore>,
+    upload_req: web::Query<UploadRequest>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
 ) -> Result<HttpResponse, Error> {
-    // Process multipart form data
-    while let Ok(Some(mut field)) = payload.try_next().await {
+    user.require(TokenScope::Upload)?;
+    
+    let expires_at = requested_expiry(upload_req.expires_at, upload_req.expires_in)?;
+    let max_downloads = requested_max_downloads(upload_req.max_downloads)?;
+    
+    // Only the first field of the form is used
+    if let Ok(Some(mut field)) = payload.try_next().await {
         let content_disposition = field.content_disposition();
         
         // Get filename from the Content-Disposition header
@@ -38,42 +151,64 @@ pub async fn upload_file(
             }
         }
         
-        // Generate a path for the uploaded file
-        let file_path = get_upload_path(&filename);
+        // Generate a storage key for the uploaded file
+        let storage_key = if upload_req.encrypted {
+            encrypted_key(&filename)
+        } else {
+            upload_key(&filename)
+        };
         
-        // Create a buffer to store the file
-        let mut data = Vec::new();
+        // Stream the field data into a temporary file; a client-encrypted
+        // file must at least carry a valid container header
+        let temp_path = get_temp_path(&filename);
+        let written = write_field_to_file(&mut field, &temp_path).await
+            .and_then(|size| {
+                if upload_req.encrypted {
+                    check_container_header(&temp_path)?;
+                }
+                Ok(size)
+            });
         
-        // Read the field data
-        while let Some(chunk) = field.next().await {
-            let chunk = chunk.map_err(|e| {
-                error!("Error reading multipart chunk: {}", e);
-                error::ErrorInternalServerError("Error reading file data")
-            })?;
-            
-            // Check file size limit while reading
-            data.extend_from_slice(&chunk);
-            if !validate_file_size(data.len() as u64) {
-                return Err(error::ErrorBadRequest("File too large"));
-            }
-        }
+        // Then move it into the blob store
+        let saved = match written {
+            Ok(size) => web::block({
+                let blob_store = blob_store.clone();
+                let storage_key = storage_key.clone();
+                let temp_path = temp_path.clone();
+                move || blob_store.put_file(&storage_key, &temp_path)
+            })
+            .await
+            .map_err(error::ErrorInternalServerError)
+            .and_then(|result| result.map_err(|e| {
+                error!("Error saving file: {}", e);
+                error::ErrorInternalServerError("Error saving file")
+            }))
+            .map(|_| size),
+            Err(e) => Err(e),
+        };
         
-        // Save the file to disk
-        save_file_to_disk(&data, &file_path).map_err(|e| {
-            error!("Error saving file: {}", e);
-            error::ErrorInternalServerError("Error saving file")
-        })?;
+        if let Err(e) = fs::remove_file(&temp_path) {
+            warn!("Failed to delete temporary file: {}", e);
+        }
+        let size = saved?;
         
         // Create file info
-        let file_info = FileInfo::new(
-            filename,
-            data.len() as u64,
-            content_type,
-            file_path,
-        );
+        let mut file_info = if upload_req.encrypted {
+            FileInfo::new_client_encrypted(size, storage_key)
+        } else {
+            FileInfo::new(
+                filename,
+                size,
+                content_type,
+                storage_key,
+            )
+        };
+        file_info.expires_at = expires_at;
+        file_info.downloads_remaining = max_downloads;
+        file_info.owner = Some(user.id.clone());
         
         // Store file info
-        file_store.add_file(file_info.clone());
+        file_store.add_file(file_info.clone())?;
         
         info!("File uploaded: {}", file_info.id);
         
@@ -89,79 +224,431 @@ pub async fn upload_file(
     Err(error::ErrorBadRequest("No file uploaded"))
 }
 
-/// Handle file encryption
-pub async fn encrypt_file(
-    req: web::Json<EncryptRequest>,
-    file_store: web::Data<FileStore>,
-) -> Result<HttpResponse, Error> {
-    // Get the file to encrypt
-    let file_info = match file_store.get_file(&req.file_id) {
-        Some(file) => file,
-        None => return Err(error::ErrorNotFound("File not found")),
+/// Checks that a file starts with a readable SilentLock container header
+///
+/// Only versions with key slots are accepted; older ones are still read but
+/// no longer written, and version 1 payloads have to be decrypted in memory.
+fn check_container_header(path: &Path) -> Result<(), Error> {
+    let mut file = File::open(path)?;
+    let (header, _) = Header::read_from(&mut file).map_err(|e| {
+        warn!("Client-encrypted upload is not a valid container: {:?}", e);
+        error::ErrorBadRequest("File is not a valid encrypted container")
+    })?;
+    if !header.has_key_slots() {
+        return Err(error::ErrorBadRequest(format!(
+            "Version {} containers can no longer be uploaded",
+            header.version
+        )));
+    }
+    Ok(())
+}
+
+/// Writes the data of a multipart field to `path`, enforcing the upload size limit
+///
+/// Returns the number of bytes written. On error the file may be left
+/// partially written, so the caller must remove it either way.
+async fn write_field_to_file(field: &mut Field, path: &Path) -> Result<u64, Error> {
+    let mut file = File::create(path).map(BufWriter::new).map_err(|e| {
+        error!("Error creating temporary file: {}", e);
+        error::ErrorInternalServerError("Error saving file")
+    })?;
+    let mut size: u64 = 0;
+    
+    // Read the field data chunk by chunk
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|e| {
+            error!("Error reading multipart chunk: {}", e);
+            error::ErrorInternalServerError("Error reading file data")
+        })?;
+        
+        // Check file size limit while reading
+        size += chunk.len() as u64;
+        if !validate_file_size(size) {
+            return Err(error::ErrorBadRequest("File too large"));
+        }
+        
+        file.write_all(&chunk).map_err(|e| {
+            error!("Error writing file: {}", e);
+            error::ErrorInternalServerError("Error saving file")
+        })?;
+    }
+    
+    file.flush().map_err(|e| {
+        error!("Error writing file: {}", e);
+        error::ErrorInternalServerError("Error saving file")
+    })?;
+    Ok(size)
+}
+
+/// Failure while encrypting into the blob store
+///
+/// Unlike `actix_web::Error` this is `Send`, so it can be returned from `web::block`.
+#[derive(Debug)]
+pub(crate) enum EncryptToBlobError {
+    Blob(BlobError),
+    Encryption(EncryptionError),
+}
+
+impl From<EncryptToBlobError> for Error {
+    fn from(e: EncryptToBlobError) -> Self {
+        match e {
+            EncryptToBlobError::Blob(e) => e.into(),
+            EncryptToBlobError::Encryption(e) => {
+                error!("Error encrypting file: {:?}", e);
+                error::ErrorInternalServerError("Error encrypting file")
+            }
+        }
+    }
+}
+
+/// Encrypts `reader` in the requested container format and stores it as a new blob
+///
+/// SilentLock containers take any mix of passphrases and RSA keys and seal
+/// `metadata` into the file; age files may have several X25519 recipients or
+/// a single passphrase, and have no room for metadata. The ciphertext is
+/// staged in a temporary file so its length is known before it is stored.
+/// Returns the new blob's key and size.
+pub(crate) fn encrypt_to_blob(
+    reader: &mut dyn Read,
+    recipients: &[Recipient],
+    format: ContainerFormat,
+    algorithm: CipherAlgorithm,
+    metadata: &FileMetadata,
+    blob_store: &dyn BlobStore,
+) -> Result<(String, u64), EncryptToBlobError> {
+    let temp_path = get_temp_path("encrypt");
+    
+    // Encrypt into the temporary file
+    let encrypted = File::create(&temp_path)
+        .map_err(EncryptionError::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            match format {
+                ContainerFormat::Age => encrypt_age_stream(&mut &mut *reader, &mut writer, recipients)?,
+                ContainerFormat::SilentLock => encrypt_stream(&mut &mut *reader, &mut writer, recipients, algorithm, Some(metadata))?,
+            };
+            writer.flush()?;
+            Ok(())
+        });
+    
+    // Move the ciphertext into the blob store
+    let result = match encrypted {
+        Ok(()) => {
+            let storage_key = encrypted_key(&format!("{}.encrypted", metadata.filename));
+            blob_store.put_file(&storage_key, &temp_path)
+                .map(|size| (storage_key, size))
+                .map_err(EncryptToBlobError::Blob)
+        },
+        Err(e) => Err(EncryptToBlobError::Encryption(e)),
     };
     
+    if let Err(e) = fs::remove_file(&temp_path) {
+        warn!("Failed to delete temporary file: {}", e);
+    }
+    result
+}
+
+/// Encrypts a stored plaintext file for `recipients`
+///
+/// On success the encrypted file is registered in the store and the original
+/// is deleted.
+async fn encrypt_stored_file(
+    file_info: &FileInfo,
+    recipients: Vec<Recipient>,
+    format: ContainerFormat,
+    algorithm: CipherAlgorithm,
+    file_store: &dyn FileStore,
+    blob_store: &web::Data<dyn BlobStore>,
+) -> Result<FileInfo, Error> {
     // Check if the file is already encrypted
     if file_info.encrypted {
         return Err(error::ErrorBadRequest("File is already encrypted"));
     }
     
-    // Generate a path for the encrypted file
-    let encrypted_path = get_encrypted_path(&format!("{}.encrypted", file_info.filename));
-    
-    // Encrypt the file
-    match encrypt_file_util(
-        &file_info.path,
-        &encrypted_path,
-        &req.passphrase,
-    ) {
-        Ok(_) => {
-            // Get the size of the encrypted file
-            let encrypted_size = fs::metadata(&encrypted_path)
-                .map(|m| m.len())
-                .unwrap_or(0);
-            
-            // Create file info for the encrypted file
-            let encrypted_file_info = FileInfo::new_encrypted(
-                &file_info,
-                encrypted_path,
-                encrypted_size,
-            );
-            
-            // Store file info
-            file_store.add_file(encrypted_file_info.clone());
-            
-            info!("File encrypted: {}", encrypted_file_info.id);
-            
-            // Delete the original file
-            if let Err(e) = fs::remove_file(&file_info.path) {
-                warn!("Failed to delete original file after encryption: {}", e);
-                // Continue even if deletion fails
-            } else {
-                info!("Original file deleted after encryption: {}", file_info.id);
-                // Remove the original file from the file store
-                file_store.remove_file(&file_info.id);
-            }
-            
-            // Return success response
-            Ok(HttpResponse::Ok().json(FileResponse {
-                success: true,
-                message: "File encrypted successfully and original file deleted".to_string(),
-                file: Some(encrypted_file_info),
-            }))
-        },
+    // Encrypt the stored contents into a new blob on a blocking thread
+    let (encrypted_storage_key, encrypted_size) = web::block({
+        let blob_store = blob_store.clone();
+        let storage_key = file_info.storage_key.clone();
+        let metadata = file_info.metadata();
+        move || {
+            let mut reader = blob_store.get(&storage_key).map_err(EncryptToBlobError::Blob)?;
+            encrypt_to_blob(&mut reader, &recipients, format, algorithm, &metadata, blob_store.get_ref())
+        }
+    }).await.map_err(error::ErrorInternalServerError)??;
+    
+    // Create file info for the encrypted file
+    let encrypted_file_info = FileInfo::new_encrypted(
+        file_info,
+        encrypted_storage_key,
+        encrypted_size,
+        format,
+    );
+    
+    // Store file info
+    file_store.add_file(encrypted_file_info.clone())?;
+    
+    info!("File encrypted: {}", encrypted_file_info.id);
+    
+    // Delete the original file
+    let deleted = web::block({
+        let blob_store = blob_store.clone();
+        let storage_key = file_info.storage_key.clone();
+        move || blob_store.delete(&storage_key)
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+    if let Err(e) = deleted {
+        warn!("Failed to delete original file after encryption: {}", e);
+        // Continue even if deletion fails
+    } else {
+        info!("Original file deleted after encryption: {}", file_info.id);
+        // Remove the original file from the file store
+        if let Err(e) = file_store.remove_file(&file_info.id) {
+            warn!("Failed to remove original file record after encryption: {}", e);
+        }
+    }
+    
+    Ok(encrypted_file_info)
+}
+
+/// Decrypts a stored encrypted file with `identity` and returns it as a download
+///
+/// The key is recovered before the response starts and the plaintext is then
+/// streamed as it is decrypted, never touching the disk. A download is only
+/// counted against the file's limit once the key has been recovered. Wrong
+/// credentials count against the file and `client` in the throttle.
+pub(crate) async fn decrypted_download(
+    file_info: &FileInfo,
+    identity: Identity,
+    file_store: &dyn FileStore,
+    blob_store: &web::Data<dyn BlobStore>,
+    throttle: &DecryptThrottle,
+    client: &str,
+) -> Result<HttpResponse, Error> {
+    // Check if the file is encrypted
+    if !file_info.encrypted {
+        return Err(error::ErrorBadRequest("File is not encrypted"));
+    }
+    
+    // Turn away attempts that come too soon after earlier failures
+    let attempt = throttle.check(&file_info.id, client)?;
+    
+    // Read the header and recover the key on a blocking thread
+    let opened = web::block({
+        let blob_store = blob_store.clone();
+        let storage_key = file_info.storage_key.clone();
+        move || -> Result<_, BlobError> {
+            let reader = blob_store.get(&storage_key)?;
+            Ok(open_stream(reader, &identity))
+        }
+    }).await.map_err(error::ErrorInternalServerError)??;
+    let opened = match opened {
+        Ok(opened) => opened,
         Err(e) => {
-            error!("Error encrypting file: {:?}", e);
-            Err(error::ErrorInternalServerError("Error encrypting file"))
+            return match e {
+                EncryptionError::Decryption(_) => {
+                    warn!("Decryption failed, possibly wrong credential: {:?}", e);
+                    attempt.record_failure();
+                    Err(error::ErrorBadRequest("Decryption failed, possibly wrong passphrase or key"))
+                },
+                EncryptionError::Format(_) => {
+                    warn!("Stored file is not a valid SilentLock container: {:?}", e);
+                    Err(error::ErrorBadRequest("File is not a valid encrypted container"))
+                },
+                _ => {
+                    error!("Error decrypting file: {:?}", e);
+                    Err(error::ErrorInternalServerError("Error decrypting file"))
+                }
+            };
+        }
+    };
+    info!("File decrypted and ready for download: {}", file_info.id);
+    attempt.record_success();
+    if claim_download(file_info, file_store)? {
+        discard_last_download(blob_store.get_ref(), file_info);
+    }
+    
+    // Restore the original name and type from the encrypted metadata;
+    // files without it fall back to the stored name minus its suffix
+    let (decrypted_filename, content_type) = match opened.metadata() {
+        Some(metadata) => (
+            sanitize_filename(&metadata.filename),
+            metadata.content_type.clone().unwrap_or_else(|| "application/octet-stream".to_string()),
+        ),
+        None => (
+            legacy_decrypted_filename(&file_info.filename),
+            "application/octet-stream".to_string(),
+        ),
+    };
+    
+    // Return the file as a download
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(content_type)
+        .insert_header(attachment(decrypted_filename));
+    if let Some(metadata) = opened.metadata() {
+        response.insert_header(header::LastModified(SystemTime::from(metadata.uploaded_at).into()));
+    }
+    
+    // Decrypt the payload as the response is sent; a chunk that fails to
+    // authenticate breaks off the download
+    let file_id = file_info.id.clone();
+    Ok(response.streaming(writer_stream(move |writer| {
+        opened.decrypt_to(&mut &mut *writer).map(|_| ()).map_err(|e| {
+            warn!("Decryption of {} failed partway through: {:?}", file_id, e);
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        })
+    })))
+}
+
+/// `Content-Disposition` header offering a download under `filename`
+///
+/// The typed header quotes and escapes the name, so it cannot add parameters.
+fn attachment(filename: String) -> header::ContentDisposition {
+    header::ContentDisposition {
+        disposition: header::DispositionType::Attachment,
+        parameters: vec![header::DispositionParam::Filename(filename)],
+    }
+}
+
+/// Name for a decrypted file whose container has no metadata
+fn legacy_decrypted_filename(stored_filename: &str) -> String {
+    let name = stored_filename
+        .strip_suffix(".encrypted")
+        .or_else(|| stored_filename.strip_suffix(".age"))
+        .unwrap_or(stored_filename);
+    sanitize_filename(name)
+}
+
+/// Handle file encryption
+pub async fn encrypt_file(
+    user: AuthUser,
+    req: web::Json<EncryptRequest>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_store: web::Data<dyn KeyStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Encrypt)?;
+    
+    // Get the file to encrypt; the encrypted file keeps its expiry and
+    // download limit unless new ones are given
+    let mut file_info = owned_file(file_store.get_ref(), &user, &req.file_id)?;
+    if let Some(expires_at) = requested_expiry(req.expires_at, req.expires_in)? {
+        file_info.expires_at = Some(expires_at);
+    }
+    if let Some(max_downloads) = requested_max_downloads(req.max_downloads)? {
+        file_info.downloads_remaining = Some(max_downloads);
+    }
+    
+    let request = req.into_inner();
+    
+    // age recipient strings imply the age format, which has no RSA support
+    let format = if request.recipients.is_empty() { request.format } else { ContainerFormat::Age };
+    if format == ContainerFormat::Age && !request.key_ids.is_empty() {
+        return Err(error::ErrorBadRequest("Registered keys cannot be used with the age format"));
+    }
+    
+    let recipients = if !request.recipients.is_empty() {
+        request.recipients
+            .iter()
+            .map(|r| parse_age_recipient(r))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| error::ErrorBadRequest("Invalid age recipient"))?
+    } else {
+        // Every passphrase and registered key gets its own key slot
+        let mut recipients: Vec<Recipient> = request.passphrase
+            .into_iter()
+            .chain(request.passphrases)
+            .map(Recipient::Passphrase)
+            .collect();
+        for key_id in &request.key_ids {
+            recipients.push(registered_recipient(key_store.get_ref(), key_id)?);
         }
+        recipients
+    };
+    
+    if recipients.is_empty() {
+        return Err(error::ErrorBadRequest("A passphrase, registered key or age recipient is required"));
+    }
+    if format == ContainerFormat::Age && recipients.len() > 1
+        && recipients.iter().any(|r| matches!(r, Recipient::Passphrase(_)))
+    {
+        return Err(error::ErrorBadRequest("The age format only allows a single passphrase"));
+    }
+    
+    let encrypted_file_info = encrypt_stored_file(
+        &file_info,
+        recipients,
+        format,
+        request.algorithm,
+        file_store.get_ref(),
+        &blob_store,
+    ).await?;
+    
+    // Return success response
+    Ok(HttpResponse::Ok().json(FileResponse {
+        success: true,
+        message: "File encrypted successfully and original file deleted".to_string(),
+        file: Some(encrypted_file_info),
+    }))
+}
+
+/// Handle file encryption to a registered RSA public key
+pub async fn encrypt_to_key(
+    user: AuthUser,
+    req: web::Json<EncryptToKeyRequest>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_store: web::Data<dyn KeyStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Encrypt)?;
+    
+    // Get the file to encrypt; the encrypted file keeps its expiry and
+    // download limit unless new ones are given
+    let mut file_info = owned_file(file_store.get_ref(), &user, &req.file_id)?;
+    if let Some(expires_at) = requested_expiry(req.expires_at, req.expires_in)? {
+        file_info.expires_at = Some(expires_at);
     }
+    if let Some(max_downloads) = requested_max_downloads(req.max_downloads)? {
+        file_info.downloads_remaining = Some(max_downloads);
+    }
+    
+    // Get the key to encrypt to
+    let key_info = match key_store.get_key(&req.key_id)? {
+        Some(key) => key,
+        None => return Err(error::ErrorNotFound("Key not found")),
+    };
+    
+    let recipient = registered_recipient(key_store.get_ref(), &key_info.id)?;
+    let encrypted_file_info = encrypt_stored_file(
+        &file_info,
+        vec![recipient],
+        ContainerFormat::SilentLock,
+        req.algorithm,
+        file_store.get_ref(),
+        &blob_store,
+    ).await?;
+    
+    // Return success response
+    Ok(HttpResponse::Ok().json(FileResponse {
+        success: true,
+        message: format!("File encrypted to key {} and original file deleted", key_info.name),
+        file: Some(encrypted_file_info),
+    }))
 }
 
 /// Handle file decryption
 pub async fn decrypt_file(
+    user: AuthUser,
+    http_req: HttpRequest,
     req: web::Json<DecryptRequest>,
     form: Option<web::Form<DecryptRequest>>,
-    file_store: web::Data<FileStore>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
+    throttle: web::Data<DecryptThrottle>,
 ) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Decrypt)?;
+    
     // Get the request data from either JSON or form data
     let request = if let Some(form_data) = form {
         form_data.into_inner()
@@ -170,86 +657,65 @@ pub async fn decrypt_file(
     };
     
     // Get the file to decrypt
-    let file_info = match file_store.get_file(&request.file_id) {
-        Some(file) => file,
-        None => return Err(error::ErrorNotFound("File not found")),
+    let file_info = owned_file(file_store.get_ref(), &user, &request.file_id)?;
+    
+    // An age secret key takes precedence over a passphrase
+    let identity = match (request.identity, request.passphrase) {
+        (Some(identity), _) => parse_age_identity(&identity)
+            .map_err(|_| error::ErrorBadRequest("Invalid age identity"))?,
+        (None, Some(passphrase)) => Identity::Passphrase(passphrase),
+        (None, None) => return Err(error::ErrorBadRequest("A passphrase or age identity is required")),
     };
     
-    // Check if the file is encrypted
-    if !file_info.encrypted {
-        return Err(error::ErrorBadRequest("File is not encrypted"));
-    }
+    decrypted_download(
+        &file_info,
+        identity,
+        file_store.get_ref(),
+        &blob_store,
+        throttle.get_ref(),
+        &client_id(&http_req),
+    ).await
+}
+
+/// Handle file decryption with an RSA private key
+pub async fn decrypt_with_key(
+    user: AuthUser,
+    http_req: HttpRequest,
+    req: web::Json<DecryptWithKeyRequest>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
+    throttle: web::Data<DecryptThrottle>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Decrypt)?;
     
-    // Generate a temporary path for the decrypted file
-    let decrypted_filename = file_info.filename.replace(".encrypted", "");
-    let temp_decrypted_path = get_upload_path(&format!("temp_decrypted_{}", decrypted_filename));
-    
-    // Decrypt the file to a temporary location
-    match decrypt_file_util(
-        &file_info.path,
-        &temp_decrypted_path,
-        &request.passphrase,
-    ) {
-        Ok(_) => {
-            // Read the decrypted file
-            let mut file = match fs::File::open(&temp_decrypted_path) {
-                Ok(file) => file,
-                Err(e) => {
-                    error!("Error opening decrypted file: {}", e);
-                    return Err(error::ErrorInternalServerError("Error opening decrypted file"));
-                }
-            };
-            
-            // Read file content into a buffer
-            let mut buffer = Vec::new();
-            if let Err(e) = file.read_to_end(&mut buffer) {
-                error!("Error reading decrypted file: {}", e);
-                return Err(error::ErrorInternalServerError("Error reading decrypted file"));
-            }
-            
-            // Close the file handle
-            drop(file);
-            
-            // Delete the temporary decrypted file
-            if let Err(e) = fs::remove_file(&temp_decrypted_path) {
-                warn!("Failed to delete temporary decrypted file: {}", e);
-                // Continue even if deletion fails
-            }
-            
-            info!("File decrypted and ready for download: {}", file_info.id);
-            
-            // Determine content type (use a generic one if not known)
-            let content_type = "application/octet-stream".to_string();
-            
-            // Return the file as a download
-            Ok(HttpResponse::Ok()
-                .content_type(content_type)
-                .append_header((
-                    header::CONTENT_DISPOSITION,
-                    format!("attachment; filename=\"{}\"", decrypted_filename),
-                ))
-                .body(buffer))
-        },
-        Err(e) => {
-            match e {
-                EncryptionError::Decryption(_) => {
-                    warn!("Decryption failed, possibly wrong passphrase: {:?}", e);
-                    Err(error::ErrorBadRequest("Decryption failed, possibly wrong passphrase"))
-                },
-                _ => {
-                    error!("Error decrypting file: {:?}", e);
-                    Err(error::ErrorInternalServerError("Error decrypting file"))
-                }
-            }
-        }
-    }
+    // Get the file to decrypt
+    let file_info = owned_file(file_store.get_ref(), &user, &req.file_id)?;
+    
+    let private_key = import_private_key(&req.private_key_pem)
+        .map_err(|_| error::ErrorBadRequest("Invalid private key"))?;
+    
+    decrypted_download(
+        &file_info,
+        Identity::RsaPrivateKey(Box::new(private_key)),
+        file_store.get_ref(),
+        &blob_store,
+        throttle.get_ref(),
+        &client_id(&http_req),
+    ).await
 }
 
-/// List all files
+/// List the signed-in user's files
 pub async fn list_files(
-    file_store: web::Data<FileStore>,
+    user: AuthUser,
+    file_store: web::Data<dyn FileStore>,
 ) -> Result<HttpResponse, Error> {
-    let files = file_store.list_files();
+    user.require(TokenScope::Read)?;
+    
+    // Expired files are hidden until the reaper deletes them
+    let files = file_store.list_files()?
+        .into_iter()
+        .filter(|file| file.is_owned_by(&user.id) && !file.is_expired())
+        .collect();
     
     Ok(HttpResponse::Ok().json(ListFilesResponse {
         files,
@@ -258,12 +724,20 @@ pub async fn list_files(
 
 /// Upload a file with encryption
 pub async fn upload_encrypt_file(
+    user: AuthUser,
     mut payload: Multipart,
     encrypt_req: web::Query<UploadEncryptRequest>,
-    file_store: web::Data<FileStore>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
 ) -> Result<HttpResponse, Error> {
-    // Process multipart form data
-    while let Ok(Some(mut field)) = payload.try_next().await {
+    user.require(TokenScope::Upload)?;
+    user.require(TokenScope::Encrypt)?;
+    
+    let expires_at = requested_expiry(encrypt_req.expires_at, encrypt_req.expires_in)?;
+    let max_downloads = requested_max_downloads(encrypt_req.max_downloads)?;
+    
+    // Only the first field of the form is used
+    if let Ok(Some(mut field)) = payload.try_next().await {
         let content_disposition = field.content_disposition();
         
         // Get filename from the Content-Disposition header
@@ -284,135 +758,353 @@ pub async fn upload_encrypt_file(
             }
         }
         
-        // Generate a path for the uploaded file (temporary)
-        let temp_path = get_upload_path(&format!("temp_{}", &filename));
+        // Describe the plaintext, which is never stored
+        let mut original_file_info = FileInfo::new(
+            filename,
+            0,
+            content_type,
+            String::new(),
+        );
+        original_file_info.expires_at = expires_at;
+        original_file_info.downloads_remaining = max_downloads;
+        original_file_info.owner = Some(user.id.clone());
         
-        // Create a buffer to store the file
-        let mut data = Vec::new();
+        // Encrypt on a blocking thread, fed with the field data as it arrives
+        let (sender, receiver) = tokio::sync::mpsc::channel(UPLOAD_CHANNEL_CHUNKS);
+        let encryption = web::block({
+            let recipients = [Recipient::Passphrase(encrypt_req.passphrase.clone())];
+            let metadata = original_file_info.metadata();
+            let blob_store = blob_store.clone();
+            let (format, algorithm) = (encrypt_req.format, encrypt_req.algorithm);
+            move || encrypt_to_blob(
+                &mut ChunkReader::new(receiver),
+                &recipients,
+                format,
+                algorithm,
+                &metadata,
+                blob_store.get_ref(),
+            )
+        });
         
-        // Read the field data
+        // Read the field data, handing each chunk to the encryption thread
+        let mut upload_error = None;
         while let Some(chunk) = field.next().await {
-            let chunk = chunk.map_err(|e| {
-                error!("Error reading multipart chunk: {}", e);
-                error::ErrorInternalServerError("Error reading file data")
-            })?;
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    error!("Error reading multipart chunk: {}", e);
+                    upload_error = Some(error::ErrorInternalServerError("Error reading file data"));
+                    break;
+                }
+            };
             
             // Check file size limit while reading
-            data.extend_from_slice(&chunk);
-            if !validate_file_size(data.len() as u64) {
-                return Err(error::ErrorBadRequest("File too large"));
+            original_file_info.size += chunk.len() as u64;
+            if !validate_file_size(original_file_info.size) {
+                upload_error = Some(error::ErrorBadRequest("File too large"));
+                break;
+            }
+            
+            // The receiver is only gone if encryption has already failed
+            if sender.send(Ok(chunk)).await.is_err() {
+                break;
             }
         }
         
-        // Save the file to disk temporarily
-        save_file_to_disk(&data, &temp_path).map_err(|e| {
-            error!("Error saving file: {}", e);
-            error::ErrorInternalServerError("Error saving file")
-        })?;
+        // An error makes the encryption fail instead of sealing a truncated file
+        if upload_error.is_some() {
+            let _ = sender.send(Err(io::Error::other("Upload aborted"))).await;
+        }
+        drop(sender);
         
-        // Create temporary file info
-        let temp_file_info = FileInfo::new(
-            filename.clone(),
-            data.len() as u64,
-            content_type,
-            temp_path.clone(),
+        let encrypted = encryption.await.map_err(error::ErrorInternalServerError)?;
+        if let Some(e) = upload_error {
+            return Err(e);
+        }
+        let (encrypted_storage_key, encrypted_size) = encrypted?;
+        
+        // Create file info for the encrypted file
+        let encrypted_file_info = FileInfo::new_encrypted(
+            &original_file_info,
+            encrypted_storage_key,
+            encrypted_size,
+            encrypt_req.format,
         );
         
-        // Generate a path for the encrypted file
-        let encrypted_path = get_encrypted_path(&format!("{}.encrypted", filename));
+        // Store file info
+        file_store.add_file(encrypted_file_info.clone())?;
         
-        // Encrypt the file
-        match encrypt_file_util(
-            &temp_path,
-            &encrypted_path,
-            &encrypt_req.passphrase,
-        ) {
-            Ok(_) => {
-                // Get the size of the encrypted file
-                let encrypted_size = fs::metadata(&encrypted_path)
-                    .map(|m| m.len())
-                    .unwrap_or(0);
-                
-                // Create file info for the encrypted file
-                let encrypted_file_info = FileInfo::new_encrypted(
-                    &temp_file_info,
-                    encrypted_path,
-                    encrypted_size,
-                );
-                
-                // Store file info
-                file_store.add_file(encrypted_file_info.clone());
-                
-                info!("File uploaded and encrypted: {}", encrypted_file_info.id);
-                
-                // Delete the temporary file
-                if let Err(e) = fs::remove_file(&temp_path) {
-                    warn!("Failed to delete temporary file: {}", e);
-                    // Continue even if deletion fails
-                }
-                
-                // Return success response
-                return Ok(HttpResponse::Ok().json(FileResponse {
-                    success: true,
-                    message: "File uploaded and encrypted successfully".to_string(),
-                    file: Some(encrypted_file_info),
-                }));
-            },
-            Err(e) => {
-                // Delete the temporary file
-                if let Err(delete_err) = fs::remove_file(&temp_path) {
-                    warn!("Failed to delete temporary file: {}", delete_err);
-                }
-                
-                error!("Error encrypting file: {:?}", e);
-                return Err(error::ErrorInternalServerError("Error encrypting file"));
-            }
-        }
+        info!("File uploaded and encrypted: {}", encrypted_file_info.id);
+        
+        // Return success response
+        return Ok(HttpResponse::Ok().json(FileResponse {
+            success: true,
+            message: "File uploaded and encrypted successfully".to_string(),
+            file: Some(encrypted_file_info),
+        }));
     }
     
     // If we get here, no file was uploaded
     Err(error::ErrorBadRequest("No file uploaded"))
 }
 
+/// Delete a file and its stored contents
+///
+/// With `secure=true` the contents of an unencrypted file are overwritten
+/// before removal. Encrypted files are only ever stored as ciphertext, so they
+/// are simply deleted.
+pub async fn delete_file(
+    user: AuthUser,
+    path: web::Path<String>,
+    delete_req: web::Query<DeleteFileRequest>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Delete)?;
+    
+    let file_id = path.into_inner();
+    
+    // Get the file info; expired files can still be deleted by their owner
+    let file_info = match file_store.get_file(&file_id)? {
+        Some(file) if file.is_owned_by(&user.id) => file,
+        _ => return Err(error::ErrorNotFound("File not found")),
+    };
+    
+    // Delete the stored contents first, so a failure leaves the record in place
+    let secure = delete_req.secure && !file_info.encrypted;
+    let storage_key = file_info.storage_key.clone();
+    web::block({
+        let blob_store = blob_store.clone();
+        move || if secure {
+            blob_store.secure_delete(&storage_key)
+        } else {
+            blob_store.delete(&storage_key)
+        }
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+    
+    // Remove the file record
+    file_store.remove_file(&file_info.id)?;
+    
+    info!("File deleted{}: {}", if secure { " securely" } else { "" }, file_info.id);
+    
+    Ok(HttpResponse::Ok().json(FileResponse {
+        success: true,
+        message: "File deleted successfully".to_string(),
+        file: Some(file_info),
+    }))
+}
+
 /// Download a file
 pub async fn download_file(
+    user: AuthUser,
+    req: HttpRequest,
     path: web::Path<String>,
-    file_store: web::Data<FileStore>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
 ) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Read)?;
+    
     let file_id = path.into_inner();
     
     // Get the file info
-    let file_info = match file_store.get_file(&file_id) {
-        Some(file) => file,
-        None => return Err(error::ErrorNotFound("File not found")),
+    let file_info = owned_file(file_store.get_ref(), &user, &file_id)?;
+    
+    stored_download(&req, file_info, file_store.get_ref(), blob_store).await
+}
+
+/// Streams a file's stored contents as a download
+///
+/// Supports single `Range` requests, `ETag` and `Last-Modified` validators,
+/// `If-None-Match` and `HEAD`. Only responses with a body count against a
+/// download limit.
+pub(crate) async fn stored_download(
+    req: &HttpRequest,
+    file_info: FileInfo,
+    file_store: &dyn FileStore,
+    blob_store: web::Data<dyn BlobStore>,
+) -> Result<HttpResponse, Error> {
+    // Look up the stored blob; object stores are asked over the network,
+    // so this and opening the blob stay off the async workers
+    let blob_info = web::block({
+        let blob_store = blob_store.clone();
+        let storage_key = file_info.storage_key.clone();
+        move || blob_store.stat(&storage_key)
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??
+    .ok_or_else(|| error::ErrorNotFound("File data not found"))?;
+    
+    // Build the validators
+    let etag = blob_etag(&file_info.storage_key, &blob_info);
+    let last_modified = SystemTime::from(blob_info.modified.unwrap_or(file_info.uploaded_at));
+    
+    // Answer a matching If-None-Match without a body
+    let not_modified = match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
     };
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .insert_header(header::LastModified(last_modified.into()))
+            .finish());
+    }
     
-    // Read the file
-    let mut file = match fs::File::open(&file_info.path) {
-        Ok(file) => file,
-        Err(e) => {
-            error!("Error opening file: {}", e);
-            return Err(error::ErrorInternalServerError("Error opening file"));
-        }
+    // Only honour Range if If-Range, when present, still matches
+    let range_applies = match req.get_header::<header::IfRange>() {
+        Some(header::IfRange::EntityTag(tag)) => tag.strong_eq(&etag),
+        Some(header::IfRange::Date(date)) => SystemTime::from(date) == last_modified,
+        None => true,
     };
     
-    // Read file content into a buffer
-    let mut buffer = Vec::new();
-    if let Err(e) = file.read_to_end(&mut buffer) {
-        error!("Error reading file: {}", e);
-        return Err(error::ErrorInternalServerError("Error reading file"));
-    }
+    let range = req.get_header::<header::Range>().filter(|_| range_applies);
+    let range = match requested_range(range.as_ref(), blob_info.size) {
+        RequestedRange::Whole => None,
+        RequestedRange::Part(start, end) => Some((start, end)),
+        RequestedRange::Unsatisfiable => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(blob_info.size),
+                }))
+                .finish());
+        },
+    };
     
     // Determine content type
     let content_type = file_info.content_type.clone()
         .unwrap_or_else(|| "application/octet-stream".to_string());
     
-    // Build response with file content
-    Ok(HttpResponse::Ok()
+    let mut response = match range {
+        Some(_) => HttpResponse::PartialContent(),
+        None => HttpResponse::Ok(),
+    };
+    response
         .content_type(content_type)
-        .append_header((
-            header::CONTENT_DISPOSITION,
-            format!("attachment; filename=\"{}\"", file_info.filename),
-        ))
-        .body(buffer))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(header::ETag(etag))
+        .insert_header(header::LastModified(last_modified.into()))
+        .insert_header(attachment(file_info.filename.clone()));
+    
+    let (offset, len) = match range {
+        Some((start, end)) => {
+            response.insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                range: Some((start, end)),
+                instance_length: Some(blob_info.size),
+            }));
+            (start, end - start + 1)
+        },
+        None => (0, blob_info.size),
+    };
+    
+    // A HEAD response carries the headers of the GET response but no body
+    if req.method() == Method::HEAD {
+        return Ok(response.body(SizedStream::new(len, futures::stream::empty::<Result<Bytes, Error>>())));
+    }
+    
+    // Open the requested bytes in the blob store before anything is counted
+    let reader = web::block({
+        let blob_store = blob_store.clone();
+        let storage_key = file_info.storage_key.clone();
+        move || blob_store.get_range(&storage_key, offset, len)
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+    
+    // Every download with a body counts against the limit, including partial ones
+    if claim_download(&file_info, file_store)? {
+        discard_last_download(blob_store.get_ref(), &file_info);
+    }
+    Ok(response.body(SizedStream::new(len, reader_stream(reader))))
+}
+
+/// Bytes of a blob selected by a `Range` header
+#[derive(Debug, PartialEq, Eq)]
+enum RequestedRange {
+    /// The whole blob
+    Whole,
+    
+    /// First and last byte, inclusive
+    Part(u64, u64),
+    
+    /// None of the requested bytes exist
+    Unsatisfiable,
+}
+
+/// Works out which bytes of a blob of `size` bytes a `Range` header asks for
+///
+/// Multiple ranges are answered with the whole blob.
+fn requested_range(range: Option<&header::Range>, size: u64) -> RequestedRange {
+    match range {
+        Some(header::Range::Bytes(specs)) if specs.len() == 1 => {
+            match specs[0].to_satisfiable_range(size) {
+                Some((start, end)) => RequestedRange::Part(start, end),
+                None => RequestedRange::Unsatisfiable,
+            }
+        },
+        _ => RequestedRange::Whole,
+    }
+}
+
+/// Computes a strong entity tag for a stored blob
+///
+/// Any rewrite of the blob, such as a rekey, changes its modification time.
+fn blob_etag(storage_key: &str, blob_info: &BlobInfo) -> header::EntityTag {
+    let mut hasher = Sha256::new();
+    hasher.update(storage_key.as_bytes());
+    hasher.update(blob_info.size.to_be_bytes());
+    if let Some(modified) = blob_info.modified {
+        hasher.update(modified.timestamp_nanos_opt().unwrap_or_default().to_be_bytes());
+    }
+    header::EntityTag::new_strong(to_hex(&hasher.finalize()[..16]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn range(value: &str, size: u64) -> RequestedRange {
+        let range: header::Range = value.parse().unwrap();
+        requested_range(Some(&range), size)
+    }
+    
+    #[test]
+    fn no_range_is_the_whole_blob() {
+        assert_eq!(requested_range(None, 100), RequestedRange::Whole);
+    }
+    
+    #[test]
+    fn single_ranges() {
+        assert_eq!(range("bytes=0-9", 100), RequestedRange::Part(0, 9));
+        assert_eq!(range("bytes=10-", 100), RequestedRange::Part(10, 99));
+        assert_eq!(range("bytes=-10", 100), RequestedRange::Part(90, 99));
+        assert_eq!(range("bytes=0-0", 1), RequestedRange::Part(0, 0));
+    }
+    
+    #[test]
+    fn ranges_past_the_end_are_clamped() {
+        assert_eq!(range("bytes=90-200", 100), RequestedRange::Part(90, 99));
+        assert_eq!(range("bytes=-200", 100), RequestedRange::Part(0, 99));
+    }
+    
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(range("bytes=100-", 100), RequestedRange::Unsatisfiable);
+        assert_eq!(range("bytes=150-200", 100), RequestedRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-", 0), RequestedRange::Unsatisfiable);
+        assert_eq!(range("bytes=-0", 100), RequestedRange::Unsatisfiable);
+    }
+    
+    #[test]
+    fn multiple_ranges_are_the_whole_blob() {
+        assert_eq!(range("bytes=0-9,20-29", 100), RequestedRange::Whole);
+    }
+    
+    #[test]
+    fn other_units_are_the_whole_blob() {
+        assert_eq!(range("lines=0-9", 100), RequestedRange::Whole);
+    }
 }
@@ -0,0 +1,96 @@
+use actix_web::{web, HttpResponse, Error, error, Result};
+use log::{info, error};
+
+use crate::handlers::auth::AuthUser;
+use crate::models::{PublicKeyInfo, KeyResponse, ListKeysResponse, RegisterKeyRequest, TokenScope};
+use crate::store::KeyStore;
+use crate::utils::to_hex;
+use crate::encryption::{import_public_key, public_key_fingerprint, export_public_key, Recipient};
+
+/// Looks up a registered public key and turns it into a recipient
+pub fn registered_recipient(key_store: &dyn KeyStore, key_id: &str) -> Result<Recipient, Error> {
+    let key_info = match key_store.get_key(key_id)? {
+        Some(key) => key,
+        None => return Err(error::ErrorNotFound("Key not found")),
+    };
+    
+    let public_key = import_public_key(&key_info.public_key_pem).map_err(|e| {
+        error!("Stored public key {} is invalid: {:?}", key_info.id, e);
+        error::ErrorInternalServerError("Stored public key is invalid")
+    })?;
+    
+    Ok(Recipient::RsaPublicKey(Box::new(public_key)))
+}
+
+/// Register an RSA public key that files can be encrypted to
+///
+/// Keys are public, so every signed-in user can see and encrypt to them.
+pub async fn register_key(
+    user: AuthUser,
+    req: web::Json<RegisterKeyRequest>,
+    key_store: web::Data<dyn KeyStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Upload)?;
+    
+    let request = req.into_inner();
+    
+    if request.name.trim().is_empty() {
+        return Err(error::ErrorBadRequest("Key name is required"));
+    }
+    
+    // Parse the key to make sure it is usable before storing it
+    let public_key = import_public_key(&request.public_key_pem)
+        .map_err(|_| error::ErrorBadRequest("Invalid RSA public key"))?;
+    
+    let fingerprint = public_key_fingerprint(&public_key)
+        .map_err(|_| error::ErrorBadRequest("Invalid RSA public key"))?;
+    
+    // Store the key in a normalized SPKI encoding
+    let public_key_pem = export_public_key(&public_key)
+        .map_err(|_| error::ErrorBadRequest("Invalid RSA public key"))?;
+    
+    let key_info = PublicKeyInfo::new(request.name, to_hex(&fingerprint), public_key_pem);
+    key_store.add_key(key_info.clone())?;
+    
+    info!("Public key registered: {} ({})", key_info.id, key_info.fingerprint);
+    
+    Ok(HttpResponse::Ok().json(KeyResponse {
+        success: true,
+        message: "Public key registered successfully".to_string(),
+        key: Some(key_info),
+    }))
+}
+
+/// List all registered public keys
+pub async fn list_keys(
+    user: AuthUser,
+    key_store: web::Data<dyn KeyStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Read)?;
+    
+    let keys = key_store.list_keys()?;
+    
+    Ok(HttpResponse::Ok().json(ListKeysResponse {
+        keys,
+    }))
+}
+
+/// Get a single registered public key
+pub async fn get_key(
+    user: AuthUser,
+    path: web::Path<String>,
+    key_store: web::Data<dyn KeyStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Read)?;
+    
+    let key_id = path.into_inner();
+    
+    match key_store.get_key(&key_id)? {
+        Some(key_info) => Ok(HttpResponse::Ok().json(KeyResponse {
+            success: true,
+            message: "Public key found".to_string(),
+            key: Some(key_info),
+        })),
+        None => Err(error::ErrorNotFound("Key not found")),
+    }
+}
@@ -1 +1,85 @@
+use actix_web::{web, middleware, HttpResponse, Responder, http::Method};
+
+pub mod auth;
 pub mod files;
+pub mod keys;
+pub mod recipients;
+pub mod shares;
+pub mod tokens;
+pub mod tus;
+
+#[cfg(test)]
+pub(crate) mod testing;
+
+/// Registers the API routes and the public share link routes
+///
+/// Handlers expect the file, share, user, key and blob stores, the
+/// `TusUploads` registry, the `DecryptThrottle` and the `RewriteLocks` as app
+/// data. Everything under `/api` except the health check and the account
+/// routes requires a signed-in user or an API token, which is checked before
+/// any handler runs.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    // API routes
+    cfg.service(
+        web::scope("/api")
+            .wrap(middleware::from_fn(auth::bearer_auth))
+            .route("/health", web::get().to(health_check))
+            .service(
+                web::scope("/auth")
+                    .route("/register", web::post().to(auth::register))
+                    .route("/login", web::post().to(auth::login))
+                    .route("/logout", web::post().to(auth::logout))
+                    .route("/me", web::get().to(auth::current_user))
+            )
+            .service(
+                web::scope("/files")
+                    .route("/upload", web::post().to(files::upload_file))
+                    .route("/upload-encrypt", web::post().to(files::upload_encrypt_file))
+                    .route("/encrypt", web::post().to(files::encrypt_file))
+                    .route("/decrypt", web::post().to(files::decrypt_file))
+                    .route("/encrypt-to-key", web::post().to(files::encrypt_to_key))
+                    .route("/decrypt-with-key", web::post().to(files::decrypt_with_key))
+                    .route("/list", web::get().to(files::list_files))
+                    .route("/download/{file_id}", web::get().to(files::download_file))
+                    .route("/download/{file_id}", web::head().to(files::download_file))
+                    .route("/tus", web::post().to(tus::create_upload))
+                    .route("/tus", web::method(Method::OPTIONS).to(tus::options))
+                    .route("/tus/{upload_id}", web::head().to(tus::upload_status))
+                    .route("/tus/{upload_id}", web::patch().to(tus::append_upload))
+                    .route("/tus/{upload_id}", web::delete().to(tus::delete_upload))
+                    .route("/{file_id}/recipients", web::get().to(recipients::list_recipients))
+                    .route("/{file_id}/recipients", web::post().to(recipients::add_recipient))
+                    .route("/{file_id}/recipients/{index}", web::delete().to(recipients::remove_recipient))
+                    .route("/{file_id}/rekey", web::post().to(recipients::rekey))
+                    .route("/{file_id}/shares", web::get().to(shares::list_shares))
+                    .route("/{file_id}/shares", web::post().to(shares::create_share))
+                    .route("/{file_id}/shares/{share_id}", web::delete().to(shares::revoke_share))
+                    .route("/{file_id}", web::delete().to(files::delete_file))
+            )
+            .service(
+                web::scope("/keys")
+                    .route("", web::post().to(keys::register_key))
+                    .route("/list", web::get().to(keys::list_keys))
+                    .route("/{key_id}", web::get().to(keys::get_key))
+            )
+            .service(
+                web::scope("/tokens")
+                    .route("", web::post().to(tokens::create_token))
+                    .route("", web::get().to(tokens::list_tokens))
+                    .route("/{token_id}", web::delete().to(tokens::revoke_token))
+            )
+    );
+    
+    // Public share links
+    cfg.route("/s/{token}", web::get().to(shares::open_share))
+        .route("/s/{token}", web::head().to(shares::open_share))
+        .route("/s/{token}", web::post().to(shares::open_share_with));
+}
+
+// Health check endpoint
+async fn health_check() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION")
+    }))
+}
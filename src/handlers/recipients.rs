@@ -0,0 +1,503 @@
+use actix_web::{web, HttpRequest, HttpResponse, Error, error, Result};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::sync::Mutex;
+use log::{info, error, warn};
+
+use crate::blob::{BlobError, BlobStore};
+use crate::handlers::auth::AuthUser;
+use crate::handlers::files::owned_file;
+use crate::handlers::keys::registered_recipient;
+use crate::models::{User, FileInfo, FileResponse, KeySlotInfo, ListRecipientsResponse, AddRecipientRequest, RemoveRecipientRequest, RekeyRequest, TokenScope};
+use crate::store::{FileStore, KeyStore, StoreError};
+use crate::throttle::{client_id, Attempt, DecryptThrottle};
+use crate::utils::{get_temp_path, to_hex};
+use crate::encryption::{
+    add_recipient_stream, remove_key_slot_stream, rekey_stream, import_private_key,
+    EncryptionError, Header, Identity, KeySlot, Recipient,
+};
+
+/// Looks up a file of `user` that key slots can be managed on
+fn encrypted_file(file_store: &dyn FileStore, user: &User, file_id: &str) -> Result<FileInfo, Error> {
+    let file_info = owned_file(file_store, user, file_id)?;
+    
+    if !file_info.encrypted {
+        return Err(error::ErrorBadRequest("File is not encrypted"));
+    }
+    
+    Ok(file_info)
+}
+
+/// Builds the credential that proves the caller can already open the file
+fn current_identity(passphrase: Option<String>, private_key_pem: Option<String>) -> Result<Identity, Error> {
+    match (private_key_pem, passphrase) {
+        (Some(pem), _) => import_private_key(&pem)
+            .map(|key| Identity::RsaPrivateKey(Box::new(key)))
+            .map_err(|_| error::ErrorBadRequest("Invalid private key")),
+        (None, Some(passphrase)) => Ok(Identity::Passphrase(passphrase)),
+        (None, None) => Err(error::ErrorBadRequest("A passphrase or private key that opens the file is required")),
+    }
+}
+
+/// Builds the recipient named by a `new_passphrase` / `key_id` pair
+fn new_recipient(
+    new_passphrase: Option<String>,
+    key_id: Option<String>,
+    key_store: &dyn KeyStore,
+) -> Result<Recipient, Error> {
+    match (new_passphrase, key_id) {
+        (Some(passphrase), None) => Ok(Recipient::Passphrase(passphrase)),
+        (None, Some(key_id)) => registered_recipient(key_store, &key_id),
+        _ => Err(error::ErrorBadRequest("Exactly one of new_passphrase or key_id is required")),
+    }
+}
+
+/// Maps a failed key slot operation to a client or server error
+fn key_slot_error(e: EncryptionError) -> Error {
+    match e {
+        EncryptionError::Decryption(_) => {
+            warn!("Key slot change rejected, possibly wrong credential: {:?}", e);
+            error::ErrorBadRequest("The supplied passphrase or key does not open this file")
+        },
+        EncryptionError::Format(message) | EncryptionError::KeySlot(message) => {
+            error::ErrorBadRequest(message)
+        },
+        _ => {
+            error!("Error updating key slots: {:?}", e);
+            error::ErrorInternalServerError("Error updating key slots")
+        }
+    }
+}
+
+/// Failure while reading or rewriting a stored file
+///
+/// Unlike `actix_web::Error` this is `Send`, so it can be returned from `web::block`.
+#[derive(Debug)]
+enum RewriteError {
+    Blob(BlobError),
+    KeySlot(EncryptionError),
+}
+
+impl From<RewriteError> for Error {
+    fn from(e: RewriteError) -> Self {
+        match e {
+            RewriteError::Blob(e) => e.into(),
+            RewriteError::KeySlot(e) => key_slot_error(e),
+        }
+    }
+}
+
+/// Stored files whose key slots are being rewritten
+///
+/// A rewrite reads the whole blob and then replaces it, so two at once on the
+/// same file would each drop the other's key slot change.
+pub struct RewriteLocks {
+    keys: Mutex<HashSet<String>>,
+}
+
+impl RewriteLocks {
+    pub fn new() -> Self {
+        Self {
+            keys: Mutex::new(HashSet::new()),
+        }
+    }
+    
+    /// Claims `storage_key` until the guard is dropped
+    ///
+    /// Fails with 409 Conflict while another rewrite of the same file runs.
+    fn lock(&self, storage_key: &str) -> Result<RewriteGuard<'_>, Error> {
+        if !self.keys.lock().unwrap().insert(storage_key.to_string()) {
+            return Err(error::ErrorConflict("The key slots of this file are already being changed"));
+        }
+        Ok(RewriteGuard {
+            locks: self,
+            storage_key: storage_key.to_string(),
+        })
+    }
+}
+
+impl Default for RewriteLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Claim on a stored file for one rewrite, released when dropped
+struct RewriteGuard<'a> {
+    locks: &'a RewriteLocks,
+    storage_key: String,
+}
+
+impl Drop for RewriteGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.keys.lock().unwrap().remove(&self.storage_key);
+    }
+}
+
+/// Rewrites a stored encrypted file through `rewrite`, returning its new size
+///
+/// The output is staged in a temporary file and only replaces the blob once
+/// `rewrite` has succeeded.
+fn rewrite_blob<F>(blob_store: &dyn BlobStore, key: &str, rewrite: F) -> Result<u64, RewriteError>
+where
+    F: FnOnce(&mut Box<dyn Read + Send>, &mut BufWriter<File>) -> Result<(), EncryptionError>,
+{
+    let mut reader = blob_store.get(key).map_err(RewriteError::Blob)?;
+    let temp_path = get_temp_path("rewrite");
+    
+    let rewritten = File::create(&temp_path)
+        .map_err(EncryptionError::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            rewrite(&mut reader, &mut writer)?;
+            writer.flush()?;
+            Ok(())
+        });
+    let result = match rewritten {
+        Ok(()) => blob_store.put_file(key, &temp_path).map_err(RewriteError::Blob),
+        Err(e) => Err(RewriteError::KeySlot(e)),
+    };
+    
+    if let Err(e) = fs::remove_file(&temp_path) {
+        warn!("Failed to delete temporary file: {}", e);
+    }
+    result
+}
+
+/// Counts the outcome of a rewrite that needed the file's current credential
+fn count_attempt(result: Result<u64, RewriteError>, attempt: Attempt<'_>) -> Result<u64, RewriteError> {
+    match &result {
+        Ok(_) => attempt.record_success(),
+        Err(RewriteError::KeySlot(EncryptionError::Decryption(_))) => attempt.record_failure(),
+        Err(_) => {},
+    }
+    result
+}
+
+/// Records the new size of a file whose header was rewritten
+fn refresh_file_info(mut file_info: FileInfo, size: u64, file_store: &dyn FileStore) -> Result<FileInfo, Error> {
+    file_info.size = size;
+    file_store.update_file(file_info.clone())?;
+    Ok(file_info)
+}
+
+/// List the key slots of an encrypted file
+pub async fn list_recipients(
+    user: AuthUser,
+    path: web::Path<String>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_store: web::Data<dyn KeyStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Read)?;
+    
+    let file_info = encrypted_file(file_store.get_ref(), &user, &path.into_inner())?;
+    
+    // Object stores are read over the network, so off the async workers
+    let storage_key = file_info.storage_key.clone();
+    let header = web::block(move || {
+        let mut reader = blob_store.get(&storage_key).map_err(RewriteError::Blob)?;
+        Header::read_from(&mut reader)
+            .map(|(header, _)| header)
+            .map_err(RewriteError::KeySlot)
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+    
+    let recipients = header.key_slots
+        .iter()
+        .enumerate()
+        .map(|(index, slot)| match slot {
+            KeySlot::Passphrase { .. } => Ok(KeySlotInfo {
+                index,
+                kind: "passphrase".to_string(),
+                fingerprint: None,
+                key_id: None,
+            }),
+            KeySlot::Rsa { fingerprint, .. } => {
+                let fingerprint = to_hex(fingerprint);
+                Ok(KeySlotInfo {
+                    index,
+                    kind: "rsa".to_string(),
+                    key_id: key_store.find_key_by_fingerprint(&fingerprint)?.map(|k| k.id),
+                    fingerprint: Some(fingerprint),
+                })
+            },
+        })
+        .collect::<Result<_, StoreError>>()?;
+    
+    Ok(HttpResponse::Ok().json(ListRecipientsResponse {
+        recipients,
+    }))
+}
+
+/// Add a passphrase or registered public key to an encrypted file
+///
+/// Only the key slots in the header are rewritten; the payload is copied as is.
+#[allow(clippy::too_many_arguments)]
+pub async fn add_recipient(
+    user: AuthUser,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    req: web::Json<AddRecipientRequest>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_store: web::Data<dyn KeyStore>,
+    throttle: web::Data<DecryptThrottle>,
+    rewrite_locks: web::Data<RewriteLocks>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Encrypt)?;
+    
+    let file_info = encrypted_file(file_store.get_ref(), &user, &path.into_inner())?;
+    let request = req.into_inner();
+    
+    // Work out who is being added
+    let recipient = new_recipient(request.new_passphrase, request.key_id, key_store.get_ref())?;
+    
+    let identity = current_identity(request.passphrase, request.private_key_pem)?;
+    
+    // Turn away attempts that come too soon after earlier failures
+    let client = client_id(&http_req);
+    let attempt = throttle.check(&file_info.id, &client)?;
+    
+    // Only one rewrite of a file may run at a time
+    let _guard = rewrite_locks.lock(&file_info.storage_key)?;
+    
+    // Unwrapping the data key runs Argon2 or RSA, so it is kept off the async workers
+    let storage_key = file_info.storage_key.clone();
+    let result = web::block(move || {
+        rewrite_blob(blob_store.get_ref(), &storage_key, |reader, writer| {
+            add_recipient_stream(reader, writer, &identity, &recipient)
+        })
+    })
+        .await
+        .map_err(|e| {
+            error!("Key slot task failed: {}", e);
+            error::ErrorInternalServerError("Error updating key slots")
+        })?;
+    let size = count_attempt(result, attempt)?;
+    
+    info!("Recipient added to file: {}", file_info.id);
+    
+    Ok(HttpResponse::Ok().json(FileResponse {
+        success: true,
+        message: "Recipient added successfully".to_string(),
+        file: Some(refresh_file_info(file_info, size, file_store.get_ref())?),
+    }))
+}
+
+/// Remove a key slot from an encrypted file
+#[allow(clippy::too_many_arguments)]
+pub async fn remove_recipient(
+    user: AuthUser,
+    http_req: HttpRequest,
+    path: web::Path<(String, usize)>,
+    req: web::Json<RemoveRecipientRequest>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
+    throttle: web::Data<DecryptThrottle>,
+    rewrite_locks: web::Data<RewriteLocks>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Encrypt)?;
+    
+    let (file_id, index) = path.into_inner();
+    let file_info = encrypted_file(file_store.get_ref(), &user, &file_id)?;
+    let request = req.into_inner();
+    
+    let identity = current_identity(request.passphrase, request.private_key_pem)?;
+    
+    // Turn away attempts that come too soon after earlier failures
+    let client = client_id(&http_req);
+    let attempt = throttle.check(&file_info.id, &client)?;
+    
+    // Only one rewrite of a file may run at a time
+    let _guard = rewrite_locks.lock(&file_info.storage_key)?;
+    
+    // Unwrapping the data key runs Argon2 or RSA, so it is kept off the async workers
+    let storage_key = file_info.storage_key.clone();
+    let result = web::block(move || {
+        rewrite_blob(blob_store.get_ref(), &storage_key, |reader, writer| {
+            remove_key_slot_stream(reader, writer, &identity, index)
+        })
+    })
+        .await
+        .map_err(|e| {
+            error!("Key slot task failed: {}", e);
+            error::ErrorInternalServerError("Error updating key slots")
+        })?;
+    let size = count_attempt(result, attempt)?;
+    
+    info!("Recipient {} removed from file: {}", index, file_info.id);
+    
+    Ok(HttpResponse::Ok().json(FileResponse {
+        success: true,
+        message: "Recipient removed successfully".to_string(),
+        file: Some(refresh_file_info(file_info, size, file_store.get_ref())?),
+    }))
+}
+
+/// Replace the passphrase or key that opens an encrypted file
+///
+/// The file keeps its ID. Only the matching key slot is rewrapped, except for
+/// older single-key files, which are re-encrypted server-side without the
+/// plaintext touching the disk.
+#[allow(clippy::too_many_arguments)]
+pub async fn rekey(
+    user: AuthUser,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    req: web::Json<RekeyRequest>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_store: web::Data<dyn KeyStore>,
+    throttle: web::Data<DecryptThrottle>,
+    rewrite_locks: web::Data<RewriteLocks>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Encrypt)?;
+    
+    let file_info = encrypted_file(file_store.get_ref(), &user, &path.into_inner())?;
+    let request = req.into_inner();
+    
+    let new = new_recipient(request.new_passphrase, request.key_id, key_store.get_ref())?;
+    let old = current_identity(request.passphrase, request.private_key_pem)?;
+    
+    // Turn away attempts that come too soon after earlier failures
+    let client = client_id(&http_req);
+    let attempt = throttle.check(&file_info.id, &client)?;
+    
+    // Only one rewrite of a file may run at a time
+    let _guard = rewrite_locks.lock(&file_info.storage_key)?;
+    
+    // Legacy files are re-encrypted, which can take a while for large files
+    let storage_key = file_info.storage_key.clone();
+    let result = web::block(move || {
+        rewrite_blob(blob_store.get_ref(), &storage_key, |reader, writer| {
+            rekey_stream(reader, writer, &old, &new)
+        })
+    })
+        .await
+        .map_err(|e| {
+            error!("Rekey task failed: {}", e);
+            error::ErrorInternalServerError("Error updating key slots")
+        })?;
+    let size = count_attempt(result, attempt)?;
+    
+    info!("File rekeyed: {}", file_info.id);
+    
+    Ok(HttpResponse::Ok().json(FileResponse {
+        success: true,
+        message: "File rekeyed successfully".to_string(),
+        file: Some(refresh_file_info(file_info, size, file_store.get_ref())?),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use serde_json::{json, Value};
+    use crate::encryption::{decrypt_stream, encrypt_stream, export_private_key, export_public_key, generate_rsa_keypair, CipherAlgorithm};
+    use crate::handlers::testing::TestApp;
+    use crate::utils::encrypted_key;
+    
+    /// Stores a file of `owner` encrypted with `passphrase`
+    fn add_encrypted_file(app: &TestApp, owner: &User, passphrase: &str) -> FileInfo {
+        let mut encrypted = Vec::new();
+        encrypt_stream(&mut &b"contents"[..], &mut encrypted, &[Recipient::Passphrase(passphrase.to_string())], CipherAlgorithm::Aes256Gcm, None).unwrap();
+        let storage_key = encrypted_key("a.txt");
+        app.blob_store.put(&storage_key, &mut &encrypted[..], encrypted.len() as u64).unwrap();
+        
+        let mut file_info = FileInfo::new("a.txt".to_string(), encrypted.len() as u64, None, storage_key);
+        file_info.encrypted = true;
+        file_info.owner = Some(owner.id.clone());
+        app.store.add_file(file_info.clone()).unwrap();
+        file_info
+    }
+    
+    fn decrypt_blob(app: &TestApp, file_info: &FileInfo, identity: &Identity) -> Result<Vec<u8>, EncryptionError> {
+        let mut plaintext = Vec::new();
+        decrypt_stream(&mut app.blob_store.get(&file_info.storage_key).unwrap(), &mut plaintext, identity)?;
+        Ok(plaintext)
+    }
+    
+    #[actix_web::test]
+    async fn passphrase_and_registered_key_slots_round_trip() {
+        let app = TestApp::new();
+        let (user, cookie) = app.sign_in("alice");
+        let file_info = add_encrypted_file(&app, &user, "pw");
+        let (private_key, public_key) = generate_rsa_keypair().unwrap();
+        let private_key_pem = export_private_key(&private_key).unwrap();
+        
+        let response = app.call(TestRequest::post()
+            .uri("/api/keys")
+            .cookie(cookie.clone())
+            .set_json(json!({ "name": "laptop", "public_key_pem": export_public_key(&public_key).unwrap() })))
+            .await;
+        let key: Value = test::read_body_json(response).await;
+        let key_id = key["key"]["id"].as_str().unwrap().to_string();
+        
+        // Add the key, proving access with the passphrase
+        let response = app.call(TestRequest::post()
+            .uri(&format!("/api/files/{}/recipients", file_info.id))
+            .cookie(cookie.clone())
+            .set_json(json!({ "passphrase": "pw", "key_id": key_id })))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        
+        let response = app.call(TestRequest::get()
+            .uri(&format!("/api/files/{}/recipients", file_info.id))
+            .cookie(cookie.clone()))
+            .await;
+        let listed: Value = test::read_body_json(response).await;
+        assert_eq!(listed["recipients"][0]["kind"], "passphrase");
+        assert_eq!(listed["recipients"][1]["kind"], "rsa");
+        assert_eq!(listed["recipients"][1]["key_id"], key_id.as_str());
+        
+        let rsa = Identity::RsaPrivateKey(Box::new(private_key));
+        assert_eq!(decrypt_blob(&app, &file_info, &Identity::Passphrase("pw".to_string())).unwrap(), b"contents");
+        assert_eq!(decrypt_blob(&app, &file_info, &rsa).unwrap(), b"contents");
+        
+        // Remove the passphrase with the key, then refuse to remove the key itself
+        let remove = |index: usize| TestRequest::delete()
+            .uri(&format!("/api/files/{}/recipients/{}", file_info.id, index))
+            .cookie(cookie.clone())
+            .set_json(json!({ "private_key_pem": private_key_pem }));
+        assert_eq!(app.call(remove(0)).await.status(), StatusCode::OK);
+        let response = app.call(remove(0)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(test::read_body(response).await, "Cannot remove the only key slot");
+        
+        assert_eq!(decrypt_blob(&app, &file_info, &rsa).unwrap(), b"contents");
+        assert!(decrypt_blob(&app, &file_info, &Identity::Passphrase("pw".to_string())).is_err());
+        let size = app.blob_store.stat(&file_info.storage_key).unwrap().unwrap().size;
+        assert_eq!(app.store.get_file(&file_info.id).unwrap().unwrap().size, size);
+    }
+    
+    #[actix_web::test]
+    async fn concurrent_rewrites_conflict() {
+        let app = TestApp::new();
+        let (user, cookie) = app.sign_in("alice");
+        let file_info = add_encrypted_file(&app, &user, "pw");
+        let add = || TestRequest::post()
+            .uri(&format!("/api/files/{}/recipients", file_info.id))
+            .cookie(cookie.clone())
+            .set_json(json!({ "passphrase": "pw", "new_passphrase": "second" }));
+        
+        // Another rewrite of the same file is running
+        let guard = app.rewrite_locks.lock(&file_info.storage_key).unwrap();
+        assert_eq!(app.call(add()).await.status(), StatusCode::CONFLICT);
+        let rekey = TestRequest::post()
+            .uri(&format!("/api/files/{}/rekey", file_info.id))
+            .cookie(cookie.clone())
+            .set_json(json!({ "passphrase": "pw", "new_passphrase": "other" }));
+        assert_eq!(app.call(rekey).await.status(), StatusCode::CONFLICT);
+        
+        // Nothing was changed, and the file can be rewritten once the other one is done
+        assert_eq!(Header::read_from(&mut app.blob_store.get(&file_info.storage_key).unwrap()).unwrap().0.key_slots.len(), 1);
+        drop(guard);
+        assert_eq!(app.call(add()).await.status(), StatusCode::OK);
+        assert_eq!(decrypt_blob(&app, &file_info, &Identity::Passphrase("second".to_string())).unwrap(), b"contents");
+    }
+}
@@ -0,0 +1,311 @@
+use actix_web::{web, HttpRequest, HttpResponse, Error, error, Result};
+use log::{info, warn};
+
+use crate::blob::BlobStore;
+use crate::handlers::auth::AuthUser;
+use crate::handlers::files::{decrypted_download, owned_file, requested_expiry, stored_download, stored_file};
+use crate::models::{Share, ShareInfo, ShareResponse, ListSharesResponse, CreateShareRequest, OpenShareRequest, TokenScope};
+use crate::store::{FileStore, ShareStore};
+use crate::throttle::{client_id, DecryptThrottle};
+use crate::utils::{generate_token, hash_token};
+use crate::encryption::Identity;
+use crate::encryption::kdf::{hash_password, verify_password};
+
+/// Looks up a share that can still be opened
+fn open_share_by_token(share_store: &dyn ShareStore, token: &str) -> Result<Share, Error> {
+    match share_store.find_share(&hash_token(token))? {
+        Some(share) if share.is_expired() => Err(error::ErrorGone("Share has expired")),
+        Some(share) => Ok(share),
+        None => Err(error::ErrorNotFound("Share not found")),
+    }
+}
+
+/// Create a share link for a file
+///
+/// The token is only returned here; the server keeps nothing but its hash.
+pub async fn create_share(
+    user: AuthUser,
+    path: web::Path<String>,
+    req: web::Json<CreateShareRequest>,
+    file_store: web::Data<dyn FileStore>,
+    share_store: web::Data<dyn ShareStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Share)?;
+    
+    let file_id = path.into_inner();
+    let request = req.into_inner();
+    
+    // Get the file to share
+    let file_info = owned_file(file_store.get_ref(), &user, &file_id)?;
+    
+    // Work out when the link expires
+    let expires_at = requested_expiry(request.expires_at, request.expires_in)?;
+    
+    // Hash the extra password, if one was given, on a blocking thread
+    let password_hash = match request.password {
+        Some(password) if password.is_empty() => return Err(error::ErrorBadRequest("Password must not be empty")),
+        Some(password) => Some(web::block(move || hash_password(&password))
+            .await
+            .map_err(error::ErrorInternalServerError)?
+            .map_err(|e| {
+                warn!("Error hashing share password: {:?}", e);
+                error::ErrorInternalServerError("Error creating share")
+            })?),
+        None => None,
+    };
+    
+    // Store the share under the hash of a fresh token
+    let (token, token_hash) = generate_token();
+    let share = Share::new(file_info.id.clone(), token_hash, password_hash, expires_at);
+    share_store.add_share(share.clone())?;
+    
+    info!("Share {} created for file {}", share.id, file_info.id);
+    
+    Ok(HttpResponse::Created().json(ShareResponse {
+        success: true,
+        message: "Share created successfully".to_string(),
+        share: Some(share.into()),
+        url: Some(format!("/s/{}", token)),
+    }))
+}
+
+/// List the share links of a file
+pub async fn list_shares(
+    user: AuthUser,
+    path: web::Path<String>,
+    file_store: web::Data<dyn FileStore>,
+    share_store: web::Data<dyn ShareStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Read)?;
+    
+    let file_id = path.into_inner();
+    
+    // Make sure the file exists and is the user's
+    if !file_store.get_file(&file_id)?.is_some_and(|file| file.is_owned_by(&user.id)) {
+        return Err(error::ErrorNotFound("File not found"));
+    }
+    
+    let shares = share_store.list_shares(&file_id)?
+        .into_iter()
+        .map(ShareInfo::from)
+        .collect();
+    
+    Ok(HttpResponse::Ok().json(ListSharesResponse {
+        shares,
+    }))
+}
+
+/// Revoke a share link
+pub async fn revoke_share(
+    user: AuthUser,
+    path: web::Path<(String, String)>,
+    file_store: web::Data<dyn FileStore>,
+    share_store: web::Data<dyn ShareStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Delete)?;
+    
+    let (file_id, share_id) = path.into_inner();
+    
+    // Make sure the file exists and is the user's
+    if !file_store.get_file(&file_id)?.is_some_and(|file| file.is_owned_by(&user.id)) {
+        return Err(error::ErrorNotFound("File not found"));
+    }
+    
+    if !share_store.remove_share(&file_id, &share_id)? {
+        return Err(error::ErrorNotFound("Share not found"));
+    }
+    
+    info!("Share {} revoked for file {}", share_id, file_id);
+    
+    Ok(HttpResponse::Ok().json(ShareResponse {
+        success: true,
+        message: "Share revoked successfully".to_string(),
+        share: None,
+        url: None,
+    }))
+}
+
+/// Download a shared file as stored
+///
+/// Links with an extra password have to be opened with a POST instead.
+pub async fn open_share(
+    req: HttpRequest,
+    path: web::Path<String>,
+    file_store: web::Data<dyn FileStore>,
+    share_store: web::Data<dyn ShareStore>,
+    blob_store: web::Data<dyn BlobStore>,
+) -> Result<HttpResponse, Error> {
+    let token = path.into_inner();
+    
+    // Get the share and the file behind it
+    let share = open_share_by_token(share_store.get_ref(), &token)?;
+    if share.has_password() {
+        return Err(error::ErrorUnauthorized("This link requires a password"));
+    }
+    let file_info = stored_file(file_store.get_ref(), &share.file_id)?;
+    
+    stored_download(&req, file_info, file_store.get_ref(), blob_store).await
+}
+
+/// Download a shared file with its password, decrypting it if a passphrase is given
+pub async fn open_share_with(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Either<web::Json<OpenShareRequest>, web::Form<OpenShareRequest>>,
+    file_store: web::Data<dyn FileStore>,
+    share_store: web::Data<dyn ShareStore>,
+    blob_store: web::Data<dyn BlobStore>,
+    throttle: web::Data<DecryptThrottle>,
+) -> Result<HttpResponse, Error> {
+    let token = path.into_inner();
+    let client = client_id(&req);
+    
+    // Get the request data from either JSON or form data
+    let request = match body {
+        web::Either::Left(json) => json.into_inner(),
+        web::Either::Right(form) => form.into_inner(),
+    };
+    
+    // Get the share and check its password; guesses count against the shared file
+    let share = open_share_by_token(share_store.get_ref(), &token)?;
+    if let Some(password_hash) = &share.password_hash {
+        let attempt = throttle.check(&share.file_id, &client)?;
+        let password = request.password.clone().unwrap_or_default();
+        let password_hash = password_hash.clone();
+        let verified = web::block(move || verify_password(&password, &password_hash))
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        if !verified {
+            warn!("Wrong password for share {}", share.id);
+            attempt.record_failure();
+            return Err(error::ErrorUnauthorized("Wrong password"));
+        }
+        
+        // The right password only ends the attempt; the file's failures stand
+        drop(attempt);
+    }
+    
+    // Get the shared file
+    let file_info = stored_file(file_store.get_ref(), &share.file_id)?;
+    
+    match request.passphrase {
+        Some(passphrase) => decrypted_download(
+            &file_info,
+            Identity::Passphrase(passphrase),
+            file_store.get_ref(),
+            &blob_store,
+            throttle.get_ref(),
+            &client,
+        ).await,
+        None => stored_download(&req, file_info, file_store.get_ref(), blob_store).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use chrono::{Duration, Utc};
+    use serde_json::{json, Value};
+    use crate::handlers::testing::TestApp;
+    
+    /// Creates a share of `file_id`, returning its link
+    async fn create(app: &TestApp, cookie: &actix_web::cookie::Cookie<'static>, file_id: &str, request: Value) -> String {
+        let response = app.call(TestRequest::post()
+            .uri(&format!("/api/files/{}/shares", file_id))
+            .cookie(cookie.clone())
+            .set_json(request))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body: Value = test::read_body_json(response).await;
+        body["url"].as_str().unwrap().to_string()
+    }
+    
+    #[actix_web::test]
+    async fn links_open_until_revoked_or_expired() {
+        let app = TestApp::new();
+        let (user, cookie) = app.sign_in("alice");
+        let file_info = app.add_file(&user, b"shared contents");
+        let url = create(&app, &cookie, &file_info.id, json!({})).await;
+        
+        // Anyone with the link can download, without signing in
+        let response = app.call(TestRequest::get().uri(&url)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(test::read_body(response).await, "shared contents");
+        assert_eq!(app.call(TestRequest::get().uri("/s/unknown")).await.status(), StatusCode::NOT_FOUND);
+        
+        // The token is not handed out again
+        let response = app.call(TestRequest::get()
+            .uri(&format!("/api/files/{}/shares", file_info.id))
+            .cookie(cookie.clone()))
+            .await;
+        let listed: Value = test::read_body_json(response).await;
+        let share = &listed["shares"][0];
+        assert!(share.get("token_hash").is_none() && share.get("password_hash").is_none());
+        
+        let response = app.call(TestRequest::delete()
+            .uri(&format!("/api/files/{}/shares/{}", file_info.id, share["id"].as_str().unwrap()))
+            .cookie(cookie.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(app.call(TestRequest::get().uri(&url)).await.status(), StatusCode::NOT_FOUND);
+        
+        // Expired links are gone rather than missing
+        let (token, token_hash) = generate_token();
+        app.store.add_share(Share::new(file_info.id.clone(), token_hash, None, Some(Utc::now() - Duration::seconds(1)))).unwrap();
+        let response = app.call(TestRequest::get().uri(&format!("/s/{}", token))).await;
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+    
+    #[actix_web::test]
+    async fn wrong_share_passwords_are_throttled() {
+        let app = TestApp::new();
+        let (user, cookie) = app.sign_in("alice");
+        let file_info = app.add_file(&user, b"shared contents");
+        let url = create(&app, &cookie, &file_info.id, json!({ "password": "letmein" })).await;
+        let open_with = |password: &str| TestRequest::post()
+            .uri(&url)
+            .set_json(json!({ "password": password }));
+        
+        assert_eq!(app.call(TestRequest::get().uri(&url)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.call(open_with("wrong")).await.status(), StatusCode::UNAUTHORIZED);
+        
+        // Even the right password waits out the backoff after a wrong one
+        let response = app.call(open_with("letmein")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key("Retry-After"));
+    }
+    
+    #[actix_web::test]
+    async fn right_share_passwords_download() {
+        let app = TestApp::new();
+        let (user, cookie) = app.sign_in("alice");
+        let file_info = app.add_file(&user, b"shared contents");
+        let url = create(&app, &cookie, &file_info.id, json!({ "password": "letmein" })).await;
+        
+        let response = app.call(TestRequest::post().uri(&url).set_json(json!({ "password": "letmein" }))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(test::read_body(response).await, "shared contents");
+    }
+    
+    #[actix_web::test]
+    async fn shares_of_other_users_files_are_not_found() {
+        let app = TestApp::new();
+        let (alice, alice_cookie) = app.sign_in("alice");
+        let (_, bob) = app.sign_in("bob");
+        let file_info = app.add_file(&alice, b"contents");
+        create(&app, &alice_cookie, &file_info.id, json!({})).await;
+        let shares = format!("/api/files/{}/shares", file_info.id);
+        
+        let response = app.call(TestRequest::post().uri(&shares).cookie(bob.clone()).set_json(json!({}))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app.call(TestRequest::get().uri(&shares).cookie(bob.clone())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app.call(TestRequest::delete().uri(&format!("{}/anything", shares)).cookie(bob)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        
+        let response = app.call(TestRequest::post().uri(&shares).set_json(json!({}))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,98 @@
+//! The API over in-memory stores and a scratch directory, for handler tests.
+
+use actix_web::{dev::ServiceResponse, test, web, App};
+use actix_web::cookie::Cookie;
+use chrono::Duration;
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::auth::SESSION_COOKIE;
+use super::recipients::RewriteLocks;
+use super::tus::TusUploads;
+use crate::blob::{BlobStore, LocalBlobStore, TrackedBlobStore};
+use crate::models::{FileInfo, Session, User};
+use crate::store::{FileStore, KeyStore, MemoryFileStore, ShareStore, UserStore};
+use crate::throttle::{DecryptThrottle, ThrottleConfig};
+use crate::utils::{generate_token, upload_key};
+
+/// App data shared by every request of a test, removed from disk when dropped
+pub struct TestApp {
+    pub root: PathBuf,
+    pub store: Arc<MemoryFileStore>,
+    pub blob_store: Arc<dyn BlobStore>,
+    pub tus_uploads: web::Data<TusUploads>,
+    pub decrypt_throttle: web::Data<DecryptThrottle>,
+    pub rewrite_locks: web::Data<RewriteLocks>,
+}
+
+impl TestApp {
+    pub fn new() -> Self {
+        // Handlers stage work in ./data/tmp, which the server creates at startup
+        std::fs::create_dir_all("./data/tmp").unwrap();
+        
+        let root = std::env::temp_dir().join(format!("silentlock-app-{}", Uuid::new_v4()));
+        let blob_store: Arc<dyn BlobStore> = Arc::new(LocalBlobStore::new(root.join("blobs")));
+        Self {
+            tus_uploads: web::Data::new(TusUploads::new(root.join("tus"))),
+            store: Arc::new(MemoryFileStore::new()),
+            blob_store: Arc::new(TrackedBlobStore::new(blob_store)),
+            decrypt_throttle: web::Data::new(DecryptThrottle::new(ThrottleConfig::default())),
+            rewrite_locks: web::Data::new(RewriteLocks::new()),
+            root,
+        }
+    }
+    
+    /// Creates a user with a session, returning the user and its session cookie
+    pub fn sign_in(&self, username: &str) -> (User, Cookie<'static>) {
+        let user = User::new(username.to_string(), "unused".to_string());
+        self.store.add_user(user.clone()).unwrap();
+        
+        let (token, token_hash) = generate_token();
+        self.store.add_session(Session::new(token_hash, user.id.clone(), Duration::days(1))).unwrap();
+        (user, Cookie::new(SESSION_COOKIE, token))
+    }
+    
+    /// Stores an unencrypted file of `owner`
+    pub fn add_file(&self, owner: &User, contents: &[u8]) -> FileInfo {
+        let storage_key = upload_key("a.txt");
+        self.blob_store.put(&storage_key, &mut &contents[..], contents.len() as u64).unwrap();
+        
+        let mut file_info = FileInfo::new("a.txt".to_string(), contents.len() as u64, None, storage_key);
+        file_info.owner = Some(owner.id.clone());
+        self.store.add_file(file_info.clone()).unwrap();
+        file_info
+    }
+    
+    /// Sends a request through a fresh service over this app's data
+    pub async fn call(&self, req: test::TestRequest) -> ServiceResponse {
+        let file_store: Arc<dyn FileStore> = self.store.clone();
+        let share_store: Arc<dyn ShareStore> = self.store.clone();
+        let user_store: Arc<dyn UserStore> = self.store.clone();
+        let key_store: Arc<dyn KeyStore> = self.store.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(file_store))
+                .app_data(web::Data::from(share_store))
+                .app_data(web::Data::from(user_store))
+                .app_data(web::Data::from(key_store))
+                .app_data(web::Data::from(self.blob_store.clone()))
+                .app_data(self.tus_uploads.clone())
+                .app_data(self.decrypt_throttle.clone())
+                .app_data(self.rewrite_locks.clone())
+                .configure(super::configure),
+        ).await;
+        
+        // Errors from middleware reach the client as their error response
+        match test::try_call_service(&app, req.to_request()).await {
+            Ok(response) => response.map_into_boxed_body(),
+            Err(e) => ServiceResponse::new(test::TestRequest::default().to_http_request(), e.error_response()),
+        }
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
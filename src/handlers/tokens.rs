@@ -0,0 +1,202 @@
+use actix_web::{web, HttpResponse, Error, error, Result};
+use log::info;
+
+use crate::handlers::auth::AuthUser;
+use crate::handlers::files::requested_expiry;
+use crate::models::{ApiToken, CreateTokenRequest, TokenResponse, ListTokensResponse};
+use crate::store::UserStore;
+use crate::utils::generate_token;
+
+/// Longest name accepted for a token
+const MAX_TOKEN_NAME_LEN: usize = 100;
+
+/// Create an API token for the signed-in user
+///
+/// The token is only returned here; the server keeps nothing but its hash.
+pub async fn create_token(
+    user: AuthUser,
+    req: web::Json<CreateTokenRequest>,
+    user_store: web::Data<dyn UserStore>,
+) -> Result<HttpResponse, Error> {
+    user.require_session()?;
+    
+    let request = req.into_inner();
+    
+    // Check what the token is for
+    let name = request.name.trim().to_string();
+    if name.is_empty() || name.len() > MAX_TOKEN_NAME_LEN {
+        return Err(error::ErrorBadRequest("Token name must be between 1 and 100 characters"));
+    }
+    let mut scopes = request.scopes;
+    scopes.sort();
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Err(error::ErrorBadRequest("At least one scope is required"));
+    }
+    
+    // Work out when the token expires
+    let expires_at = requested_expiry(request.expires_at, request.expires_in)?;
+    
+    // Store the token under the hash of a fresh secret
+    let (secret, token_hash) = generate_token();
+    let token = ApiToken::new(user.id.clone(), name, token_hash, scopes, expires_at);
+    user_store.add_api_token(token.clone())?;
+    
+    info!("API token {} created for user {}", token.id, user.id);
+    
+    Ok(HttpResponse::Created().json(TokenResponse {
+        success: true,
+        message: "Token created successfully".to_string(),
+        token: Some(token),
+        secret: Some(secret),
+    }))
+}
+
+/// List the API tokens of the signed-in user
+pub async fn list_tokens(
+    user: AuthUser,
+    user_store: web::Data<dyn UserStore>,
+) -> Result<HttpResponse, Error> {
+    user.require_session()?;
+    
+    let tokens = user_store.list_api_tokens(&user.id)?;
+    
+    Ok(HttpResponse::Ok().json(ListTokensResponse {
+        tokens,
+    }))
+}
+
+/// Revoke an API token, which stops it working immediately
+pub async fn revoke_token(
+    user: AuthUser,
+    path: web::Path<String>,
+    user_store: web::Data<dyn UserStore>,
+) -> Result<HttpResponse, Error> {
+    user.require_session()?;
+    
+    let token_id = path.into_inner();
+    
+    if !user_store.remove_api_token(&user.id, &token_id)? {
+        return Err(error::ErrorNotFound("Token not found"));
+    }
+    
+    info!("API token {} revoked for user {}", token_id, user.id);
+    
+    Ok(HttpResponse::Ok().json(TokenResponse {
+        success: true,
+        message: "Token revoked successfully".to_string(),
+        token: None,
+        secret: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use chrono::{Duration, Utc};
+    use serde_json::{json, Value};
+    use crate::handlers::testing::TestApp;
+    use crate::models::TokenScope;
+    
+    fn bearer(req: TestRequest, secret: &str) -> TestRequest {
+        req.insert_header((header::AUTHORIZATION, format!("Bearer {}", secret)))
+    }
+    
+    #[actix_web::test]
+    async fn tokens_are_limited_to_their_scopes() {
+        let app = TestApp::new();
+        let (user, cookie) = app.sign_in("alice");
+        let file_info = app.add_file(&user, b"contents");
+        
+        let response = app.call(TestRequest::post()
+            .uri("/api/tokens")
+            .cookie(cookie.clone())
+            .set_json(json!({ "name": "ci", "scopes": ["read", "read"] })))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body: Value = test::read_body_json(response).await;
+        let secret = body["secret"].as_str().unwrap().to_string();
+        assert_eq!(body["token"]["scopes"], json!(["read"]));
+        assert!(body["token"].get("token_hash").is_none());
+        
+        let download = format!("/api/files/download/{}", file_info.id);
+        let response = app.call(bearer(TestRequest::get().uri(&download), &secret)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(test::read_body(response).await, "contents");
+        
+        // Scopes the token was not granted are forbidden
+        let response = app.call(bearer(TestRequest::delete().uri(&format!("/api/files/{}", file_info.id)), &secret)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let shares = format!("/api/files/{}/shares", file_info.id);
+        let response = app.call(bearer(TestRequest::post().uri(&shares).set_json(json!({})), &secret)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        
+        // Tokens cannot manage tokens, whatever their scopes
+        let response = app.call(bearer(TestRequest::get().uri("/api/tokens"), &secret)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app.call(bearer(TestRequest::post().uri("/api/tokens"), &secret)
+            .set_json(json!({ "name": "more", "scopes": ["delete"] })))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(app.store.find_api_token(&crate::utils::hash_token(&secret)).unwrap().is_some());
+        assert_eq!(app.store.list_api_tokens(&user.id).unwrap().len(), 1);
+    }
+    
+    #[actix_web::test]
+    async fn unknown_revoked_and_expired_tokens_are_unauthorized() {
+        let app = TestApp::new();
+        let (user, cookie) = app.sign_in("alice");
+        
+        // A bad token is turned away even alongside a valid session
+        let response = app.call(bearer(TestRequest::get().uri("/api/files/list"), "made up").cookie(cookie.clone())).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        
+        let (secret, token_hash) = generate_token();
+        let expired = ApiToken::new(user.id.clone(), "old".to_string(), token_hash, vec![TokenScope::Read], Some(Utc::now() - Duration::seconds(1)));
+        app.store.add_api_token(expired).unwrap();
+        let response = app.call(bearer(TestRequest::get().uri("/api/files/list"), &secret)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        
+        let (secret, token_hash) = generate_token();
+        let token = ApiToken::new(user.id.clone(), "ci".to_string(), token_hash, vec![TokenScope::Read], None);
+        app.store.add_api_token(token.clone()).unwrap();
+        let response = app.call(bearer(TestRequest::get().uri("/api/files/list"), &secret)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        
+        let response = app.call(TestRequest::delete().uri(&format!("/api/tokens/{}", token.id)).cookie(cookie.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.call(bearer(TestRequest::get().uri("/api/files/list"), &secret)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    
+    #[actix_web::test]
+    async fn tokens_act_only_as_their_user() {
+        let app = TestApp::new();
+        let (alice, _) = app.sign_in("alice");
+        let (bob, bob_cookie) = app.sign_in("bob");
+        let file_info = app.add_file(&alice, b"contents");
+        
+        let (secret, token_hash) = generate_token();
+        let scopes = vec![TokenScope::Read, TokenScope::Delete, TokenScope::Share];
+        let token = ApiToken::new(alice.id.clone(), "ci".to_string(), token_hash, scopes, None);
+        app.store.add_api_token(token.clone()).unwrap();
+        
+        // Bob can neither see nor revoke Alice's token
+        let response = app.call(TestRequest::get().uri("/api/tokens").cookie(bob_cookie.clone())).await;
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["tokens"].as_array().map(Vec::len), Some(0));
+        let response = app.call(TestRequest::delete().uri(&format!("/api/tokens/{}", token.id)).cookie(bob_cookie)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        
+        // A token of Bob's finds none of Alice's files
+        let (bob_secret, token_hash) = generate_token();
+        app.store.add_api_token(ApiToken::new(bob.id.clone(), "ci".to_string(), token_hash, vec![TokenScope::Read, TokenScope::Delete], None)).unwrap();
+        let download = format!("/api/files/download/{}", file_info.id);
+        assert_eq!(app.call(bearer(TestRequest::get().uri(&download), &bob_secret)).await.status(), StatusCode::NOT_FOUND);
+        let response = app.call(bearer(TestRequest::delete().uri(&format!("/api/files/{}", file_info.id)), &bob_secret)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(app.call(bearer(TestRequest::get().uri(&download), &secret)).await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,821 @@
+//! Resumable uploads over the tus 1.0 protocol, with the creation,
+//! termination and expiration extensions.
+//!
+//! An upload is created with `POST`, its data sent with one or more `PATCH`
+//! requests and its progress queried with `HEAD`. The data is collected in a
+//! temporary file; once the last byte arrives the file is moved into the blob
+//! store, encrypted first if a passphrase was given, and registered like any
+//! other upload. Upload state is kept in memory, so unfinished uploads do not
+//! survive a restart.
+//!
+//! The temporary file never holds plaintext: data is encrypted with ChaCha20
+//! as it arrives, under a random key that only lives in the upload's state.
+//! Partial uploads can't be read from disk, and whatever a crash leaves
+//! behind can't be read at all. An upload can only be continued by the user who
+//! created it, and expires `UPLOAD_LIFETIME` after it last received data;
+//! the reaper then deletes its partial data.
+
+use actix_web::{web, HttpRequest, HttpResponse, Error, error, Result, http::{header, StatusCode}};
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use futures::StreamExt;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use log::{info, error, warn};
+use uuid::Uuid;
+
+use crate::models::{FileInfo, TokenScope, UploadEncryptRequest};
+use crate::blob::BlobStore;
+use crate::handlers::auth::AuthUser;
+use crate::handlers::files::{encrypt_to_blob, requested_expiry, requested_max_downloads, EncryptToBlobError};
+use crate::store::FileStore;
+use crate::utils::{sanitize_filename, upload_key, validate_content_type, validate_file_size, MAX_FILE_SIZE};
+use crate::encryption::Recipient;
+
+/// Protocol version spoken by the server
+const TUS_VERSION: &str = "1.0.0";
+
+/// Extensions supported besides the core protocol
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+
+/// How long an unfinished upload is kept after it last received data
+const UPLOAD_LIFETIME: Duration = Duration::hours(24);
+
+/// Content type required on `PATCH` requests
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+
+/// Header carrying the id of the file registered when an upload completes
+const FILE_ID_HEADER: &str = "SilentLock-File-Id";
+
+/// Key the partial data of an upload is encrypted with on disk
+type DataKey = [u8; 32];
+
+/// Keystream for an upload's data, positioned at byte `offset`
+///
+/// Every upload has its own key, so the fixed nonce never repeats a keystream.
+fn data_cipher(key: &DataKey, offset: u64) -> ChaCha20 {
+    let mut cipher = ChaCha20::new(key.into(), &[0u8; 12].into());
+    cipher.seek(offset);
+    cipher
+}
+
+/// Reads the data file of an upload back as plaintext
+struct DataReader {
+    file: File,
+    cipher: ChaCha20,
+}
+
+impl DataReader {
+    fn open(path: &Path, key: &DataKey) -> io::Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+            cipher: data_cipher(key, 0),
+        })
+    }
+}
+
+impl Read for DataReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read(buf)?;
+        self.cipher.apply_keystream(&mut buf[..n]);
+        Ok(n)
+    }
+}
+
+/// An upload in progress
+struct TusUpload {
+    /// Total length announced at creation
+    length: u64,
+    
+    /// Number of bytes received so far
+    offset: u64,
+    
+    /// Original filename
+    filename: String,
+    
+    /// Content type of the file, if given
+    content_type: Option<String>,
+    
+    /// `Upload-Metadata` as sent at creation, without the passphrase
+    metadata: String,
+    
+    /// How to encrypt the file once complete, if at all
+    encryption: Option<UploadEncryptRequest>,
+    
+    /// Time after which the finished file is deleted, if any
+    expires_at: Option<DateTime<Utc>>,
+    
+    /// Downloads allowed before the finished file is deleted, if limited
+    max_downloads: Option<u32>,
+    
+    /// ID of the user who created the upload and owns the finished file
+    owner: String,
+    
+    /// Key encrypting the data file, which is never written anywhere
+    key: DataKey,
+    
+    /// Whether a `PATCH` is currently writing to this upload
+    busy: bool,
+    
+    /// Time after which the unfinished upload is discarded
+    upload_expires: DateTime<Utc>,
+}
+
+impl TusUpload {
+    /// Whether the upload has gone unfinished for too long
+    fn is_expired(&self) -> bool {
+        self.upload_expires <= Utc::now()
+    }
+}
+
+/// In-memory registry of unfinished tus uploads
+pub struct TusUploads {
+    uploads: Mutex<HashMap<String, TusUpload>>,
+    
+    /// Directory the partial data is collected in
+    dir: PathBuf,
+}
+
+impl TusUploads {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            uploads: Mutex::new(HashMap::new()),
+            dir: dir.into(),
+        }
+    }
+    
+    fn data_path(&self, upload_id: &str) -> PathBuf {
+        self.dir.join(upload_id)
+    }
+    
+    /// Looks up an upload of `owner` that can still be continued
+    fn usable<'a>(
+        uploads: &'a mut HashMap<String, TusUpload>,
+        upload_id: &str,
+        owner: &str,
+    ) -> Result<&'a mut TusUpload, Error> {
+        match uploads.get_mut(upload_id).filter(|upload| upload.owner == owner) {
+            Some(upload) if upload.is_expired() => Err(tus_error(StatusCode::GONE, "Upload has expired")),
+            Some(upload) => Ok(upload),
+            None => Err(tus_error(StatusCode::NOT_FOUND, "Upload not found")),
+        }
+    }
+    
+    /// Discards expired uploads and their data, returning how many were removed
+    ///
+    /// Data files without an upload, left over from before a restart, are
+    /// deleted once they are as old as an expired upload would be.
+    pub fn remove_expired(&self) -> usize {
+        let expired = {
+            let mut uploads = self.uploads.lock().unwrap();
+            let expired: Vec<String> = uploads.iter()
+                .filter(|(_, upload)| upload.is_expired() && !upload.busy)
+                .map(|(upload_id, _)| upload_id.clone())
+                .collect();
+            for upload_id in &expired {
+                uploads.remove(upload_id);
+            }
+            expired
+        };
+        for upload_id in &expired {
+            if let Err(e) = fs::remove_file(self.data_path(upload_id)) {
+                warn!("Failed to delete data of expired upload {}: {}", upload_id, e);
+            }
+        }
+        
+        // Sweep out data files that no upload refers to any more
+        let cutoff = SystemTime::now() - UPLOAD_LIFETIME.to_std().unwrap_or_default();
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return expired.len(),
+        };
+        let mut orphaned = 0;
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let stale = entry.metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified < cutoff);
+            if stale && !self.uploads.lock().unwrap().contains_key(&name) {
+                match fs::remove_file(entry.path()) {
+                    Ok(()) => orphaned += 1,
+                    Err(e) => warn!("Failed to delete orphaned upload data {}: {}", name, e),
+                }
+            }
+        }
+        
+        expired.len() + orphaned
+    }
+}
+
+/// `Upload-Expires` header announcing when an unfinished upload is discarded
+fn expires_header(upload: &TusUpload) -> (&'static str, String) {
+    ("Upload-Expires", header::HttpDate::from(SystemTime::from(upload.upload_expires)).to_string())
+}
+
+/// Builds an error response carrying the `Tus-Resumable` header
+fn tus_error(status: StatusCode, message: &'static str) -> Error {
+    let response = HttpResponse::build(status)
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .body(message);
+    error::InternalError::from_response(message, response).into()
+}
+
+/// Rejects requests that don't speak our protocol version
+fn check_version(req: &HttpRequest) -> Result<(), Error> {
+    match req.headers().get("Tus-Resumable").and_then(|v| v.to_str().ok()) {
+        Some(TUS_VERSION) => Ok(()),
+        _ => {
+            let response = HttpResponse::PreconditionFailed()
+                .insert_header(("Tus-Resumable", TUS_VERSION))
+                .insert_header(("Tus-Version", TUS_VERSION))
+                .body("Unsupported tus version");
+            Err(error::InternalError::from_response("Unsupported tus version", response).into())
+        }
+    }
+}
+
+/// Reads a numeric header such as `Upload-Length`
+fn u64_header(req: &HttpRequest, name: &str) -> Option<u64> {
+    req.headers().get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// Parses `Upload-Metadata`: comma-separated keys, each with an optional base64 value
+fn parse_metadata(value: &str) -> Option<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, ' ');
+        let key = parts.next()?;
+        let value = match parts.next() {
+            Some(encoded) => String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?,
+            None => String::new(),
+        };
+        metadata.insert(key.to_string(), value);
+    }
+    Some(metadata)
+}
+
+/// Describe the server's tus support
+pub async fn options() -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", MAX_FILE_SIZE.to_string()))
+        .finish()
+}
+
+/// Create a new upload
+///
+/// `Upload-Metadata` must include `filename` and may include `filetype`. A
+/// `passphrase`, with optional `format` and `algorithm`, encrypts the file
+/// once the upload completes. `expires_at` or `expires_in` sets its expiry and
+/// `max_downloads` its download limit.
+pub async fn create_upload(
+    user: AuthUser,
+    req: HttpRequest,
+    tus_uploads: web::Data<TusUploads>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Upload)?;
+    
+    check_version(&req)?;
+    
+    // Get the upload length; deferred lengths are not supported
+    let length = u64_header(&req, "Upload-Length")
+        .ok_or_else(|| tus_error(StatusCode::BAD_REQUEST, "Missing or invalid Upload-Length"))?;
+    if !validate_file_size(length) {
+        return Err(tus_error(StatusCode::PAYLOAD_TOO_LARGE, "File too large"));
+    }
+    
+    // Parse the metadata
+    let raw_metadata = req.headers()
+        .get("Upload-Metadata")
+        .map(|v| v.to_str().unwrap_or_default())
+        .unwrap_or_default();
+    let metadata = parse_metadata(raw_metadata)
+        .ok_or_else(|| tus_error(StatusCode::BAD_REQUEST, "Invalid Upload-Metadata"))?;
+    
+    // Get the filename, accepting the key used by common clients too
+    let filename = metadata.get("filename")
+        .or_else(|| metadata.get("name"))
+        .filter(|name| !name.is_empty())
+        .map(|name| sanitize_filename(name))
+        .ok_or_else(|| tus_error(StatusCode::BAD_REQUEST, "No filename provided"))?;
+    
+    // Validate content type if available
+    let content_type = metadata.get("filetype")
+        .or_else(|| metadata.get("type"))
+        .filter(|ct| !ct.is_empty())
+        .cloned();
+    if let Some(ct) = &content_type {
+        if !validate_content_type(ct) {
+            return Err(tus_error(StatusCode::BAD_REQUEST, "Invalid content type"));
+        }
+    }
+    
+    // Read the encryption options the same way as for /upload-encrypt
+    let encryption = match metadata.get("passphrase") {
+        Some(_) => {
+            let options = ["passphrase", "format", "algorithm"].iter()
+                .filter_map(|key| metadata.get(*key).map(|value| (key.to_string(), value.clone().into())))
+                .collect::<serde_json::Map<_, _>>();
+            let encryption: UploadEncryptRequest = serde_json::from_value(options.into())
+                .map_err(|_| tus_error(StatusCode::BAD_REQUEST, "Invalid encryption options"))?;
+            Some(encryption)
+        },
+        None => None,
+    };
+    
+    // Read the expiry, either a timestamp or a number of seconds from now
+    let expires_at = metadata.get("expires_at")
+        .map(|value| DateTime::parse_from_rfc3339(value).map(|value| value.with_timezone(&Utc)))
+        .transpose()
+        .map_err(|_| tus_error(StatusCode::BAD_REQUEST, "Invalid expires_at"))?;
+    let expires_in = metadata.get("expires_in")
+        .map(|value| value.parse())
+        .transpose()
+        .map_err(|_| tus_error(StatusCode::BAD_REQUEST, "Invalid expires_in"))?;
+    let expires_at = requested_expiry(expires_at, expires_in)
+        .map_err(|_| tus_error(StatusCode::BAD_REQUEST, "Invalid expiry"))?;
+    
+    // Read the download limit
+    let max_downloads = metadata.get("max_downloads")
+        .map(|value| value.parse())
+        .transpose()
+        .ok()
+        .and_then(|max_downloads| requested_max_downloads(max_downloads).ok())
+        .ok_or_else(|| tus_error(StatusCode::BAD_REQUEST, "Invalid max_downloads"))?;
+    
+    // Keep the passphrase out of what HEAD echoes back
+    let echoed_metadata = raw_metadata
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty() && pair.split(' ').next() != Some("passphrase"))
+        .collect::<Vec<_>>()
+        .join(",");
+    
+    // Create the file the data is collected in
+    let upload_id = Uuid::new_v4().to_string();
+    fs::create_dir_all(&tus_uploads.dir)
+        .and_then(|_| File::create(tus_uploads.data_path(&upload_id)))
+        .map_err(|e| {
+            error!("Error creating upload file: {}", e);
+            tus_error(StatusCode::INTERNAL_SERVER_ERROR, "Error creating upload")
+        })?;
+    
+    let mut key = DataKey::default();
+    OsRng.fill_bytes(&mut key);
+    
+    let upload = TusUpload {
+        length,
+        offset: 0,
+        filename,
+        content_type,
+        metadata: echoed_metadata,
+        encryption,
+        expires_at,
+        max_downloads,
+        owner: user.id.clone(),
+        key,
+        busy: false,
+        upload_expires: Utc::now() + UPLOAD_LIFETIME,
+    };
+    
+    let location = format!("{}/{}", req.path().trim_end_matches('/'), upload_id);
+    let mut response = HttpResponse::Created();
+    response
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header((header::LOCATION, location));
+    if length > 0 {
+        response.insert_header(expires_header(&upload));
+    }
+    
+    // An empty upload is complete as soon as it is created
+    if length == 0 {
+        let file_info = complete_upload(&tus_uploads, &upload_id, upload, file_store.get_ref(), &blob_store).await?;
+        response.insert_header((FILE_ID_HEADER, file_info.id));
+        return Ok(response.finish());
+    }
+    
+    tus_uploads.uploads.lock().unwrap().insert(upload_id.clone(), upload);
+    info!("Upload created: {}", upload_id);
+    
+    Ok(response.finish())
+}
+
+/// Report how much of an upload has been received
+pub async fn upload_status(
+    user: AuthUser,
+    req: HttpRequest,
+    path: web::Path<String>,
+    tus_uploads: web::Data<TusUploads>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Upload)?;
+    
+    check_version(&req)?;
+    let upload_id = path.into_inner();
+    
+    let mut uploads = tus_uploads.uploads.lock().unwrap();
+    let upload = TusUploads::usable(&mut uploads, &upload_id, &user.id)?;
+    
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Upload-Offset", upload.offset.to_string()))
+        .insert_header(("Upload-Length", upload.length.to_string()))
+        .insert_header(expires_header(upload))
+        .insert_header((header::CACHE_CONTROL, "no-store"));
+    if !upload.metadata.is_empty() {
+        response.insert_header(("Upload-Metadata", upload.metadata.clone()));
+    }
+    Ok(response.finish())
+}
+
+/// Append data to an upload
+///
+/// Data received before a dropped connection is kept, so the client can
+/// resume from the offset reported by `HEAD`.
+pub async fn append_upload(
+    user: AuthUser,
+    req: HttpRequest,
+    path: web::Path<String>,
+    mut payload: web::Payload,
+    tus_uploads: web::Data<TusUploads>,
+    file_store: web::Data<dyn FileStore>,
+    blob_store: web::Data<dyn BlobStore>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Upload)?;
+    
+    check_version(&req)?;
+    let upload_id = path.into_inner();
+    
+    // Check the request
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    if content_type != Some(OFFSET_OCTET_STREAM) {
+        return Err(tus_error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Content-Type must be application/offset+octet-stream"));
+    }
+    let offset = u64_header(&req, "Upload-Offset")
+        .ok_or_else(|| tus_error(StatusCode::BAD_REQUEST, "Missing or invalid Upload-Offset"))?;
+    
+    // Claim the upload, which must be idle and at the offset the client expects
+    let (length, key) = {
+        let mut uploads = tus_uploads.uploads.lock().unwrap();
+        let upload = TusUploads::usable(&mut uploads, &upload_id, &user.id)?;
+        if upload.busy || upload.offset != offset {
+            return Err(tus_error(StatusCode::CONFLICT, "Upload-Offset does not match"));
+        }
+        upload.busy = true;
+        (upload.length, upload.key)
+    };
+    
+    // Append the request body, stopping at the first error
+    let mut received = offset;
+    let appended = async {
+        // Drop anything past the offset left by a write that failed halfway
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(tus_uploads.data_path(&upload_id))
+            .and_then(|mut file| {
+                file.set_len(offset)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(file)
+            })
+            .map_err(|e| {
+                error!("Error opening upload file: {}", e);
+                tus_error(StatusCode::INTERNAL_SERVER_ERROR, "Error saving upload")
+            })?;
+        let mut cipher = data_cipher(&key, offset);
+        
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| {
+                error!("Error reading upload data: {}", e);
+                tus_error(StatusCode::BAD_REQUEST, "Error reading upload data")
+            })?;
+            
+            if received + chunk.len() as u64 > length {
+                return Err(tus_error(StatusCode::BAD_REQUEST, "Data exceeds Upload-Length"));
+            }
+            
+            let mut chunk = chunk.to_vec();
+            cipher.apply_keystream(&mut chunk);
+            file.write_all(&chunk).map_err(|e| {
+                error!("Error writing upload file: {}", e);
+                tus_error(StatusCode::INTERNAL_SERVER_ERROR, "Error saving upload")
+            })?;
+            received += chunk.len() as u64;
+        }
+        Ok(())
+    }.await;
+    
+    // Record progress and release the upload, taking it out once complete;
+    // receiving data gives it a new lease of life
+    let (completed, upload_expires) = {
+        let mut uploads = tus_uploads.uploads.lock().unwrap();
+        let upload_expires = match uploads.get_mut(&upload_id) {
+            Some(upload) => {
+                upload.offset = received;
+                upload.busy = false;
+                upload.upload_expires = Utc::now() + UPLOAD_LIFETIME;
+                expires_header(upload)
+            },
+            None => return Err(tus_error(StatusCode::NOT_FOUND, "Upload not found")),
+        };
+        let completed = if appended.is_ok() && received == length {
+            uploads.remove(&upload_id)
+        } else {
+            None
+        };
+        (completed, upload_expires)
+    };
+    appended?;
+    
+    let mut response = HttpResponse::NoContent();
+    response
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Upload-Offset", received.to_string()));
+    
+    match completed {
+        Some(upload) => {
+            let file_info = complete_upload(&tus_uploads, &upload_id, upload, file_store.get_ref(), &blob_store).await?;
+            response.insert_header((FILE_ID_HEADER, file_info.id));
+        },
+        None => {
+            response.insert_header(upload_expires);
+        },
+    }
+    
+    Ok(response.finish())
+}
+
+/// Abandon an upload and discard its data
+pub async fn delete_upload(
+    user: AuthUser,
+    req: HttpRequest,
+    path: web::Path<String>,
+    tus_uploads: web::Data<TusUploads>,
+) -> Result<HttpResponse, Error> {
+    user.require(TokenScope::Upload)?;
+    
+    check_version(&req)?;
+    let upload_id = path.into_inner();
+    
+    {
+        let mut uploads = tus_uploads.uploads.lock().unwrap();
+        match uploads.get(&upload_id).filter(|upload| upload.owner == user.id) {
+            Some(upload) if upload.busy => return Err(tus_error(StatusCode::CONFLICT, "Upload is in progress")),
+            Some(_) => { uploads.remove(&upload_id); },
+            None => return Err(tus_error(StatusCode::NOT_FOUND, "Upload not found")),
+        }
+    }
+    
+    if let Err(e) = fs::remove_file(tus_uploads.data_path(&upload_id)) {
+        warn!("Failed to delete upload file: {}", e);
+    }
+    
+    info!("Upload deleted: {}", upload_id);
+    
+    Ok(HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .finish())
+}
+
+/// Moves a finished upload into the blob store and registers it
+///
+/// The upload's data file is removed whether or not this succeeds.
+async fn complete_upload(
+    tus_uploads: &TusUploads,
+    upload_id: &str,
+    upload: TusUpload,
+    file_store: &dyn FileStore,
+    blob_store: &web::Data<dyn BlobStore>,
+) -> Result<FileInfo, Error> {
+    // Encrypting and storing can take a while for large files
+    let data_path = tus_uploads.data_path(upload_id);
+    let result = web::block({
+        let data_path = data_path.clone();
+        let blob_store = blob_store.clone();
+        move || store_upload(&data_path, upload, blob_store.get_ref())
+    }).await;
+    
+    if let Err(e) = fs::remove_file(&data_path) {
+        warn!("Failed to delete upload file: {}", e);
+    }
+    
+    let file_info = result.map_err(error::ErrorInternalServerError)??;
+    file_store.add_file(file_info.clone())?;
+    info!("Upload {} completed as file {}", upload_id, file_info.id);
+    Ok(file_info)
+}
+
+/// Stores the data of a finished upload, encrypting it if requested
+///
+/// Returns the record of the stored file, which the caller registers.
+fn store_upload(
+    data_path: &Path,
+    upload: TusUpload,
+    blob_store: &dyn BlobStore,
+) -> Result<FileInfo, EncryptToBlobError> {
+    let file_info = match upload.encryption {
+        Some(encryption) => {
+            // Describe the plaintext, which is not kept
+            let mut original_file_info = FileInfo::new(
+                upload.filename,
+                upload.length,
+                upload.content_type,
+                String::new(),
+            );
+            original_file_info.expires_at = upload.expires_at;
+            original_file_info.downloads_remaining = upload.max_downloads;
+            original_file_info.owner = Some(upload.owner);
+            
+            let mut data = DataReader::open(data_path, &upload.key)
+                .map_err(|e| EncryptToBlobError::Encryption(e.into()))?;
+            let (encrypted_storage_key, encrypted_size) = encrypt_to_blob(
+                &mut data,
+                &[Recipient::Passphrase(encryption.passphrase)],
+                encryption.format,
+                encryption.algorithm,
+                &original_file_info.metadata(),
+                blob_store,
+            )?;
+            
+            FileInfo::new_encrypted(
+                &original_file_info,
+                encrypted_storage_key,
+                encrypted_size,
+                encryption.format,
+            )
+        },
+        None => {
+            let storage_key = upload_key(&upload.filename);
+            let mut data = DataReader::open(data_path, &upload.key)
+                .map_err(|e| EncryptToBlobError::Blob(e.into()))?;
+            blob_store.put(&storage_key, &mut data, upload.length)
+                .map_err(EncryptToBlobError::Blob)?;
+            let mut file_info = FileInfo::new(upload.filename, upload.length, upload.content_type, storage_key);
+            file_info.expires_at = upload.expires_at;
+            file_info.downloads_remaining = upload.max_downloads;
+            file_info.owner = Some(upload.owner);
+            file_info
+        }
+    };
+    
+    Ok(file_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::cookie::Cookie;
+    use actix_web::dev::ServiceResponse;
+    use actix_web::test::TestRequest;
+    use crate::handlers::testing::TestApp;
+    
+    /// Creates an upload of `length` bytes, returning its URL
+    async fn create(app: &TestApp, cookie: &Cookie<'static>, length: u64) -> String {
+        let response = app.call(TestRequest::post()
+            .uri("/api/files/tus")
+            .cookie(cookie.clone())
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .insert_header(("Upload-Length", length.to_string()))
+            .insert_header(("Upload-Metadata", format!("filename {}", BASE64.encode("a.txt")))))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        header_value(&response, "Location")
+    }
+    
+    async fn patch(app: &TestApp, cookie: &Cookie<'static>, location: &str, offset: u64, data: &[u8]) -> ServiceResponse {
+        app.call(TestRequest::patch()
+            .uri(location)
+            .cookie(cookie.clone())
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .insert_header(("Upload-Offset", offset.to_string()))
+            .insert_header((header::CONTENT_TYPE, OFFSET_OCTET_STREAM))
+            .set_payload(data.to_vec()))
+            .await
+    }
+    
+    async fn head(app: &TestApp, cookie: &Cookie<'static>, location: &str) -> ServiceResponse {
+        app.call(TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri(location)
+            .cookie(cookie.clone())
+            .insert_header(("Tus-Resumable", TUS_VERSION)))
+            .await
+    }
+    
+    fn header_value(response: &ServiceResponse, name: &str) -> String {
+        response.headers().get(name).unwrap().to_str().unwrap().to_string()
+    }
+    
+    fn upload_id(location: &str) -> &str {
+        location.rsplit('/').next().unwrap()
+    }
+    
+    #[actix_web::test]
+    async fn patches_at_the_wrong_offset_conflict() {
+        let app = TestApp::new();
+        let (_, cookie) = app.sign_in("alice");
+        let location = create(&app, &cookie, 10).await;
+        
+        let response = patch(&app, &cookie, &location, 0, b"01234").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        for offset in [0, 4, 6] {
+            let response = patch(&app, &cookie, &location, offset, b"56789").await;
+            assert_eq!(response.status(), StatusCode::CONFLICT, "offset {}", offset);
+        }
+        assert_eq!(header_value(&head(&app, &cookie, &location).await, "Upload-Offset"), "5");
+        
+        // Data beyond the announced length is refused without losing what came before
+        let response = patch(&app, &cookie, &location, 5, b"56789X").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(header_value(&head(&app, &cookie, &location).await, "Upload-Offset"), "5");
+    }
+    
+    #[actix_web::test]
+    async fn uploads_resume_and_complete() {
+        let app = TestApp::new();
+        let (user, cookie) = app.sign_in("alice");
+        let location = create(&app, &cookie, 10).await;
+        
+        let response = patch(&app, &cookie, &location, 0, b"01234").await;
+        assert_eq!(header_value(&response, "Upload-Offset"), "5");
+        assert!(response.headers().get(FILE_ID_HEADER).is_none());
+        assert_eq!(header_value(&head(&app, &cookie, &location).await, "Upload-Offset"), "5");
+        
+        let response = patch(&app, &cookie, &location, 5, b"56789").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let file_id = header_value(&response, FILE_ID_HEADER);
+        
+        let file_info = app.store.get_file(&file_id).unwrap().unwrap();
+        assert_eq!((file_info.size, file_info.owner.as_deref()), (10, Some(user.id.as_str())));
+        let mut stored = Vec::new();
+        app.blob_store.get(&file_info.storage_key).unwrap().read_to_end(&mut stored).unwrap();
+        assert_eq!(stored, b"0123456789");
+        
+        // The upload is gone once complete, along with its data
+        assert!(!app.tus_uploads.data_path(upload_id(&location)).exists());
+        assert_eq!(head(&app, &cookie, &location).await.status(), StatusCode::NOT_FOUND);
+    }
+    
+    #[actix_web::test]
+    async fn partial_data_is_encrypted_on_disk() {
+        let app = TestApp::new();
+        let (_, cookie) = app.sign_in("alice");
+        let location = create(&app, &cookie, 64).await;
+        let plaintext = [b'a'; 48];
+        patch(&app, &cookie, &location, 0, &plaintext[..20]).await;
+        patch(&app, &cookie, &location, 20, &plaintext[20..]).await;
+        
+        let data_path = app.tus_uploads.data_path(upload_id(&location));
+        let on_disk = fs::read(&data_path).unwrap();
+        assert_eq!(on_disk.len(), plaintext.len());
+        assert!(!on_disk.windows(8).any(|window| window == &plaintext[..8]));
+        
+        let key = app.tus_uploads.uploads.lock().unwrap()[upload_id(&location)].key;
+        let mut read = Vec::new();
+        DataReader::open(&data_path, &key).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, plaintext);
+    }
+    
+    #[actix_web::test]
+    async fn expired_uploads_are_gone_and_removed() {
+        let app = TestApp::new();
+        let (_, cookie) = app.sign_in("alice");
+        let location = create(&app, &cookie, 10).await;
+        patch(&app, &cookie, &location, 0, b"01234").await;
+        
+        app.tus_uploads.uploads.lock().unwrap()
+            .get_mut(upload_id(&location)).unwrap()
+            .upload_expires = Utc::now() - Duration::seconds(1);
+        assert_eq!(head(&app, &cookie, &location).await.status(), StatusCode::GONE);
+        assert_eq!(patch(&app, &cookie, &location, 5, b"56789").await.status(), StatusCode::GONE);
+        
+        assert_eq!(app.tus_uploads.remove_expired(), 1);
+        assert!(!app.tus_uploads.data_path(upload_id(&location)).exists());
+        assert_eq!(head(&app, &cookie, &location).await.status(), StatusCode::NOT_FOUND);
+    }
+    
+    #[actix_web::test]
+    async fn uploads_belong_to_their_creator() {
+        let app = TestApp::new();
+        let (_, alice) = app.sign_in("alice");
+        let (_, bob) = app.sign_in("bob");
+        let location = create(&app, &alice, 10).await;
+        
+        assert_eq!(head(&app, &bob, &location).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(patch(&app, &bob, &location, 0, b"01234").await.status(), StatusCode::NOT_FOUND);
+        let response = app.call(TestRequest::patch().uri(&location).set_payload("01234")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
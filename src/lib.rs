@@ -0,0 +1,34 @@
+//! SilentLock file encryption and storage.
+//!
+//! The `encryption` module reads and writes SilentLock containers and the
+//! `models` module holds the records the server keeps. Both build without any
+//! server dependencies. With the default `server` feature the crate also
+//! provides the storage traits and backends and the actix handlers that the
+//! `silentlock` binary serves.
+
+pub mod encryption;
+pub mod models;
+
+#[cfg(feature = "server")]
+pub mod blob;
+#[cfg(feature = "server")]
+pub mod handlers;
+#[cfg(feature = "server")]
+pub mod reaper;
+#[cfg(feature = "server")]
+pub mod store;
+#[cfg(feature = "server")]
+pub mod throttle;
+#[cfg(feature = "server")]
+pub mod utils;
+
+pub use encryption::{
+    decrypt_stream, encrypt_stream, CipherAlgorithm, ContainerFormat, EncryptionError, FileMetadata, Identity,
+    Recipient,
+};
+pub use models::FileInfo;
+
+#[cfg(feature = "server")]
+pub use blob::{BlobError, BlobStore};
+#[cfg(feature = "server")]
+pub use store::{FileStore, ShareStore, StoreError, UserStore};
@@ -1,17 +1,22 @@
-use actix_web::{web, App, HttpServer, Responder, HttpResponse};
+use actix_web::{web, App, HttpServer, Responder};
 use actix_files as fs;
 use log::{info, error};
 use std::io;
+use std::path::Path;
+use std::sync::Arc;
 
-mod encryption;
-mod handlers;
-mod models;
-mod utils;
-
-use crate::utils::FileStore;
+use silentlock::blob::{BlobStore, LocalBlobStore, S3BlobStore, S3Config, TrackedBlobStore};
+use silentlock::store::{FileStore, KeyStore, MemoryFileStore, ShareStore, SqliteFileStore, UserStore};
+use silentlock::handlers::{self, recipients::RewriteLocks, tus::TusUploads};
+use silentlock::reaper;
+use silentlock::throttle::{DecryptThrottle, ThrottleConfig};
+use silentlock::utils::discard_file_contents;
 
 use tokio::signal;
 
+/// The record stores, all backed by the same database
+type Stores = (Arc<dyn FileStore>, Arc<dyn ShareStore>, Arc<dyn UserStore>, Arc<dyn KeyStore>);
+
 #[actix_web::main]
 async fn main() -> io::Result<()> {
     // Initialize logger
@@ -22,12 +27,105 @@ async fn main() -> io::Result<()> {
     // Create data directory if it doesn't exist
     std::fs::create_dir_all("./data/uploads")?;
     std::fs::create_dir_all("./data/encrypted")?;
+    std::fs::create_dir_all("./data/tmp")?;
     
     // Create static directory for web UI
     std::fs::create_dir_all("./static")?;
     
-    // Initialize file store
-    let file_store = web::Data::new(FileStore::new());
+    // Initialize file, share, user and key store; SILENTLOCK_DATABASE=memory keeps records in memory only
+    let database = std::env::var("SILENTLOCK_DATABASE")
+        .unwrap_or_else(|_| "./data/silentlock.db".to_string());
+    let ephemeral = database == "memory";
+    let (file_store, share_store, user_store, key_store): Stores = if ephemeral {
+        info!("Using in-memory file store; records and files are discarded on shutdown");
+        let store = Arc::new(MemoryFileStore::new());
+        (store.clone(), store.clone(), store.clone(), store)
+    } else {
+        info!("Using file store at {}", database);
+        let store = Arc::new(SqliteFileStore::open(Path::new(&database)).map_err(|e| {
+            error!("Error opening file store: {}", e);
+            io::Error::other("Failed to open file store")
+        })?);
+        (store.clone(), store.clone(), store.clone(), store)
+    };
+    let file_store = web::Data::from(file_store);
+    let share_store = web::Data::from(share_store);
+    let user_store = web::Data::from(user_store);
+    let key_store = web::Data::from(key_store);
+    
+    // Hand files from before accounts existed to SILENTLOCK_UNOWNED_FILES_OWNER, if set
+    if let Ok(username) = std::env::var("SILENTLOCK_UNOWNED_FILES_OWNER") {
+        let owner = user_store.find_user(&username.trim().to_lowercase())
+            .map_err(|e| {
+                error!("Error looking up the owner for unowned files: {}", e);
+                io::Error::other("Failed to look up the owner for unowned files")
+            })?
+            .ok_or_else(|| {
+                error!("SILENTLOCK_UNOWNED_FILES_OWNER names no user: {}", username);
+                io::Error::other("Unknown owner for unowned files")
+            })?;
+        let adopted = file_store.adopt_unowned_files(&owner.id).map_err(|e| {
+            error!("Error assigning unowned files: {}", e);
+            io::Error::other("Failed to assign unowned files")
+        })?;
+        info!("Gave {} unowned file{} to {}", adopted, if adopted == 1 { "" } else { "s" }, owner.username);
+    }
+    
+    // Initialize blob store; SILENTLOCK_BLOB_STORE=s3 keeps file contents in an S3 bucket
+    let blob_store: Arc<dyn BlobStore> = match std::env::var("SILENTLOCK_BLOB_STORE").as_deref() {
+        Ok("s3") => {
+            let config = S3Config::from_env().map_err(|e| {
+                error!("Invalid S3 configuration: {}", e);
+                io::Error::other("Invalid S3 configuration")
+            })?;
+            info!("Using S3 blob store, bucket {}", config.bucket);
+            Arc::new(S3BlobStore::new(config).map_err(|e| {
+                error!("Error opening S3 blob store: {}", e);
+                io::Error::other("Failed to open S3 blob store")
+            })?)
+        },
+        Ok("local") | Err(_) => {
+            info!("Using local blob store in ./data");
+            Arc::new(LocalBlobStore::new("./data"))
+        },
+        Ok(other) => {
+            error!("Unknown blob store {}", other);
+            return Err(io::Error::other("Unknown blob store"));
+        }
+    };
+    
+    // Hold back deletions of blobs that downloads are still reading
+    let blob_store: Arc<dyn BlobStore> = Arc::new(TrackedBlobStore::new(blob_store));
+    let blob_store = web::Data::from(blob_store);
+    
+    // Initialize registry of resumable uploads
+    let tus_uploads = web::Data::new(TusUploads::new("./data/tmp/tus"));
+    
+    // Delete expired files, sessions and uploads in the background
+    actix_web::rt::spawn(reaper::run(
+        file_store.clone().into_inner(),
+        blob_store.clone().into_inner(),
+        user_store.clone().into_inner(),
+        tus_uploads.clone().into_inner(),
+    ));
+    
+    // Initialize registry of files whose key slots are being rewritten
+    let rewrite_locks = web::Data::new(RewriteLocks::new());
+    
+    // Initialize counters of failed decryption attempts
+    let throttle_config = ThrottleConfig::from_env().map_err(|e| {
+        error!("Invalid decryption throttle configuration: {}", e);
+        io::Error::other("Invalid decryption throttle configuration")
+    })?;
+    info!(
+        "Locking files and clients for {} seconds after {} failed decryption attempts",
+        throttle_config.lockout.as_secs(), throttle_config.max_failures,
+    );
+    let decrypt_throttle = web::Data::new(DecryptThrottle::new(throttle_config));
+    
+    // Keep the stores for cleaning up ephemeral files on shutdown
+    let cleanup_file_store = file_store.clone();
+    let cleanup_blob_store = blob_store.clone();
     
     // Create a task to handle Ctrl+C
     let ctrl_c = async {
@@ -40,22 +138,24 @@ async fn main() -> io::Result<()> {
         App::new()
             // Register the file store
             .app_data(file_store.clone())
+            // Register the share store
+            .app_data(share_store.clone())
+            // Register the user store
+            .app_data(user_store.clone())
+            // Register the blob store
+            .app_data(blob_store.clone())
+            // Register the public key store
+            .app_data(key_store.clone())
+            // Register the resumable upload registry
+            .app_data(tus_uploads.clone())
+            // Register the failed decryption attempt counters
+            .app_data(decrypt_throttle.clone())
+            // Register the key slot rewrite locks
+            .app_data(rewrite_locks.clone())
             // Serve static files from the static directory
             .service(fs::Files::new("/static", "./static").show_files_listing())
-            // API routes
-            .service(
-                web::scope("/api")
-                    .route("/health", web::get().to(health_check))
-                    .service(
-                        web::scope("/files")
-                            .route("/upload", web::post().to(handlers::files::upload_file))
-                            .route("/upload-encrypt", web::post().to(handlers::files::upload_encrypt_file))
-                            .route("/encrypt", web::post().to(handlers::files::encrypt_file))
-                            .route("/decrypt", web::post().to(handlers::files::decrypt_file))
-                            .route("/list", web::get().to(handlers::files::list_files))
-                            .route("/download/{file_id}", web::get().to(handlers::files::download_file))
-                    )
-            )
+            // API routes and public share links
+            .configure(handlers::configure)
             // Serve index.html for all other routes
             .route("/", web::get().to(index))
             .route("/{filename:.*}", web::get().to(index))
@@ -72,24 +172,38 @@ async fn main() -> io::Result<()> {
     // Wait for shutdown signal
     ctrl_c.await;
     
-    // Clean up on shutdown
-    info!("Cleaning up files and data...");
-    
     // Stop the server gracefully
     server_handle.stop(true).await;
     
-    // Clean up data directories
-    if let Err(e) = std::fs::remove_dir_all("./data/uploads") {
-        error!("Error cleaning up uploads directory: {}", e);
-    }
-    if let Err(e) = std::fs::remove_dir_all("./data/encrypted") {
-        error!("Error cleaning up encrypted directory: {}", e);
+    // Files are only kept across restarts when their records are
+    if ephemeral {
+        info!("Cleaning up files and data...");
+        
+        // Delete the contents of every file, which may live in an S3 bucket
+        match cleanup_file_store.list_files() {
+            Ok(files) => {
+                for file_info in files {
+                    if let Err(e) = discard_file_contents(cleanup_blob_store.as_ref(), &file_info) {
+                        error!("Error deleting contents of file {}: {}", file_info.id, e);
+                    }
+                }
+            },
+            Err(e) => error!("Error listing files to clean up: {}", e),
+        }
+        
+        // Clean up data directories
+        if let Err(e) = std::fs::remove_dir_all("./data/uploads") {
+            error!("Error cleaning up uploads directory: {}", e);
+        }
+        if let Err(e) = std::fs::remove_dir_all("./data/encrypted") {
+            error!("Error cleaning up encrypted directory: {}", e);
+        }
+        
+        // Recreate empty directories
+        std::fs::create_dir_all("./data/uploads")?;
+        std::fs::create_dir_all("./data/encrypted")?;
     }
     
-    // Recreate empty directories
-    std::fs::create_dir_all("./data/uploads")?;
-    std::fs::create_dir_all("./data/encrypted")?;
-    
     info!("Shutdown complete");
     
     // Wait for server to stop
@@ -97,19 +211,11 @@ async fn main() -> io::Result<()> {
         Ok(result) => result,
         Err(e) => {
             error!("Server task error: {}", e);
-            Err(io::Error::new(io::ErrorKind::Other, "Server task failed"))
+            Err(io::Error::other("Server task failed"))
         }
     }
 }
 
-// Health check endpoint
-async fn health_check() -> impl Responder {
-    HttpResponse::Ok().json(serde_json::json!({
-        "status": "ok",
-        "version": env!("CARGO_PKG_VERSION")
-    }))
-}
-
 // Serve the index.html file for the frontend
 async fn index() -> impl Responder {
     fs::NamedFile::open_async("./static/index.html").await
@@ -1,6 +1,7 @@
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
-use std::path::PathBuf;
+
+use crate::encryption::{CipherAlgorithm, ContainerFormat, FileMetadata};
 
 /// Represents a file in the system
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -23,14 +24,26 @@ pub struct FileInfo {
     /// Timestamp when the file was uploaded
     pub uploaded_at: chrono::DateTime<chrono::Utc>,
     
-    /// Path to the file on disk (not exposed to clients)
-    #[serde(skip_serializing)]
-    pub path: PathBuf,
+    /// Key of the file contents in the blob store (not exposed to clients)
+    #[serde(skip_serializing, default)]
+    pub storage_key: String,
+    
+    /// Time after which the file is deleted, if any
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    
+    /// Downloads left before the file is deleted, if limited
+    #[serde(default)]
+    pub downloads_remaining: Option<u32>,
+    
+    /// ID of the user who owns the file; files from before accounts have none
+    #[serde(default)]
+    pub owner: Option<String>,
 }
 
 impl FileInfo {
     /// Creates a new FileInfo instance for an uploaded file
-    pub fn new(filename: String, size: u64, content_type: Option<String>, path: PathBuf) -> Self {
+    pub fn new(filename: String, size: u64, content_type: Option<String>, storage_key: String) -> Self {
         Self {
             id: Uuid::new_v4().to_string(),
             filename,
@@ -38,24 +51,85 @@ impl FileInfo {
             content_type,
             encrypted: false,
             uploaded_at: chrono::Utc::now(),
-            path,
+            storage_key,
+            expires_at: None,
+            downloads_remaining: None,
+            owner: None,
         }
     }
     
     /// Creates a new FileInfo instance for an encrypted file
+    ///
+    /// The encrypted file keeps the original's expiry, download limit and owner.
+    ///
+    /// SilentLock containers carry the original name in their encrypted
+    /// metadata, so they are listed under an opaque name. age files cannot,
+    /// and keep the original name with an `.age` suffix.
     pub fn new_encrypted(
         original: &FileInfo,
-        encrypted_path: PathBuf,
+        encrypted_storage_key: String,
         encrypted_size: u64,
+        format: ContainerFormat,
     ) -> Self {
+        let id = Uuid::new_v4().to_string();
+        let filename = match format {
+            ContainerFormat::SilentLock => format!("{}.slck", id),
+            ContainerFormat::Age => format!("{}.age", original.filename),
+        };
+        
         Self {
-            id: Uuid::new_v4().to_string(),
-            filename: format!("{}.encrypted", original.filename),
+            id,
+            filename,
             size: encrypted_size,
             content_type: Some("application/octet-stream".to_string()),
             encrypted: true,
             uploaded_at: chrono::Utc::now(),
-            path: encrypted_path,
+            storage_key: encrypted_storage_key,
+            expires_at: original.expires_at,
+            downloads_remaining: original.downloads_remaining,
+            owner: original.owner.clone(),
+        }
+    }
+    
+    /// Creates a new FileInfo instance for a container encrypted by the client
+    ///
+    /// The server never sees the original name, so the file is listed under
+    /// an opaque one like server-encrypted SilentLock containers.
+    pub fn new_client_encrypted(size: u64, storage_key: String) -> Self {
+        let id = Uuid::new_v4().to_string();
+        Self {
+            filename: format!("{}.slck", id),
+            id,
+            size,
+            content_type: Some("application/octet-stream".to_string()),
+            encrypted: true,
+            uploaded_at: chrono::Utc::now(),
+            storage_key,
+            expires_at: None,
+            downloads_remaining: None,
+            owner: None,
+        }
+    }
+    
+    /// Whether the file's expiry time has passed
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= chrono::Utc::now())
+    }
+    
+    /// Whether the file belongs to the user with ID `user_id`
+    ///
+    /// Files from before accounts existed belong to no one until they are
+    /// handed over with `FileStore::adopt_unowned_files`.
+    pub fn is_owned_by(&self, user_id: &str) -> bool {
+        self.owner.as_deref() == Some(user_id)
+    }
+    
+    /// Attributes to seal into the container when this file is encrypted
+    pub fn metadata(&self) -> FileMetadata {
+        FileMetadata {
+            filename: self.filename.clone(),
+            content_type: self.content_type.clone(),
+            uploaded_at: self.uploaded_at,
         }
     }
 }
@@ -67,7 +141,40 @@ pub struct EncryptRequest {
     pub file_id: String,
     
     /// Passphrase to use for encryption
-    pub passphrase: String,
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    
+    /// Further passphrases, any of which can decrypt the file
+    #[serde(default)]
+    pub passphrases: Vec<String>,
+    
+    /// IDs of registered public keys that can decrypt the file
+    #[serde(default)]
+    pub key_ids: Vec<String>,
+    
+    /// age X25519 recipients (`age1...`) to encrypt to in place of a passphrase
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    
+    /// Container format, SilentLock if omitted; age recipients imply age
+    #[serde(default)]
+    pub format: ContainerFormat,
+    
+    /// Cipher to encrypt with, AES-256-GCM if omitted (SilentLock format only)
+    #[serde(default)]
+    pub algorithm: CipherAlgorithm,
+    
+    /// Time after which the encrypted file is deleted
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    
+    /// Seconds after which the encrypted file is deleted, in place of `expires_at`
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    
+    /// Number of downloads allowed before the encrypted file is deleted
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
 }
 
 /// Request to decrypt a file
@@ -77,7 +184,12 @@ pub struct DecryptRequest {
     pub file_id: String,
     
     /// Passphrase to use for decryption
-    pub passphrase: String,
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    
+    /// age X25519 secret key (`AGE-SECRET-KEY-1...`) to use in place of a passphrase
+    #[serde(default)]
+    pub identity: Option<String>,
 }
 
 /// Response for file operations
@@ -93,6 +205,14 @@ pub struct FileResponse {
     pub file: Option<FileInfo>,
 }
 
+/// Options for deleting a file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteFileRequest {
+    /// Overwrite the contents of an unencrypted file before removing it
+    #[serde(default)]
+    pub secure: bool,
+}
+
 /// Response for listing files
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ListFilesResponse {
@@ -100,9 +220,575 @@ pub struct ListFilesResponse {
     pub files: Vec<FileInfo>,
 }
 
+/// Options for a plain upload
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadRequest {
+    /// Time after which the file is deleted
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    
+    /// Seconds after which the file is deleted, in place of `expires_at`
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    
+    /// Number of downloads allowed before the file is deleted
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+    
+    /// Whether the file is a SilentLock container already encrypted by the client
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
 /// Request to upload a file with encryption
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UploadEncryptRequest {
     /// Passphrase to use for encryption
     pub passphrase: String,
+    
+    /// Container format, SilentLock if omitted
+    #[serde(default)]
+    pub format: ContainerFormat,
+    
+    /// Cipher to encrypt with, AES-256-GCM if omitted (SilentLock format only)
+    #[serde(default)]
+    pub algorithm: CipherAlgorithm,
+    
+    /// Time after which the file is deleted
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    
+    /// Seconds after which the file is deleted, in place of `expires_at`
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    
+    /// Number of downloads allowed before the file is deleted
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+}
+
+/// A registered RSA public key that files can be encrypted to
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PublicKeyInfo {
+    /// Unique identifier for the key
+    pub id: String,
+    
+    /// Human-readable label
+    pub name: String,
+    
+    /// Hex-encoded SHA-256 fingerprint of the key
+    pub fingerprint: String,
+    
+    /// The key itself in PEM format
+    pub public_key_pem: String,
+    
+    /// Timestamp when the key was registered
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl PublicKeyInfo {
+    /// Creates a new PublicKeyInfo instance for a registered key
+    pub fn new(name: String, fingerprint: String, public_key_pem: String) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            name,
+            fingerprint,
+            public_key_pem,
+            created_at: chrono::Utc::now(),
+        }
+    }
+}
+
+/// Request to register an RSA public key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegisterKeyRequest {
+    /// Human-readable label for the key
+    pub name: String,
+    
+    /// RSA public key in PEM format (SPKI or PKCS#1)
+    pub public_key_pem: String,
+}
+
+/// Response for key operations
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyResponse {
+    /// Success status
+    pub success: bool,
+    
+    /// Message describing the result
+    pub message: String,
+    
+    /// Key information if available
+    pub key: Option<PublicKeyInfo>,
+}
+
+/// Response for listing public keys
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListKeysResponse {
+    /// List of keys
+    pub keys: Vec<PublicKeyInfo>,
+}
+
+/// Request to encrypt a file to a registered public key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncryptToKeyRequest {
+    /// ID of the file to encrypt
+    pub file_id: String,
+    
+    /// ID of the registered public key to encrypt to
+    pub key_id: String,
+    
+    /// Cipher to encrypt with, AES-256-GCM if omitted
+    #[serde(default)]
+    pub algorithm: CipherAlgorithm,
+    
+    /// Time after which the encrypted file is deleted
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    
+    /// Seconds after which the encrypted file is deleted, in place of `expires_at`
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    
+    /// Number of downloads allowed before the encrypted file is deleted
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+}
+
+/// Request to decrypt a file with an RSA private key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DecryptWithKeyRequest {
+    /// ID of the file to decrypt
+    pub file_id: String,
+    
+    /// RSA private key in PEM format (PKCS#8 or PKCS#1)
+    pub private_key_pem: String,
+}
+
+/// A key slot of a stored encrypted file
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KeySlotInfo {
+    /// Position of the slot in the file header, used to remove it
+    pub index: usize,
+    
+    /// Kind of recipient the slot was made for: "passphrase" or "rsa"
+    pub kind: String,
+    
+    /// Hex-encoded fingerprint of the public key, for RSA slots
+    pub fingerprint: Option<String>,
+    
+    /// ID of the matching registered public key, if there is one
+    pub key_id: Option<String>,
+}
+
+/// Response for listing the key slots of a file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListRecipientsResponse {
+    /// Key slots in header order
+    pub recipients: Vec<KeySlotInfo>,
+}
+
+/// Request to add a recipient to an encrypted file
+///
+/// One of `passphrase` or `private_key_pem` must open the file, and one of
+/// `new_passphrase` or `key_id` names the recipient to add.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddRecipientRequest {
+    /// Passphrase that currently opens the file
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    
+    /// RSA private key in PEM format that currently opens the file
+    #[serde(default)]
+    pub private_key_pem: Option<String>,
+    
+    /// Passphrase to add
+    #[serde(default)]
+    pub new_passphrase: Option<String>,
+    
+    /// ID of a registered public key to add
+    #[serde(default)]
+    pub key_id: Option<String>,
+}
+
+/// Request to remove a recipient from an encrypted file
+///
+/// One of `passphrase` or `private_key_pem` must open the file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoveRecipientRequest {
+    /// Passphrase that currently opens the file
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    
+    /// RSA private key in PEM format that currently opens the file
+    #[serde(default)]
+    pub private_key_pem: Option<String>,
+}
+
+/// Request to replace a credential of an encrypted file
+///
+/// One of `passphrase` or `private_key_pem` must open the file, and one of
+/// `new_passphrase` or `key_id` names the credential that replaces it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RekeyRequest {
+    /// Passphrase that currently opens the file
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    
+    /// RSA private key in PEM format that currently opens the file
+    #[serde(default)]
+    pub private_key_pem: Option<String>,
+    
+    /// Passphrase to replace it with
+    #[serde(default)]
+    pub new_passphrase: Option<String>,
+    
+    /// ID of a registered public key to replace it with
+    #[serde(default)]
+    pub key_id: Option<String>,
+}
+
+/// A link that gives access to a file without knowing its ID
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Share {
+    /// Unique identifier for the share, used to revoke it
+    pub id: String,
+    
+    /// ID of the shared file
+    pub file_id: String,
+    
+    /// Hex-encoded SHA-256 of the link token; the token itself is never stored
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    
+    /// Argon2 hash of the extra password, if the share has one
+    #[serde(skip_serializing)]
+    pub password_hash: Option<String>,
+    
+    /// Timestamp when the share was created
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    
+    /// Time after which the link stops working, if any
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Share {
+    /// Creates a new Share instance for a file
+    pub fn new(
+        file_id: String,
+        token_hash: String,
+        password_hash: Option<String>,
+        expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            file_id,
+            token_hash,
+            password_hash,
+            created_at: chrono::Utc::now(),
+            expires_at,
+        }
+    }
+    
+    /// Whether the share's expiry time has passed
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= chrono::Utc::now())
+    }
+    
+    /// Whether opening the share requires a password
+    pub fn has_password(&self) -> bool {
+        self.password_hash.is_some()
+    }
+}
+
+/// Public description of a share
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareInfo {
+    #[serde(flatten)]
+    pub share: Share,
+    
+    /// Whether opening the share requires a password
+    pub has_password: bool,
+}
+
+impl From<Share> for ShareInfo {
+    fn from(share: Share) -> Self {
+        let has_password = share.has_password();
+        Self {
+            share,
+            has_password,
+        }
+    }
+}
+
+/// Request to create a share link for a file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateShareRequest {
+    /// Extra password required to open the link
+    #[serde(default)]
+    pub password: Option<String>,
+    
+    /// Time after which the link stops working
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    
+    /// Seconds after which the link stops working, in place of `expires_at`
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+/// Response for share operations
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareResponse {
+    /// Success status
+    pub success: bool,
+    
+    /// Message describing the result
+    pub message: String,
+    
+    /// Share information if available
+    pub share: Option<ShareInfo>,
+    
+    /// Path of the link, only returned when the share is created
+    pub url: Option<String>,
+}
+
+/// Response for listing the shares of a file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListSharesResponse {
+    /// List of shares
+    pub shares: Vec<ShareInfo>,
+}
+
+/// Request to open a share link
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenShareRequest {
+    /// The share's extra password, if it has one
+    #[serde(default)]
+    pub password: Option<String>,
+    
+    /// Passphrase to decrypt the file with; without it the stored file is sent as is
+    #[serde(default)]
+    pub passphrase: Option<String>,
+}
+
+/// A registered user
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct User {
+    /// Unique identifier for the user
+    pub id: String,
+    
+    /// Name the user signs in with, in lowercase
+    pub username: String,
+    
+    /// Argon2 hash of the password
+    #[serde(skip_serializing, default)]
+    pub password_hash: String,
+    
+    /// Timestamp when the user registered
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl User {
+    /// Creates a new User instance for a registration
+    pub fn new(username: String, password_hash: String) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            username,
+            password_hash,
+            created_at: chrono::Utc::now(),
+        }
+    }
+}
+
+/// A signed-in browser session, identified by the hash of its cookie
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// Hex-encoded SHA-256 of the session token; the token itself is never stored
+    pub token_hash: String,
+    
+    /// ID of the signed-in user
+    pub user_id: String,
+    
+    /// Timestamp when the session was created
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    
+    /// Time after which the session is no longer accepted
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Session {
+    /// Creates a new Session instance that lasts for `lifetime`
+    pub fn new(token_hash: String, user_id: String, lifetime: chrono::Duration) -> Self {
+        let created_at = chrono::Utc::now();
+        Self {
+            token_hash,
+            user_id,
+            created_at,
+            expires_at: created_at + lifetime,
+        }
+    }
+    
+    /// Whether the session's expiry time has passed
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= chrono::Utc::now()
+    }
+}
+
+/// Request to register or sign in
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CredentialsRequest {
+    /// Name to sign in with
+    pub username: String,
+    
+    /// The user's password
+    pub password: String,
+}
+
+/// Response for account operations
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserResponse {
+    /// Success status
+    pub success: bool,
+    
+    /// Message describing the result
+    pub message: String,
+    
+    /// User information if available
+    pub user: Option<User>,
+}
+
+/// Something an API token is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// List and download files, list their shares, and look up keys
+    Read,
+    
+    /// Upload files and register keys
+    Upload,
+    
+    /// Encrypt files and change their recipients
+    Encrypt,
+    
+    /// Decrypt files on the server
+    Decrypt,
+    
+    /// Delete files and revoke their shares
+    Delete,
+    
+    /// Create share links, which give anyone holding them the file
+    Share,
+}
+
+impl TokenScope {
+    /// Name of the scope as written in requests
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Upload => "upload",
+            TokenScope::Encrypt => "encrypt",
+            TokenScope::Decrypt => "decrypt",
+            TokenScope::Delete => "delete",
+            TokenScope::Share => "share",
+        }
+    }
+}
+
+/// A long-lived token for machine clients, identified by the hash of its value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Unique identifier for the token, used to revoke it
+    pub id: String,
+    
+    /// ID of the user the token acts as
+    pub user_id: String,
+    
+    /// Name given to the token by its owner
+    pub name: String,
+    
+    /// Hex-encoded SHA-256 of the token; the token itself is never stored
+    #[serde(skip_serializing, default)]
+    pub token_hash: String,
+    
+    /// What the token is allowed to do
+    pub scopes: Vec<TokenScope>,
+    
+    /// Timestamp when the token was created
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    
+    /// Time after which the token is no longer accepted, if any
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ApiToken {
+    /// Creates a new ApiToken instance for a user
+    pub fn new(
+        user_id: String,
+        name: String,
+        token_hash: String,
+        scopes: Vec<TokenScope>,
+        expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            user_id,
+            name,
+            token_hash,
+            scopes,
+            created_at: chrono::Utc::now(),
+            expires_at,
+        }
+    }
+    
+    /// Whether the token's expiry time has passed
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= chrono::Utc::now())
+    }
+    
+    /// Whether the token was granted `scope`
+    pub fn allows(&self, scope: TokenScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// Request to create an API token
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateTokenRequest {
+    /// Name to recognize the token by
+    pub name: String,
+    
+    /// What the token is allowed to do
+    pub scopes: Vec<TokenScope>,
+    
+    /// Time after which the token stops working
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    
+    /// Seconds after which the token stops working, in place of `expires_at`
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+/// Response for API token operations
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenResponse {
+    /// Success status
+    pub success: bool,
+    
+    /// Message describing the result
+    pub message: String,
+    
+    /// Token information if available
+    pub token: Option<ApiToken>,
+    
+    /// The token to send as `Authorization: Bearer`, only returned when it is created
+    pub secret: Option<String>,
+}
+
+/// Response for listing a user's API tokens
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListTokensResponse {
+    /// List of tokens
+    pub tokens: Vec<ApiToken>,
 }
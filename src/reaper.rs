@@ -0,0 +1,80 @@
+//! Background deletion of expired files and sessions.
+//!
+//! Expired files answer 410 Gone as soon as their expiry passes; the reaper
+//! then removes their contents and records on its next pass. It also picks up
+//! files whose last download was claimed but never cleaned up, and removes
+//! sessions and unfinished tus uploads that have expired.
+
+use log::{info, error, warn};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::blob::BlobStore;
+use crate::handlers::tus::TusUploads;
+use crate::store::{FileStore, StoreError, UserStore};
+use crate::utils::discard_file_contents;
+
+/// How often the reaper looks for expired files
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Deletes expired files, sessions and uploads every `REAP_INTERVAL` for as long as the server runs
+pub async fn run(
+    file_store: Arc<dyn FileStore>,
+    blob_store: Arc<dyn BlobStore>,
+    user_store: Arc<dyn UserStore>,
+    tus_uploads: Arc<TusUploads>,
+) {
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        
+        let file_store = file_store.clone();
+        let blob_store = blob_store.clone();
+        match tokio::task::spawn_blocking(move || reap_expired(file_store.as_ref(), blob_store.as_ref())).await {
+            Ok(Ok(0)) => {},
+            Ok(Ok(reaped)) => info!("Deleted {} expired files", reaped),
+            Ok(Err(e)) => error!("Error deleting expired files: {}", e),
+            Err(e) => error!("Reaper task failed: {}", e),
+        }
+        
+        let user_store = user_store.clone();
+        match tokio::task::spawn_blocking(move || user_store.remove_expired_sessions()).await {
+            Ok(Ok(0)) => {},
+            Ok(Ok(removed)) => info!("Removed {} expired sessions", removed),
+            Ok(Err(e)) => error!("Error removing expired sessions: {}", e),
+            Err(e) => error!("Reaper task failed: {}", e),
+        }
+        
+        let tus_uploads = tus_uploads.clone();
+        match tokio::task::spawn_blocking(move || tus_uploads.remove_expired()).await {
+            Ok(0) => {},
+            Ok(removed) => info!("Removed {} expired uploads", removed),
+            Err(e) => error!("Reaper task failed: {}", e),
+        }
+    }
+}
+
+/// Deletes every expired or used-up file, returning how many were removed
+///
+/// A file whose contents can't be deleted keeps its record and is retried
+/// next time.
+pub fn reap_expired(file_store: &dyn FileStore, blob_store: &dyn BlobStore) -> Result<usize, StoreError> {
+    let mut reaped = 0;
+    
+    for file_info in file_store.list_files()? {
+        if !file_info.is_expired() && file_info.downloads_remaining != Some(0) {
+            continue;
+        }
+        
+        if let Err(e) = discard_file_contents(blob_store, &file_info) {
+            warn!("Failed to delete contents of expired file {}: {}", file_info.id, e);
+            continue;
+        }
+        
+        file_store.remove_file(&file_info.id)?;
+        info!("Expired file deleted: {}", file_info.id);
+        reaped += 1;
+    }
+    
+    Ok(reaped)
+}
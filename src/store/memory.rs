@@ -0,0 +1,223 @@
+use std::sync::RwLock;
+
+use super::{DownloadClaim, FileStore, KeyStore, ShareStore, StoreError, UserStore};
+use crate::models::{ApiToken, FileInfo, PublicKeyInfo, Session, Share, User};
+
+/// In-memory storage for file information, shares, users, API tokens and public keys
+///
+/// Everything is lost when the process exits.
+pub struct MemoryFileStore {
+    files: RwLock<Vec<FileInfo>>,
+    shares: RwLock<Vec<Share>>,
+    users: RwLock<Vec<User>>,
+    sessions: RwLock<Vec<Session>>,
+    api_tokens: RwLock<Vec<ApiToken>>,
+    keys: RwLock<Vec<PublicKeyInfo>>,
+}
+
+impl MemoryFileStore {
+    pub fn new() -> Self {
+        Self {
+            files: RwLock::new(Vec::new()),
+            shares: RwLock::new(Vec::new()),
+            users: RwLock::new(Vec::new()),
+            sessions: RwLock::new(Vec::new()),
+            api_tokens: RwLock::new(Vec::new()),
+            keys: RwLock::new(Vec::new()),
+        }
+    }
+}
+
+impl Default for MemoryFileStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileStore for MemoryFileStore {
+    fn add_file(&self, file_info: FileInfo) -> Result<(), StoreError> {
+        let mut files = self.files.write().unwrap();
+        files.push(file_info);
+        Ok(())
+    }
+    
+    fn get_file(&self, id: &str) -> Result<Option<FileInfo>, StoreError> {
+        let files = self.files.read().unwrap();
+        Ok(files.iter().find(|f| f.id == id).cloned())
+    }
+    
+    fn list_files(&self) -> Result<Vec<FileInfo>, StoreError> {
+        let files = self.files.read().unwrap();
+        Ok(files.clone())
+    }
+    
+    fn update_file(&self, file_info: FileInfo) -> Result<(), StoreError> {
+        let mut files = self.files.write().unwrap();
+        if let Some(file) = files.iter_mut().find(|f| f.id == file_info.id) {
+            let downloads_remaining = file.downloads_remaining;
+            *file = FileInfo {
+                downloads_remaining,
+                ..file_info
+            };
+        }
+        Ok(())
+    }
+    
+    fn claim_download(&self, id: &str) -> Result<DownloadClaim, StoreError> {
+        let mut files = self.files.write().unwrap();
+        let claim = match files.iter_mut().find(|f| f.id == id) {
+            Some(file) => match &mut file.downloads_remaining {
+                None => DownloadClaim::Unlimited,
+                Some(0) => DownloadClaim::Refused,
+                Some(remaining) => {
+                    *remaining -= 1;
+                    DownloadClaim::Claimed(*remaining)
+                }
+            },
+            None => DownloadClaim::Refused,
+        };
+        Ok(claim)
+    }
+    
+    fn remove_file(&self, id: &str) -> Result<(), StoreError> {
+        let mut files = self.files.write().unwrap();
+        if let Some(pos) = files.iter().position(|f| f.id == id) {
+            files.remove(pos);
+        }
+        self.shares.write().unwrap().retain(|s| s.file_id != id);
+        Ok(())
+    }
+    
+    fn adopt_unowned_files(&self, owner: &str) -> Result<usize, StoreError> {
+        let mut files = self.files.write().unwrap();
+        let mut adopted = 0;
+        for file in files.iter_mut().filter(|f| f.owner.is_none()) {
+            file.owner = Some(owner.to_string());
+            adopted += 1;
+        }
+        Ok(adopted)
+    }
+}
+
+impl ShareStore for MemoryFileStore {
+    fn add_share(&self, share: Share) -> Result<(), StoreError> {
+        let mut shares = self.shares.write().unwrap();
+        shares.push(share);
+        Ok(())
+    }
+    
+    fn find_share(&self, token_hash: &str) -> Result<Option<Share>, StoreError> {
+        let shares = self.shares.read().unwrap();
+        Ok(shares.iter().find(|s| s.token_hash == token_hash).cloned())
+    }
+    
+    fn list_shares(&self, file_id: &str) -> Result<Vec<Share>, StoreError> {
+        let shares = self.shares.read().unwrap();
+        Ok(shares.iter().filter(|s| s.file_id == file_id).cloned().collect())
+    }
+    
+    fn remove_share(&self, file_id: &str, id: &str) -> Result<bool, StoreError> {
+        let mut shares = self.shares.write().unwrap();
+        match shares.iter().position(|s| s.file_id == file_id && s.id == id) {
+            Some(pos) => {
+                shares.remove(pos);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl UserStore for MemoryFileStore {
+    fn add_user(&self, user: User) -> Result<bool, StoreError> {
+        let mut users = self.users.write().unwrap();
+        if users.iter().any(|u| u.username == user.username) {
+            return Ok(false);
+        }
+        users.push(user);
+        Ok(true)
+    }
+    
+    fn get_user(&self, id: &str) -> Result<Option<User>, StoreError> {
+        let users = self.users.read().unwrap();
+        Ok(users.iter().find(|u| u.id == id).cloned())
+    }
+    
+    fn find_user(&self, username: &str) -> Result<Option<User>, StoreError> {
+        let users = self.users.read().unwrap();
+        Ok(users.iter().find(|u| u.username == username).cloned())
+    }
+    
+    fn add_session(&self, session: Session) -> Result<(), StoreError> {
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.push(session);
+        Ok(())
+    }
+    
+    fn find_session(&self, token_hash: &str) -> Result<Option<Session>, StoreError> {
+        let sessions = self.sessions.read().unwrap();
+        Ok(sessions.iter().find(|s| s.token_hash == token_hash).cloned())
+    }
+    
+    fn remove_session(&self, token_hash: &str) -> Result<(), StoreError> {
+        self.sessions.write().unwrap().retain(|s| s.token_hash != token_hash);
+        Ok(())
+    }
+    
+    fn remove_expired_sessions(&self) -> Result<usize, StoreError> {
+        let mut sessions = self.sessions.write().unwrap();
+        let before = sessions.len();
+        sessions.retain(|s| !s.is_expired());
+        Ok(before - sessions.len())
+    }
+    
+    fn add_api_token(&self, token: ApiToken) -> Result<(), StoreError> {
+        let mut api_tokens = self.api_tokens.write().unwrap();
+        api_tokens.push(token);
+        Ok(())
+    }
+    
+    fn find_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>, StoreError> {
+        let api_tokens = self.api_tokens.read().unwrap();
+        Ok(api_tokens.iter().find(|t| t.token_hash == token_hash).cloned())
+    }
+    
+    fn list_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>, StoreError> {
+        let api_tokens = self.api_tokens.read().unwrap();
+        Ok(api_tokens.iter().filter(|t| t.user_id == user_id).cloned().collect())
+    }
+    
+    fn remove_api_token(&self, user_id: &str, id: &str) -> Result<bool, StoreError> {
+        let mut api_tokens = self.api_tokens.write().unwrap();
+        match api_tokens.iter().position(|t| t.user_id == user_id && t.id == id) {
+            Some(pos) => {
+                api_tokens.remove(pos);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl KeyStore for MemoryFileStore {
+    fn add_key(&self, key_info: PublicKeyInfo) -> Result<(), StoreError> {
+        let mut keys = self.keys.write().unwrap();
+        keys.push(key_info);
+        Ok(())
+    }
+    
+    fn get_key(&self, id: &str) -> Result<Option<PublicKeyInfo>, StoreError> {
+        let keys = self.keys.read().unwrap();
+        Ok(keys.iter().find(|k| k.id == id).cloned())
+    }
+    
+    fn find_key_by_fingerprint(&self, fingerprint: &str) -> Result<Option<PublicKeyInfo>, StoreError> {
+        let keys = self.keys.read().unwrap();
+        Ok(keys.iter().find(|k| k.fingerprint == fingerprint).cloned())
+    }
+    
+    fn list_keys(&self) -> Result<Vec<PublicKeyInfo>, StoreError> {
+        let keys = self.keys.read().unwrap();
+        Ok(keys.clone())
+    }
+}
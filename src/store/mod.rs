@@ -0,0 +1,376 @@
+//! Storage for `FileInfo`, `Share`, `User` and `PublicKeyInfo` records.
+//!
+//! The server keeps its file records behind the `FileStore` trait, share
+//! links behind `ShareStore`, accounts with their sessions and API tokens
+//! behind `UserStore` and registered public keys behind `KeyStore`; all are
+//! implemented by the same stores. SQLite is used by
+//! default so records survive restarts; the in-memory store is kept for tests
+//! and throwaway instances.
+
+use actix_web::{HttpResponse, ResponseError};
+use log::error;
+use thiserror::Error;
+
+use crate::models::{ApiToken, FileInfo, PublicKeyInfo, Session, Share, User};
+
+pub mod memory;
+pub mod sqlite;
+
+pub use memory::MemoryFileStore;
+pub use sqlite::SqliteFileStore;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    
+    #[error("Corrupt record: {0}")]
+    Corrupt(String),
+}
+
+// Storage failures are the server's problem, so handlers can simply use `?`
+impl ResponseError for StoreError {
+    fn error_response(&self) -> HttpResponse {
+        error!("File store error: {}", self);
+        HttpResponse::InternalServerError().body("Storage error")
+    }
+}
+
+/// Outcome of claiming one download of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadClaim {
+    /// The file has no download limit
+    Unlimited,
+    
+    /// A download was claimed, leaving this many
+    Claimed(u32),
+    
+    /// The file has no downloads left or does not exist
+    Refused,
+}
+
+/// Persistence for file records, looked up by ID
+pub trait FileStore: Send + Sync {
+    /// Stores a new record
+    fn add_file(&self, file_info: FileInfo) -> Result<(), StoreError>;
+    
+    /// Looks up a record by ID
+    fn get_file(&self, id: &str) -> Result<Option<FileInfo>, StoreError>;
+    
+    /// Returns all records in upload order
+    fn list_files(&self) -> Result<Vec<FileInfo>, StoreError>;
+    
+    /// Replaces the record with the same ID, if there is one
+    ///
+    /// The remaining download count is left alone; only `claim_download` changes it.
+    fn update_file(&self, file_info: FileInfo) -> Result<(), StoreError>;
+    
+    /// Atomically takes one download from a file's remaining count
+    fn claim_download(&self, id: &str) -> Result<DownloadClaim, StoreError>;
+    
+    /// Removes a record by ID, if there is one, along with its shares
+    fn remove_file(&self, id: &str) -> Result<(), StoreError>;
+    
+    /// Gives every file without an owner to the user with ID `owner`
+    ///
+    /// Files uploaded before accounts existed have no owner. Returns how many
+    /// were handed over.
+    fn adopt_unowned_files(&self, owner: &str) -> Result<usize, StoreError>;
+}
+
+/// Persistence for share links, looked up by the hash of their token
+pub trait ShareStore: Send + Sync {
+    /// Stores a new share
+    fn add_share(&self, share: Share) -> Result<(), StoreError>;
+    
+    /// Looks up a share by the hash of its token
+    fn find_share(&self, token_hash: &str) -> Result<Option<Share>, StoreError>;
+    
+    /// Returns the shares of a file in creation order
+    fn list_shares(&self, file_id: &str) -> Result<Vec<Share>, StoreError>;
+    
+    /// Removes a share of a file, returning whether there was one
+    fn remove_share(&self, file_id: &str, id: &str) -> Result<bool, StoreError>;
+}
+
+/// Persistence for user accounts, their sessions and their API tokens
+pub trait UserStore: Send + Sync {
+    /// Stores a new user, returning false if the username is already taken
+    fn add_user(&self, user: User) -> Result<bool, StoreError>;
+    
+    /// Looks up a user by ID
+    fn get_user(&self, id: &str) -> Result<Option<User>, StoreError>;
+    
+    /// Looks up a user by username
+    fn find_user(&self, username: &str) -> Result<Option<User>, StoreError>;
+    
+    /// Stores a new session
+    fn add_session(&self, session: Session) -> Result<(), StoreError>;
+    
+    /// Looks up a session by the hash of its token
+    fn find_session(&self, token_hash: &str) -> Result<Option<Session>, StoreError>;
+    
+    /// Removes a session by the hash of its token, if there is one
+    fn remove_session(&self, token_hash: &str) -> Result<(), StoreError>;
+    
+    /// Removes every expired session, returning how many were removed
+    fn remove_expired_sessions(&self) -> Result<usize, StoreError>;
+    
+    /// Stores a new API token
+    fn add_api_token(&self, token: ApiToken) -> Result<(), StoreError>;
+    
+    /// Looks up an API token by the hash of its value
+    fn find_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>, StoreError>;
+    
+    /// Returns the API tokens of a user in creation order
+    fn list_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>, StoreError>;
+    
+    /// Removes an API token of a user, returning whether there was one
+    fn remove_api_token(&self, user_id: &str, id: &str) -> Result<bool, StoreError>;
+}
+
+/// Persistence for registered RSA public keys
+pub trait KeyStore: Send + Sync {
+    /// Stores a new key
+    fn add_key(&self, key_info: PublicKeyInfo) -> Result<(), StoreError>;
+    
+    /// Looks up a key by ID
+    fn get_key(&self, id: &str) -> Result<Option<PublicKeyInfo>, StoreError>;
+    
+    /// Looks up the first key registered with a hex-encoded fingerprint
+    fn find_key_by_fingerprint(&self, fingerprint: &str) -> Result<Option<PublicKeyInfo>, StoreError>;
+    
+    /// Returns all keys in registration order
+    fn list_keys(&self) -> Result<Vec<PublicKeyInfo>, StoreError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TokenScope;
+    use std::sync::Arc;
+    use std::thread;
+    
+    fn stores() -> Vec<Arc<dyn FileStore>> {
+        vec![
+            Arc::new(MemoryFileStore::new()),
+            Arc::new(SqliteFileStore::open_in_memory().unwrap()),
+        ]
+    }
+    
+    fn add(store: &dyn FileStore, downloads_remaining: Option<u32>, owner: Option<&str>) -> String {
+        let mut file_info = FileInfo::new("a.txt".to_string(), 1, None, "key".to_string());
+        file_info.downloads_remaining = downloads_remaining;
+        file_info.owner = owner.map(str::to_string);
+        let id = file_info.id.clone();
+        store.add_file(file_info).unwrap();
+        id
+    }
+    
+    #[test]
+    fn parallel_claims_never_exceed_the_limit() {
+        for store in stores() {
+            let id = add(&*store, Some(10), None);
+            let claims: Vec<DownloadClaim> = (0..32)
+                .map(|_| {
+                    let store = store.clone();
+                    let id = id.clone();
+                    thread::spawn(move || store.claim_download(&id).unwrap())
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect();
+            
+            let mut left: Vec<u32> = claims.iter()
+                .filter_map(|claim| match claim {
+                    DownloadClaim::Claimed(left) => Some(*left),
+                    _ => None,
+                })
+                .collect();
+            left.sort_unstable();
+            assert_eq!(left, (0..10).collect::<Vec<_>>());
+            assert_eq!(claims.iter().filter(|claim| **claim == DownloadClaim::Refused).count(), 22);
+            assert_eq!(store.get_file(&id).unwrap().unwrap().downloads_remaining, Some(0));
+        }
+    }
+    
+    #[test]
+    fn claims_on_unlimited_and_missing_files() {
+        for store in stores() {
+            let id = add(&*store, None, None);
+            assert_eq!(store.claim_download(&id).unwrap(), DownloadClaim::Unlimited);
+            assert_eq!(store.get_file(&id).unwrap().unwrap().downloads_remaining, None);
+            assert_eq!(store.claim_download("missing").unwrap(), DownloadClaim::Refused);
+        }
+    }
+    
+    #[test]
+    fn update_leaves_the_download_count_alone() {
+        for store in stores() {
+            let id = add(&*store, Some(2), None);
+            store.claim_download(&id).unwrap();
+            
+            let mut file_info = store.get_file(&id).unwrap().unwrap();
+            file_info.downloads_remaining = Some(5);
+            store.update_file(file_info).unwrap();
+            assert_eq!(store.get_file(&id).unwrap().unwrap().downloads_remaining, Some(1));
+        }
+    }    
+    fn key(name: &str, fingerprint: &str) -> PublicKeyInfo {
+        PublicKeyInfo::new(name.to_string(), fingerprint.to_string(), "pem".to_string())
+    }
+    
+    #[test]
+    fn keys_are_found_by_id_and_fingerprint() {
+        let key_stores: Vec<Arc<dyn KeyStore>> = vec![
+            Arc::new(MemoryFileStore::new()),
+            Arc::new(SqliteFileStore::open_in_memory().unwrap()),
+        ];
+        for store in key_stores {
+            let first = key("first", "aa");
+            let second = key("second", "bb");
+            let again = key("again", "aa");
+            for key_info in [&first, &second, &again] {
+                store.add_key(key_info.clone()).unwrap();
+            }
+            
+            let found = store.get_key(&second.id).unwrap().unwrap();
+            assert_eq!((found.name.as_str(), found.created_at), ("second", second.created_at));
+            assert_eq!(store.get_key("missing").unwrap().map(|k| k.id), None);
+            assert_eq!(store.find_key_by_fingerprint("aa").unwrap().map(|k| k.id), Some(first.id.clone()));
+            assert_eq!(store.find_key_by_fingerprint("cc").unwrap().map(|k| k.id), None);
+            
+            let ids: Vec<String> = store.list_keys().unwrap().into_iter().map(|k| k.id).collect();
+            assert_eq!(ids, [first.id, second.id, again.id]);
+        }
+    }
+    
+    #[test]
+    fn keys_survive_reopening_the_database() {
+        let path = std::env::temp_dir().join(format!("silentlock-keys-{}.db", uuid::Uuid::new_v4()));
+        let key_info = key("laptop", "aa");
+        {
+            let store = SqliteFileStore::open(&path).unwrap();
+            store.add_key(key_info.clone()).unwrap();
+        }
+        
+        let store = SqliteFileStore::open(&path).unwrap();
+        let found = store.get_key(&key_info.id).unwrap().unwrap();
+        assert_eq!(found.public_key_pem, key_info.public_key_pem);
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+    
+    #[test]
+    fn shares_are_found_by_token_hash_and_removed_with_their_file() {
+        let memory = Arc::new(MemoryFileStore::new());
+        let sqlite = Arc::new(SqliteFileStore::open_in_memory().unwrap());
+        let pairs: Vec<(Arc<dyn FileStore>, Arc<dyn ShareStore>)> = vec![
+            (memory.clone(), memory),
+            (sqlite.clone(), sqlite),
+        ];
+        for (files, shares) in pairs {
+            let file_id = add(&*files, None, None);
+            let other_id = add(&*files, None, None);
+            let first = Share::new(file_id.clone(), "hash-1".to_string(), Some("password".to_string()), None);
+            let second = Share::new(file_id.clone(), "hash-2".to_string(), None, Some(chrono::Utc::now()));
+            let other = Share::new(other_id.clone(), "hash-3".to_string(), None, None);
+            for share in [&first, &second, &other] {
+                shares.add_share(share.clone()).unwrap();
+            }
+            
+            let found = shares.find_share("hash-1").unwrap().unwrap();
+            assert_eq!((found.id.as_str(), found.password_hash.as_deref()), (first.id.as_str(), Some("password")));
+            assert!(shares.find_share("hash-2").unwrap().unwrap().is_expired());
+            assert!(shares.find_share(&first.id).unwrap().is_none());
+            
+            // Shares can only be removed through their own file
+            assert!(!shares.remove_share(&other_id, &first.id).unwrap());
+            assert!(shares.remove_share(&file_id, &first.id).unwrap());
+            assert!(!shares.remove_share(&file_id, &first.id).unwrap());
+            assert!(shares.find_share("hash-1").unwrap().is_none());
+            
+            files.remove_file(&file_id).unwrap();
+            assert!(shares.list_shares(&file_id).unwrap().is_empty());
+            assert!(shares.find_share("hash-2").unwrap().is_none());
+            assert_eq!(shares.list_shares(&other_id).unwrap().len(), 1);
+        }
+    }
+    
+    #[test]
+    fn adopts_only_unowned_files() {
+        for store in stores() {
+            let unowned = add(&*store, None, None);
+            let owned = add(&*store, None, Some("bob"));
+            
+            assert_eq!(store.adopt_unowned_files("alice").unwrap(), 1);
+            assert_eq!(store.get_file(&unowned).unwrap().unwrap().owner.as_deref(), Some("alice"));
+            assert_eq!(store.get_file(&owned).unwrap().unwrap().owner.as_deref(), Some("bob"));
+            assert_eq!(store.adopt_unowned_files("alice").unwrap(), 0);
+        }
+    }
+    
+    #[test]
+    fn usernames_are_unique_and_sessions_expire() {
+        let user_stores: Vec<Arc<dyn UserStore>> = vec![
+            Arc::new(MemoryFileStore::new()),
+            Arc::new(SqliteFileStore::open_in_memory().unwrap()),
+        ];
+        for store in user_stores {
+            let alice = User::new("alice".to_string(), "hash".to_string());
+            assert!(store.add_user(alice.clone()).unwrap());
+            assert!(!store.add_user(User::new("alice".to_string(), "other".to_string())).unwrap());
+            assert_eq!(store.find_user("alice").unwrap().map(|u| u.id), Some(alice.id.clone()));
+            assert_eq!(store.get_user(&alice.id).unwrap().map(|u| u.password_hash), Some("hash".to_string()));
+            assert!(store.find_user("bob").unwrap().is_none());
+            
+            store.add_session(Session::new("live".to_string(), alice.id.clone(), chrono::Duration::days(1))).unwrap();
+            store.add_session(Session::new("stale".to_string(), alice.id.clone(), chrono::Duration::seconds(-1))).unwrap();
+            assert!(store.find_session("stale").unwrap().unwrap().is_expired());
+            assert_eq!(store.remove_expired_sessions().unwrap(), 1);
+            assert!(store.find_session("stale").unwrap().is_none());
+            
+            let session = store.find_session("live").unwrap().unwrap();
+            assert_eq!(session.user_id, alice.id);
+            assert!(!session.is_expired());
+            store.remove_session("live").unwrap();
+            assert!(store.find_session("live").unwrap().is_none());
+        }
+    }
+    
+    #[test]
+    fn api_tokens_belong_to_their_user() {
+        let user_stores: Vec<Arc<dyn UserStore>> = vec![
+            Arc::new(MemoryFileStore::new()),
+            Arc::new(SqliteFileStore::open_in_memory().unwrap()),
+        ];
+        for store in user_stores {
+            let scopes = vec![TokenScope::Read, TokenScope::Upload];
+            let first = ApiToken::new("alice".to_string(), "ci".to_string(), "hash-1".to_string(), scopes, None);
+            let second = ApiToken::new("alice".to_string(), "backup".to_string(), "hash-2".to_string(), vec![TokenScope::Read], Some(chrono::Utc::now()));
+            let other = ApiToken::new("bob".to_string(), "ci".to_string(), "hash-3".to_string(), vec![TokenScope::Delete], None);
+            for token in [&first, &second, &other] {
+                store.add_api_token(token.clone()).unwrap();
+            }
+            
+            let found = store.find_api_token("hash-1").unwrap().unwrap();
+            assert_eq!((found.id.as_str(), found.scopes.as_slice()), (first.id.as_str(), &[TokenScope::Read, TokenScope::Upload][..]));
+            assert!(found.allows(TokenScope::Upload) && !found.allows(TokenScope::Delete));
+            assert!(store.find_api_token("hash-2").unwrap().unwrap().is_expired());
+            assert!(store.find_api_token(&first.id).unwrap().is_none());
+            
+            let ids: Vec<String> = store.list_api_tokens("alice").unwrap().into_iter().map(|t| t.id).collect();
+            assert_eq!(ids, [first.id.clone(), second.id.clone()]);
+            
+            // Tokens can only be revoked by their own user
+            assert!(!store.remove_api_token("bob", &first.id).unwrap());
+            assert!(store.remove_api_token("alice", &first.id).unwrap());
+            assert!(!store.remove_api_token("alice", &first.id).unwrap());
+            assert!(store.find_api_token("hash-1").unwrap().is_none());
+            assert_eq!(store.list_api_tokens("bob").unwrap().len(), 1);
+        }
+    }
+}
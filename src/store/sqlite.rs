@@ -0,0 +1,662 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, Row};
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::{DownloadClaim, FileStore, KeyStore, ShareStore, StoreError, UserStore};
+use crate::models::{ApiToken, FileInfo, PublicKeyInfo, Session, Share, TokenScope, User};
+
+/// Schema migrations, applied in order; `PRAGMA user_version` records how many have run
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE files (
+        id TEXT PRIMARY KEY NOT NULL,
+        filename TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        content_type TEXT,
+        encrypted INTEGER NOT NULL,
+        uploaded_at TEXT NOT NULL,
+        path TEXT NOT NULL
+    )",
+    // File contents moved into the blob store, whose local root is ./data
+    "ALTER TABLE files RENAME COLUMN path TO storage_key;
+     UPDATE files SET storage_key = substr(storage_key, 8) WHERE storage_key LIKE './data/%';",
+    "ALTER TABLE files ADD COLUMN expires_at TEXT",
+    "ALTER TABLE files ADD COLUMN downloads_remaining INTEGER",
+    "CREATE TABLE shares (
+        id TEXT PRIMARY KEY NOT NULL,
+        file_id TEXT NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE,
+        password_hash TEXT,
+        created_at TEXT NOT NULL,
+        expires_at TEXT
+    );
+     CREATE INDEX shares_file_id ON shares (file_id);",
+    "CREATE TABLE users (
+        id TEXT PRIMARY KEY NOT NULL,
+        username TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+     CREATE TABLE sessions (
+        token_hash TEXT PRIMARY KEY NOT NULL,
+        user_id TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        expires_at TEXT NOT NULL
+    );
+     ALTER TABLE files ADD COLUMN owner TEXT;",
+    "CREATE TABLE api_tokens (
+        id TEXT PRIMARY KEY NOT NULL,
+        user_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE,
+        scopes TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        expires_at TEXT
+    );
+     CREATE INDEX api_tokens_user_id ON api_tokens (user_id);",
+    "CREATE TABLE public_keys (
+        id TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL,
+        fingerprint TEXT NOT NULL,
+        public_key_pem TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+     CREATE INDEX public_keys_fingerprint ON public_keys (fingerprint);",
+];
+
+/// Columns selected for every `FileInfo`, in the order `row_to_file_info` reads them
+const FILE_COLUMNS: &str = "id, filename, size, content_type, encrypted, uploaded_at, storage_key, expires_at, downloads_remaining, owner";
+
+/// Columns selected for every `Share`, in the order `row_to_share` reads them
+const SHARE_COLUMNS: &str = "id, file_id, token_hash, password_hash, created_at, expires_at";
+
+/// Columns selected for every `User`, in the order `row_to_user` reads them
+const USER_COLUMNS: &str = "id, username, password_hash, created_at";
+
+/// Columns selected for every `Session`, in the order `row_to_session` reads them
+const SESSION_COLUMNS: &str = "token_hash, user_id, created_at, expires_at";
+
+/// Columns selected for every `ApiToken`, in the order `row_to_api_token` reads them
+const API_TOKEN_COLUMNS: &str = "id, user_id, name, token_hash, scopes, created_at, expires_at";
+
+/// Columns selected for every `PublicKeyInfo`, in the order `row_to_public_key` reads them
+const PUBLIC_KEY_COLUMNS: &str = "id, name, fingerprint, public_key_pem, created_at";
+
+/// File records, shares, users, API tokens and public keys kept in a SQLite database
+///
+/// The database runs in WAL mode with full syncs, so a committed record
+/// survives a crash or power loss.
+pub struct SqliteFileStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteFileStore {
+    /// Opens or creates the database at `path` and brings its schema up to date
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        Self::init(Connection::open(path)?)
+    }
+    
+    /// Creates a database that lives only as long as the store
+    #[cfg(test)]
+    pub(crate) fn open_in_memory() -> Result<Self, StoreError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+    
+    fn init(mut conn: Connection) -> Result<Self, StoreError> {
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "FULL")?;
+        
+        // Run any migrations this database has not seen yet
+        let applied: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        let tx = conn.transaction()?;
+        for migration in MIGRATIONS.iter().skip(applied) {
+            tx.execute_batch(migration)?;
+        }
+        tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+        tx.commit()?;
+        
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+/// Parses a stored RFC 3339 timestamp, naming the record it belongs to on error
+fn parse_timestamp(record: &str, timestamp: &str) -> Result<DateTime<Utc>, StoreError> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(|e| StoreError::Corrupt(format!("{}: bad timestamp: {}", record, e)))
+}
+
+/// Builds a `FileInfo` from a row selected with `FILE_COLUMNS`
+fn row_to_file_info(row: &Row<'_>) -> rusqlite::Result<Result<FileInfo, StoreError>> {
+    let id: String = row.get(0)?;
+    let size: i64 = row.get(2)?;
+    let uploaded_at: String = row.get(5)?;
+    
+    let expires_at: Option<String> = row.get(7)?;
+    
+    let record = format!("file {}", id);
+    let parse = |timestamp: &str| parse_timestamp(&record, timestamp);
+    let uploaded_at = match parse(&uploaded_at) {
+        Ok(timestamp) => timestamp,
+        Err(e) => return Ok(Err(e)),
+    };
+    let expires_at = match expires_at.as_deref().map(parse).transpose() {
+        Ok(timestamp) => timestamp,
+        Err(e) => return Ok(Err(e)),
+    };
+    
+    Ok(Ok(FileInfo {
+        filename: row.get(1)?,
+        size: size as u64,
+        content_type: row.get(3)?,
+        encrypted: row.get(4)?,
+        uploaded_at,
+        storage_key: row.get(6)?,
+        expires_at,
+        downloads_remaining: row.get(8)?,
+        owner: row.get(9)?,
+        id,
+    }))
+}
+
+/// Builds a `Share` from a row selected with `SHARE_COLUMNS`
+fn row_to_share(row: &Row<'_>) -> rusqlite::Result<Result<Share, StoreError>> {
+    let id: String = row.get(0)?;
+    let created_at: String = row.get(4)?;
+    let expires_at: Option<String> = row.get(5)?;
+    
+    let record = format!("share {}", id);
+    let parse = |timestamp: &str| parse_timestamp(&record, timestamp);
+    let created_at = match parse(&created_at) {
+        Ok(timestamp) => timestamp,
+        Err(e) => return Ok(Err(e)),
+    };
+    let expires_at = match expires_at.as_deref().map(parse).transpose() {
+        Ok(timestamp) => timestamp,
+        Err(e) => return Ok(Err(e)),
+    };
+    
+    Ok(Ok(Share {
+        file_id: row.get(1)?,
+        token_hash: row.get(2)?,
+        password_hash: row.get(3)?,
+        created_at,
+        expires_at,
+        id,
+    }))
+}
+
+/// Builds a `User` from a row selected with `USER_COLUMNS`
+fn row_to_user(row: &Row<'_>) -> rusqlite::Result<Result<User, StoreError>> {
+    let id: String = row.get(0)?;
+    let created_at: String = row.get(3)?;
+    
+    let created_at = match parse_timestamp(&format!("user {}", id), &created_at) {
+        Ok(timestamp) => timestamp,
+        Err(e) => return Ok(Err(e)),
+    };
+    
+    Ok(Ok(User {
+        username: row.get(1)?,
+        password_hash: row.get(2)?,
+        created_at,
+        id,
+    }))
+}
+
+/// Builds a `Session` from a row selected with `SESSION_COLUMNS`
+fn row_to_session(row: &Row<'_>) -> rusqlite::Result<Result<Session, StoreError>> {
+    let user_id: String = row.get(1)?;
+    let created_at: String = row.get(2)?;
+    let expires_at: String = row.get(3)?;
+    
+    let record = format!("session of user {}", user_id);
+    let parse = |timestamp: &str| parse_timestamp(&record, timestamp);
+    let (created_at, expires_at) = match (parse(&created_at), parse(&expires_at)) {
+        (Ok(created_at), Ok(expires_at)) => (created_at, expires_at),
+        (Err(e), _) | (_, Err(e)) => return Ok(Err(e)),
+    };
+    
+    Ok(Ok(Session {
+        token_hash: row.get(0)?,
+        user_id,
+        created_at,
+        expires_at,
+    }))
+}
+
+/// Builds an `ApiToken` from a row selected with `API_TOKEN_COLUMNS`
+fn row_to_api_token(row: &Row<'_>) -> rusqlite::Result<Result<ApiToken, StoreError>> {
+    let id: String = row.get(0)?;
+    let scopes: String = row.get(4)?;
+    let created_at: String = row.get(5)?;
+    let expires_at: Option<String> = row.get(6)?;
+    
+    let record = format!("API token {}", id);
+    let scopes: Vec<TokenScope> = match serde_json::from_str(&scopes) {
+        Ok(scopes) => scopes,
+        Err(e) => return Ok(Err(StoreError::Corrupt(format!("{} has invalid scopes: {}", record, e)))),
+    };
+    let parse = |timestamp: &str| parse_timestamp(&record, timestamp);
+    let created_at = match parse(&created_at) {
+        Ok(timestamp) => timestamp,
+        Err(e) => return Ok(Err(e)),
+    };
+    let expires_at = match expires_at.as_deref().map(parse).transpose() {
+        Ok(timestamp) => timestamp,
+        Err(e) => return Ok(Err(e)),
+    };
+    
+    Ok(Ok(ApiToken {
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        token_hash: row.get(3)?,
+        scopes,
+        created_at,
+        expires_at,
+        id,
+    }))
+}
+
+/// Builds a `PublicKeyInfo` from a row selected with `PUBLIC_KEY_COLUMNS`
+fn row_to_public_key(row: &Row<'_>) -> rusqlite::Result<Result<PublicKeyInfo, StoreError>> {
+    let id: String = row.get(0)?;
+    let created_at: String = row.get(4)?;
+    
+    let created_at = match parse_timestamp(&format!("public key {}", id), &created_at) {
+        Ok(timestamp) => timestamp,
+        Err(e) => return Ok(Err(e)),
+    };
+    
+    Ok(Ok(PublicKeyInfo {
+        name: row.get(1)?,
+        fingerprint: row.get(2)?,
+        public_key_pem: row.get(3)?,
+        created_at,
+        id,
+    }))
+}
+
+impl FileStore for SqliteFileStore {
+    fn add_file(&self, file_info: FileInfo) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!("INSERT INTO files ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)", FILE_COLUMNS),
+            params![
+                file_info.id,
+                file_info.filename,
+                file_info.size as i64,
+                file_info.content_type,
+                file_info.encrypted,
+                file_info.uploaded_at.to_rfc3339(),
+                file_info.storage_key,
+                file_info.expires_at.map(|timestamp| timestamp.to_rfc3339()),
+                file_info.downloads_remaining,
+                file_info.owner,
+            ],
+        )?;
+        Ok(())
+    }
+    
+    fn get_file(&self, id: &str) -> Result<Option<FileInfo>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!("SELECT {} FROM files WHERE id = ?1", FILE_COLUMNS),
+            params![id],
+            row_to_file_info,
+        )
+        .optional()?
+        .transpose()
+    }
+    
+    fn list_files(&self) -> Result<Vec<FileInfo>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM files ORDER BY rowid", FILE_COLUMNS))?;
+        let rows = stmt.query_map([], row_to_file_info)?;
+        
+        let mut files = Vec::new();
+        for row in rows {
+            files.push(row??);
+        }
+        Ok(files)
+    }
+    
+    fn update_file(&self, file_info: FileInfo) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE files SET filename = ?2, size = ?3, content_type = ?4, encrypted = ?5, uploaded_at = ?6, storage_key = ?7,
+             expires_at = ?8 WHERE id = ?1",
+            params![
+                file_info.id,
+                file_info.filename,
+                file_info.size as i64,
+                file_info.content_type,
+                file_info.encrypted,
+                file_info.uploaded_at.to_rfc3339(),
+                file_info.storage_key,
+                file_info.expires_at.map(|timestamp| timestamp.to_rfc3339()),
+            ],
+        )?;
+        Ok(())
+    }
+    
+    fn claim_download(&self, id: &str) -> Result<DownloadClaim, StoreError> {
+        // The connection lock makes the read and the decrement one step
+        let conn = self.conn.lock().unwrap();
+        let remaining: Option<Option<u32>> = conn.query_row(
+            "SELECT downloads_remaining FROM files WHERE id = ?1",
+            params![id],
+            |row| row.get(0),
+        )
+        .optional()?;
+        
+        let claim = match remaining {
+            Some(None) => DownloadClaim::Unlimited,
+            Some(Some(remaining)) if remaining > 0 => {
+                conn.execute(
+                    "UPDATE files SET downloads_remaining = ?2 WHERE id = ?1",
+                    params![id, remaining - 1],
+                )?;
+                DownloadClaim::Claimed(remaining - 1)
+            },
+            _ => DownloadClaim::Refused,
+        };
+        Ok(claim)
+    }
+    
+    fn remove_file(&self, id: &str) -> Result<(), StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM shares WHERE file_id = ?1", params![id])?;
+        tx.execute("DELETE FROM files WHERE id = ?1", params![id])?;
+        tx.commit()?;
+        Ok(())
+    }
+    
+    fn adopt_unowned_files(&self, owner: &str) -> Result<usize, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let adopted = conn.execute("UPDATE files SET owner = ?1 WHERE owner IS NULL", params![owner])?;
+        Ok(adopted)
+    }
+}
+
+impl ShareStore for SqliteFileStore {
+    fn add_share(&self, share: Share) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!("INSERT INTO shares ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)", SHARE_COLUMNS),
+            params![
+                share.id,
+                share.file_id,
+                share.token_hash,
+                share.password_hash,
+                share.created_at.to_rfc3339(),
+                share.expires_at.map(|timestamp| timestamp.to_rfc3339()),
+            ],
+        )?;
+        Ok(())
+    }
+    
+    fn find_share(&self, token_hash: &str) -> Result<Option<Share>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!("SELECT {} FROM shares WHERE token_hash = ?1", SHARE_COLUMNS),
+            params![token_hash],
+            row_to_share,
+        )
+        .optional()?
+        .transpose()
+    }
+    
+    fn list_shares(&self, file_id: &str) -> Result<Vec<Share>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM shares WHERE file_id = ?1 ORDER BY rowid", SHARE_COLUMNS))?;
+        let rows = stmt.query_map(params![file_id], row_to_share)?;
+        
+        let mut shares = Vec::new();
+        for row in rows {
+            shares.push(row??);
+        }
+        Ok(shares)
+    }
+    
+    fn remove_share(&self, file_id: &str, id: &str) -> Result<bool, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM shares WHERE file_id = ?1 AND id = ?2", params![file_id, id])?;
+        Ok(removed > 0)
+    }
+}
+
+impl UserStore for SqliteFileStore {
+    fn add_user(&self, user: User) -> Result<bool, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let inserted = conn.execute(
+            &format!("INSERT INTO users ({}) VALUES (?1, ?2, ?3, ?4)", USER_COLUMNS),
+            params![
+                user.id,
+                user.username,
+                user.password_hash,
+                user.created_at.to_rfc3339(),
+            ],
+        );
+        
+        // The unique index on usernames settles concurrent registrations
+        match inserted {
+            Ok(_) => Ok(true),
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+    
+    fn get_user(&self, id: &str) -> Result<Option<User>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!("SELECT {} FROM users WHERE id = ?1", USER_COLUMNS),
+            params![id],
+            row_to_user,
+        )
+        .optional()?
+        .transpose()
+    }
+    
+    fn find_user(&self, username: &str) -> Result<Option<User>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!("SELECT {} FROM users WHERE username = ?1", USER_COLUMNS),
+            params![username],
+            row_to_user,
+        )
+        .optional()?
+        .transpose()
+    }
+    
+    fn add_session(&self, session: Session) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!("INSERT INTO sessions ({}) VALUES (?1, ?2, ?3, ?4)", SESSION_COLUMNS),
+            params![
+                session.token_hash,
+                session.user_id,
+                session.created_at.to_rfc3339(),
+                session.expires_at.to_rfc3339(),
+            ],
+        )?;
+        Ok(())
+    }
+    
+    fn find_session(&self, token_hash: &str) -> Result<Option<Session>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!("SELECT {} FROM sessions WHERE token_hash = ?1", SESSION_COLUMNS),
+            params![token_hash],
+            row_to_session,
+        )
+        .optional()?
+        .transpose()
+    }
+    
+    fn remove_session(&self, token_hash: &str) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM sessions WHERE token_hash = ?1", params![token_hash])?;
+        Ok(())
+    }
+    
+    fn remove_expired_sessions(&self) -> Result<usize, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM sessions WHERE julianday(expires_at) <= julianday('now')", [])?;
+        Ok(removed)
+    }
+    
+    fn add_api_token(&self, token: ApiToken) -> Result<(), StoreError> {
+        let scopes = serde_json::to_string(&token.scopes)
+            .map_err(|e| StoreError::Corrupt(format!("Cannot encode scopes of API token {}: {}", token.id, e)))?;
+        
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!("INSERT INTO api_tokens ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)", API_TOKEN_COLUMNS),
+            params![
+                token.id,
+                token.user_id,
+                token.name,
+                token.token_hash,
+                scopes,
+                token.created_at.to_rfc3339(),
+                token.expires_at.map(|timestamp| timestamp.to_rfc3339()),
+            ],
+        )?;
+        Ok(())
+    }
+    
+    fn find_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!("SELECT {} FROM api_tokens WHERE token_hash = ?1", API_TOKEN_COLUMNS),
+            params![token_hash],
+            row_to_api_token,
+        )
+        .optional()?
+        .transpose()
+    }
+    
+    fn list_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM api_tokens WHERE user_id = ?1 ORDER BY rowid", API_TOKEN_COLUMNS))?;
+        let rows = stmt.query_map(params![user_id], row_to_api_token)?;
+        
+        let mut api_tokens = Vec::new();
+        for row in rows {
+            api_tokens.push(row??);
+        }
+        Ok(api_tokens)
+    }
+    
+    fn remove_api_token(&self, user_id: &str, id: &str) -> Result<bool, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM api_tokens WHERE user_id = ?1 AND id = ?2", params![user_id, id])?;
+        Ok(removed > 0)
+    }
+}
+
+impl KeyStore for SqliteFileStore {
+    fn add_key(&self, key_info: PublicKeyInfo) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!("INSERT INTO public_keys ({}) VALUES (?1, ?2, ?3, ?4, ?5)", PUBLIC_KEY_COLUMNS),
+            params![
+                key_info.id,
+                key_info.name,
+                key_info.fingerprint,
+                key_info.public_key_pem,
+                key_info.created_at.to_rfc3339(),
+            ],
+        )?;
+        Ok(())
+    }
+    
+    fn get_key(&self, id: &str) -> Result<Option<PublicKeyInfo>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!("SELECT {} FROM public_keys WHERE id = ?1", PUBLIC_KEY_COLUMNS),
+            params![id],
+            row_to_public_key,
+        )
+        .optional()?
+        .transpose()
+    }
+    
+    fn find_key_by_fingerprint(&self, fingerprint: &str) -> Result<Option<PublicKeyInfo>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!("SELECT {} FROM public_keys WHERE fingerprint = ?1 ORDER BY rowid LIMIT 1", PUBLIC_KEY_COLUMNS),
+            params![fingerprint],
+            row_to_public_key,
+        )
+        .optional()?
+        .transpose()
+    }
+    
+    fn list_keys(&self) -> Result<Vec<PublicKeyInfo>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM public_keys ORDER BY rowid", PUBLIC_KEY_COLUMNS))?;
+        let rows = stmt.query_map([], row_to_public_key)?;
+        
+        let mut keys = Vec::new();
+        for row in rows {
+            keys.push(row??);
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn older_databases_are_migrated_keeping_their_records() {
+        // A database from before file contents moved into the blob store
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute(
+            "INSERT INTO files (id, filename, size, content_type, encrypted, uploaded_at, path)
+             VALUES ('old', 'a.txt', 3, NULL, 1, '2024-01-01T00:00:00+00:00', './data/encrypted/old')",
+            [],
+        ).unwrap();
+        
+        let store = SqliteFileStore::init(conn).unwrap();
+        let file_info = store.get_file("old").unwrap().unwrap();
+        assert_eq!(file_info.storage_key, "encrypted/old");
+        assert_eq!((file_info.expires_at, file_info.downloads_remaining, file_info.owner), (None, None, None));
+        
+        // Every later table is there
+        let user = User::new("alice".to_string(), "hash".to_string());
+        assert!(store.add_user(user.clone()).unwrap());
+        store.add_api_token(ApiToken::new(user.id.clone(), "ci".to_string(), "hash".to_string(), vec![TokenScope::Read], None)).unwrap();
+        assert_eq!(store.list_api_tokens(&user.id).unwrap().len(), 1);
+        assert_eq!(store.adopt_unowned_files(&user.id).unwrap(), 1);
+        
+        let version: usize = store.conn.lock().unwrap().pragma_query_value(None, "user_version", |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+    
+    #[test]
+    fn current_databases_are_left_alone() {
+        let path = std::env::temp_dir().join(format!("silentlock-migrate-{}.db", uuid::Uuid::new_v4()));
+        let user = User::new("alice".to_string(), "hash".to_string());
+        {
+            let store = SqliteFileStore::open(&path).unwrap();
+            store.add_user(user.clone()).unwrap();
+        }
+        
+        let store = SqliteFileStore::open(&path).unwrap();
+        assert_eq!(store.find_user("alice").unwrap().map(|u| u.id), Some(user.id));
+        
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
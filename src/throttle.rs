@@ -0,0 +1,479 @@
+//! Throttling of failed attempts to open encrypted files.
+//!
+//! Every wrong passphrase, key or share password counts against both the file
+//! and the client that sent it. Each failure doubles the wait before the next
+//! attempt is accepted, and once either has failed `max_failures` times in a
+//! row it is locked out for `lockout`, which is logged as a security event.
+//! Attempts still running count as failures, so parallel requests get no more
+//! guesses than sequential ones. Counters live in memory, so a restart clears
+//! them.
+
+use actix_web::{http::header, HttpRequest, HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
+use log::warn;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Log target of security events, so they can be filtered or routed on their own
+pub const SECURITY_LOG: &str = "silentlock::security";
+
+/// Wait after the first failure, doubled by every further one
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between attempts short of a lockout
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Number of tracked files or clients above which forgotten ones are swept out
+const SWEEP_THRESHOLD: usize = 10_000;
+
+#[derive(Error, Debug)]
+#[error("{0} must be a positive whole number")]
+pub struct InvalidSetting(pub &'static str);
+
+/// Limits on failed attempts
+#[derive(Debug, Clone)]
+pub struct ThrottleConfig {
+    /// Failures in a row after which a file or client is locked out
+    pub max_failures: u32,
+    
+    /// How long a lockout lasts; failures older than this are forgotten
+    pub lockout: Duration,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            lockout: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+impl ThrottleConfig {
+    /// Reads the configuration from the environment
+    ///
+    /// Uses `SILENTLOCK_DECRYPT_MAX_FAILURES` and
+    /// `SILENTLOCK_DECRYPT_LOCKOUT_SECS`, keeping the defaults of 5 failures
+    /// and 15 minutes for unset ones.
+    pub fn from_env() -> Result<Self, InvalidSetting> {
+        let var = |name: &'static str| match std::env::var(name) {
+            Ok(value) => value.trim().parse::<u32>()
+                .ok()
+                .filter(|value| *value > 0)
+                .map(Some)
+                .ok_or(InvalidSetting(name)),
+            Err(_) => Ok(None),
+        };
+        
+        let defaults = Self::default();
+        Ok(Self {
+            max_failures: var("SILENTLOCK_DECRYPT_MAX_FAILURES")?.unwrap_or(defaults.max_failures),
+            lockout: var("SILENTLOCK_DECRYPT_LOCKOUT_SECS")?
+                .map(|secs| Duration::from_secs(secs.into()))
+                .unwrap_or(defaults.lockout),
+        })
+    }
+}
+
+/// Rejection of an attempt that came too soon after earlier failures
+#[derive(Debug)]
+pub struct Throttled {
+    /// How long until another attempt is accepted
+    pub retry_after: Duration,
+}
+
+impl Throttled {
+    /// Whole seconds to wait, rounded up so a retry is never early
+    fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0)
+    }
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.retry_after_secs();
+        write!(f, "Too many failed attempts, try again in {} second{}", secs, if secs == 1 { "" } else { "s" })
+    }
+}
+
+impl ResponseError for Throttled {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+    
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, self.retry_after_secs()))
+            .body(self.to_string())
+    }
+}
+
+/// Failed attempts on one file or from one client
+#[derive(Debug, Default)]
+struct Attempts {
+    /// Failures in a row since the last lockout or success
+    failures: u32,
+    
+    /// When the last failure happened
+    last_failure: Option<Instant>,
+    
+    /// End of the current lockout, if there is one
+    locked_until: Option<Instant>,
+    
+    /// Attempts let through whose outcome is not known yet
+    in_flight: u32,
+}
+
+impl Attempts {
+    /// How long until another attempt is accepted, if it has to wait
+    ///
+    /// Attempts in flight count as if they had failed: without standing
+    /// failures no more may run at once than would start a lockout, and after
+    /// a failure they run one at a time.
+    fn wait(&self, now: Instant, config: &ThrottleConfig) -> Option<Duration> {
+        if let Some(locked_until) = self.locked_until.filter(|until| *until > now) {
+            return Some(locked_until - now);
+        }
+        
+        if self.is_forgotten(now, config) {
+            return (self.in_flight >= config.max_failures).then_some(BASE_DELAY);
+        }
+        if self.in_flight > 0 {
+            return Some(backoff(self.failures));
+        }
+        
+        let ready = self.last_failure? + backoff(self.failures);
+        (ready > now).then(|| ready - now)
+    }
+    
+    /// Counts a failure, returning true if it starts a lockout
+    fn fail(&mut self, now: Instant, config: &ThrottleConfig) -> bool {
+        let in_flight = self.in_flight;
+        
+        // Start over once a lockout has ended or earlier failures are old enough to forget
+        if self.is_forgotten(now, config) {
+            *self = Self {
+                in_flight,
+                ..Self::default()
+            };
+        }
+        
+        self.failures += 1;
+        self.last_failure = Some(now);
+        
+        if self.failures < config.max_failures {
+            return false;
+        }
+        *self = Self {
+            locked_until: Some(now + config.lockout),
+            in_flight,
+            ..Self::default()
+        };
+        true
+    }
+    
+    /// Whether no failure or lockout matters any more
+    fn is_forgotten(&self, now: Instant, config: &ThrottleConfig) -> bool {
+        let locked = self.locked_until.is_some_and(|until| until > now);
+        let recent = self.last_failure.is_some_and(|last| now.duration_since(last) < config.lockout);
+        !locked && !recent
+    }
+}
+
+/// Wait before the next attempt after `failures` failures in a row
+fn backoff(failures: u32) -> Duration {
+    match failures {
+        0 => Duration::ZERO,
+        n => BASE_DELAY.saturating_mul(1 << (n - 1).min(16)).min(MAX_DELAY),
+    }
+}
+
+/// Counters of failed attempts per file and per client
+pub struct DecryptThrottle {
+    config: ThrottleConfig,
+    files: Mutex<HashMap<String, Attempts>>,
+    clients: Mutex<HashMap<String, Attempts>>,
+}
+
+impl DecryptThrottle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            config,
+            files: Mutex::new(HashMap::new()),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+    
+    /// Lets an attempt on a file from a client through, unless either has to wait
+    ///
+    /// The attempt is reserved under the same locks as the check, so parallel
+    /// requests cannot all pass it. It counts as in flight until it is settled.
+    pub fn check(&self, file_id: &str, client: &str) -> Result<Attempt<'_>, Throttled> {
+        let now = Instant::now();
+        let mut files = self.files.lock().unwrap();
+        let mut clients = self.clients.lock().unwrap();
+        
+        let file_wait = files.get(file_id).and_then(|attempts| attempts.wait(now, &self.config));
+        let client_wait = clients.get(client).and_then(|attempts| attempts.wait(now, &self.config));
+        if let Some(retry_after) = file_wait.max(client_wait) {
+            return Err(Throttled {
+                retry_after,
+            });
+        }
+        
+        self.entry(&mut files, file_id, now).in_flight += 1;
+        self.entry(&mut clients, client, now).in_flight += 1;
+        Ok(Attempt {
+            throttle: self,
+            file_id: file_id.to_string(),
+            client: client.to_string(),
+            settled: false,
+        })
+    }
+    
+    /// Looks up the counters under `key`, sweeping out forgotten ones when there are many
+    fn entry<'a>(&self, counters: &'a mut HashMap<String, Attempts>, key: &str, now: Instant) -> &'a mut Attempts {
+        if counters.len() >= SWEEP_THRESHOLD {
+            counters.retain(|_, attempts| attempts.in_flight > 0 || !attempts.is_forgotten(now, &self.config));
+        }
+        counters.entry(key.to_string()).or_default()
+    }
+    
+    /// Ends an attempt under `key`, counting it as a failure if `failed`
+    ///
+    /// Returns true if the failure starts a lockout.
+    fn settle(&self, counters: &Mutex<HashMap<String, Attempts>>, key: &str, failed: bool) -> bool {
+        let now = Instant::now();
+        let mut counters = counters.lock().unwrap();
+        let attempts = self.entry(&mut counters, key, now);
+        attempts.in_flight = attempts.in_flight.saturating_sub(1);
+        failed && attempts.fail(now, &self.config)
+    }
+}
+
+/// An attempt let through by `DecryptThrottle::check`
+///
+/// Settle it with `record_success` or `record_failure`. Dropping it unsettled,
+/// for example after a storage error, releases it without counting anything.
+#[must_use]
+pub struct Attempt<'a> {
+    throttle: &'a DecryptThrottle,
+    file_id: String,
+    client: String,
+    settled: bool,
+}
+
+impl Attempt<'_> {
+    /// Counts a wrong credential for the file from the client
+    pub fn record_failure(mut self) {
+        let throttle = self.throttle;
+        let lockout = throttle.config.lockout.as_secs();
+        self.settled = true;
+        
+        if throttle.settle(&throttle.files, &self.file_id, true) {
+            warn!(
+                target: SECURITY_LOG,
+                "File {} locked for {} seconds after {} failed attempts to open it; last one from {}",
+                self.file_id, lockout, throttle.config.max_failures, self.client,
+            );
+        }
+        if throttle.settle(&throttle.clients, &self.client, true) {
+            warn!(
+                target: SECURITY_LOG,
+                "Client {} locked out for {} seconds after {} failed attempts to open files; last one on {}",
+                self.client, lockout, throttle.config.max_failures, self.file_id,
+            );
+        }
+    }
+    
+    /// Clears the failures of the file once the right credential has opened it
+    ///
+    /// The client's failures stand, so opening a file of one's own does not
+    /// buy more guesses at others.
+    pub fn record_success(mut self) {
+        let throttle = self.throttle;
+        self.settled = true;
+        
+        let mut files = throttle.files.lock().unwrap();
+        if let Some(attempts) = files.get_mut(&self.file_id) {
+            attempts.in_flight = attempts.in_flight.saturating_sub(1);
+            if attempts.in_flight == 0 {
+                files.remove(&self.file_id);
+            } else {
+                *attempts = Attempts {
+                    in_flight: attempts.in_flight,
+                    ..Attempts::default()
+                };
+            }
+        }
+        drop(files);
+        throttle.settle(&throttle.clients, &self.client, false);
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.throttle.settle(&self.throttle.files, &self.file_id, false);
+            self.throttle.settle(&self.throttle.clients, &self.client, false);
+        }
+    }
+}
+
+/// Identifies the client of a request by its IP address
+///
+/// Forwarding headers are ignored, since clients could set them to dodge the
+/// per-client limit; behind a reverse proxy all clients therefore share one.
+pub fn client_id(req: &HttpRequest) -> String {
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn config() -> ThrottleConfig {
+        ThrottleConfig {
+            max_failures: 3,
+            lockout: Duration::from_secs(60),
+        }
+    }
+    
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        assert_eq!(backoff(0), Duration::ZERO);
+        assert_eq!(backoff(1), BASE_DELAY);
+        assert_eq!(backoff(2), BASE_DELAY * 2);
+        assert_eq!(backoff(4), BASE_DELAY * 8);
+        assert_eq!(backoff(20), MAX_DELAY);
+        assert_eq!(backoff(u32::MAX), MAX_DELAY);
+    }
+    
+    #[test]
+    fn failures_back_off_then_lock_out() {
+        let config = config();
+        let start = Instant::now();
+        let mut attempts = Attempts::default();
+        assert_eq!(attempts.wait(start, &config), None);
+        
+        assert!(!attempts.fail(start, &config));
+        assert_eq!(attempts.wait(start, &config), Some(BASE_DELAY));
+        assert_eq!(attempts.wait(start + BASE_DELAY, &config), None);
+        
+        let second = start + BASE_DELAY;
+        assert!(!attempts.fail(second, &config));
+        assert_eq!(attempts.wait(second, &config), Some(BASE_DELAY * 2));
+        
+        let third = second + BASE_DELAY * 2;
+        assert!(attempts.fail(third, &config));
+        assert_eq!(attempts.wait(third, &config), Some(config.lockout));
+        assert_eq!(attempts.wait(third + config.lockout, &config), None);
+        
+        // The lockout starts the count over
+        assert!(!attempts.fail(third + config.lockout, &config));
+        assert_eq!(attempts.failures, 1);
+    }
+    
+    #[test]
+    fn old_failures_are_forgotten() {
+        let config = config();
+        let start = Instant::now();
+        let mut attempts = Attempts::default();
+        attempts.fail(start, &config);
+        attempts.fail(start, &config);
+        
+        let later = start + config.lockout;
+        assert_eq!(attempts.wait(later, &config), None);
+        assert!(!attempts.fail(later, &config));
+        assert_eq!(attempts.failures, 1);
+    }
+    
+    #[test]
+    fn failure_makes_the_next_attempt_wait() {
+        let throttle = DecryptThrottle::new(config());
+        throttle.check("file", "client").unwrap().record_failure();
+        
+        let throttled = throttle.check("file", "other client").err().unwrap();
+        assert!(throttled.retry_after <= BASE_DELAY);
+        assert!(throttle.check("other file", "client").is_err());
+        assert!(throttle.check("other file", "other client").is_ok());
+    }
+    
+    #[test]
+    fn parallel_attempts_are_limited() {
+        let throttle = DecryptThrottle::new(config());
+        let running: Vec<_> = (0..3)
+            .map(|i| throttle.check("file", &format!("client {}", i)).unwrap())
+            .collect();
+        assert!(throttle.check("file", "client 3").is_err());
+        
+        // A failure among them still makes the next attempt wait
+        let mut running = running.into_iter();
+        running.next().unwrap().record_failure();
+        drop(running);
+        assert!(throttle.check("file", "client 3").is_err());
+    }
+    
+    #[test]
+    fn in_flight_attempt_after_a_failure_blocks_others() {
+        let throttle = DecryptThrottle::new(ThrottleConfig {
+            max_failures: 10,
+            ..config()
+        });
+        throttle.check("file", "a").unwrap().record_failure();
+        throttle.files.lock().unwrap().get_mut("file").unwrap().last_failure = Some(Instant::now() - BASE_DELAY);
+        
+        let attempt = throttle.check("file", "b").unwrap();
+        assert!(throttle.check("file", "c").is_err());
+        drop(attempt);
+        assert!(throttle.check("file", "c").is_ok());
+    }
+    
+    #[test]
+    fn success_clears_the_file_but_not_the_client() {
+        let throttle = DecryptThrottle::new(config());
+        throttle.check("file", "client").unwrap().record_failure();
+        throttle.files.lock().unwrap().get_mut("file").unwrap().last_failure = Some(Instant::now() - BASE_DELAY);
+        throttle.clients.lock().unwrap().get_mut("client").unwrap().last_failure = Some(Instant::now() - BASE_DELAY);
+        
+        throttle.check("file", "client").unwrap().record_success();
+        assert!(!throttle.files.lock().unwrap().contains_key("file"));
+        assert_eq!(throttle.clients.lock().unwrap()["client"].failures, 1);
+        assert_eq!(throttle.clients.lock().unwrap()["client"].in_flight, 0);
+    }
+    
+    #[test]
+    fn dropped_attempt_counts_nothing() {
+        let throttle = DecryptThrottle::new(config());
+        for _ in 0..10 {
+            drop(throttle.check("file", "client").unwrap());
+        }
+        
+        let files = throttle.files.lock().unwrap();
+        assert_eq!(files["file"].failures, 0);
+        assert_eq!(files["file"].in_flight, 0);
+    }
+    
+    #[test]
+    fn lockout_after_max_failures() {
+        let config = config();
+        let throttle = DecryptThrottle::new(config.clone());
+        for _ in 0..config.max_failures {
+            throttle.check("file", "client").unwrap().record_failure();
+            for counters in [&throttle.files, &throttle.clients] {
+                for attempts in counters.lock().unwrap().values_mut() {
+                    attempts.last_failure = attempts.last_failure.map(|last| last - BASE_DELAY * 4);
+                }
+            }
+        }
+        
+        let throttled = throttle.check("file", "other client").err().unwrap();
+        assert!(throttled.retry_after > config.lockout / 2);
+        assert!(throttle.check("other file", "client").is_err());
+    }
+}
@@ -1,8 +1,15 @@
 use std::path::{Path, PathBuf};
-use std::fs::{self, File};
-use std::io::{self, Read, Write};
+use std::io::{self, BufWriter, Read, Write};
+use actix_web::web::Bytes;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures::Stream;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
 use uuid::Uuid;
-use log::{error, warn};
+use crate::blob::{BlobError, BlobStore};
 use crate::models::FileInfo;
 
 /// Sanitizes a filename to prevent directory traversal and other security issues
@@ -15,17 +22,12 @@ pub fn sanitize_filename(filename: &str) -> String {
     
     // Replace any potentially problematic characters
     let filename = filename
-        .replace('/', "_")
-        .replace('\\', "_")
-        .replace(':', "_")
-        .replace('*', "_")
-        .replace('?', "_")
-        .replace('"', "_")
-        .replace('<', "_")
-        .replace('>', "_")
-        .replace('|', "_");
+        .replace(['/', '\\', ':', '*', '?', '"', '<', '>', '|'], "_");
     
-    filename.to_string()
+    // Control characters cannot appear in a response header
+    filename.chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .collect()
 }
 
 /// Generates a unique filename for storing uploaded files
@@ -46,91 +48,185 @@ pub fn generate_unique_filename(original_filename: &str) -> String {
     }
 }
 
-/// Gets the path for storing an uploaded file
-pub fn get_upload_path(filename: &str) -> PathBuf {
-    let unique_filename = generate_unique_filename(filename);
-    PathBuf::from("./data/uploads").join(unique_filename)
+/// Gets the blob store key for an uploaded file
+pub fn upload_key(filename: &str) -> String {
+    format!("uploads/{}", generate_unique_filename(filename))
 }
 
-/// Gets the path for storing an encrypted file
-pub fn get_encrypted_path(filename: &str) -> PathBuf {
-    let unique_filename = generate_unique_filename(filename);
-    PathBuf::from("./data/encrypted").join(unique_filename)
+/// Gets the blob store key for an encrypted file
+pub fn encrypted_key(filename: &str) -> String {
+    format!("encrypted/{}", generate_unique_filename(filename))
 }
 
-/// Validates a file size to ensure it's within acceptable limits
-pub fn validate_file_size(size: u64) -> bool {
-    // Limit file size to 100MB
-    const MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
-    size <= MAX_FILE_SIZE
+/// Gets a path for a short-lived local working file
+///
+/// Used to stage plain uploads and ciphertext; plaintext being encrypted never lands here.
+pub fn get_temp_path(filename: &str) -> PathBuf {
+    PathBuf::from("./data/tmp").join(generate_unique_filename(filename))
 }
 
-/// Validates a file's content type
-pub fn validate_content_type(content_type: &str) -> bool {
-    // This is a basic implementation
-    // In a production environment, you might want to be more specific
-    // about which content types are allowed
-    !content_type.contains("application/x-msdownload") &&
-    !content_type.contains("application/x-msdos-program") &&
-    !content_type.contains("application/x-msdos-windows") &&
-    !content_type.contains("application/x-download")
+/// Blocking `Read` adapter over chunks sent from an async task
+///
+/// Lets the synchronous encryption code consume a request body as it
+/// arrives. The stream ends when the sender is dropped; an error sent
+/// through the channel is returned from `read`.
+pub struct ChunkReader {
+    receiver: mpsc::Receiver<io::Result<Bytes>>,
+    
+    /// Unread part of the current chunk
+    chunk: Bytes,
 }
 
-/// Saves an in-memory file to disk
-pub fn save_file_to_disk(
-    data: &[u8],
-    path: &Path,
-) -> io::Result<()> {
-    // Create parent directories if they don't exist
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
+impl ChunkReader {
+    pub fn new(receiver: mpsc::Receiver<io::Result<Bytes>>) -> Self {
+        Self {
+            receiver,
+            chunk: Bytes::new(),
+        }
     }
-    
-    // Write the file
-    let mut file = File::create(path)?;
-    file.write_all(data)?;
-    
-    Ok(())
 }
 
-/// In-memory storage for file information
-/// In a production environment, this would be replaced with a database
-pub struct FileStore {
-    files: std::sync::RwLock<Vec<FileInfo>>,
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+        
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
 }
 
-impl FileStore {
-    pub fn new() -> Self {
-        Self {
-            files: std::sync::RwLock::new(Vec::new()),
+/// Size of the buffers read by `reader_stream`
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Turns a blocking reader into a stream of chunks for a response body
+///
+/// The reader is drained on a blocking thread, which stops early once the
+/// stream is dropped, e.g. because the client went away.
+pub fn reader_stream(mut reader: Box<dyn Read + Send>) -> impl Stream<Item = io::Result<Bytes>> {
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || loop {
+        let mut buf = vec![0u8; STREAM_BUFFER_SIZE];
+        let item = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                buf.truncate(n);
+                Ok(Bytes::from(buf))
+            },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+        };
+        let failed = item.is_err();
+        if sender.blocking_send(item).is_err() || failed {
+            break;
         }
-    }
+    });
     
-    pub fn add_file(&self, file_info: FileInfo) {
-        let mut files = self.files.write().unwrap();
-        files.push(file_info);
-    }
-    
-    pub fn get_file(&self, id: &str) -> Option<FileInfo> {
-        let files = self.files.read().unwrap();
-        files.iter().find(|f| f.id == id).cloned()
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    })
+}
+
+/// Blocking `Write` adapter that sends chunks to an async task
+///
+/// The counterpart of `ChunkReader`, letting synchronous decryption code feed
+/// a response body. Writes fail once the receiving end has been dropped.
+struct ChunkWriter {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender.blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
     }
     
-    pub fn list_files(&self) -> Vec<FileInfo> {
-        let files = self.files.read().unwrap();
-        files.clone()
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
-    
-    pub fn remove_file(&self, id: &str) {
-        let mut files = self.files.write().unwrap();
-        if let Some(pos) = files.iter().position(|f| f.id == id) {
-            files.remove(pos);
+}
+
+/// Runs `write` on a blocking thread and streams its output as a response body
+///
+/// An error from `write` ends the stream with that error, so the client sees
+/// a broken download rather than a short one. Writes fail once the stream is
+/// dropped, e.g. because the client went away.
+pub fn writer_stream<F>(write: F) -> impl Stream<Item = io::Result<Bytes>>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = BufWriter::with_capacity(STREAM_BUFFER_SIZE, ChunkWriter {
+            sender: sender.clone(),
+        });
+        let result = write(&mut writer).and_then(|()| writer.flush());
+        drop(writer);
+        if let Err(e) = result {
+            let _ = sender.blocking_send(Err(e));
         }
-    }
+    });
+    
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    })
 }
 
-impl Default for FileStore {
-    fn default() -> Self {
-        Self::new()
+/// Deletes a file's stored contents once the file itself is gone
+///
+/// Unencrypted contents are overwritten first, as for a secure delete.
+pub fn discard_file_contents(blob_store: &dyn BlobStore, file_info: &FileInfo) -> Result<(), BlobError> {
+    if file_info.encrypted {
+        blob_store.delete(&file_info.storage_key)
+    } else {
+        blob_store.secure_delete(&file_info.storage_key)
     }
 }
+
+/// Encodes bytes as lowercase hex
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Number of random bytes in share link and session tokens
+const TOKEN_LEN: usize = 32;
+
+/// Generates a new random token, returning it with the hash that is stored
+pub fn generate_token() -> (String, String) {
+    let mut bytes = [0u8; TOKEN_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let token_hash = hash_token(&token);
+    (token, token_hash)
+}
+
+/// Hashes a token for lookup; the token itself is never stored
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Largest file accepted for upload, 100MB
+pub const MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Validates a file size to ensure it's within acceptable limits
+pub fn validate_file_size(size: u64) -> bool {
+    size <= MAX_FILE_SIZE
+}
+
+/// Validates a file's content type
+pub fn validate_content_type(content_type: &str) -> bool {
+    // This is a basic implementation
+    // In a production environment, you might want to be more specific
+    // about which content types are allowed
+    !content_type.contains("application/x-msdownload") &&
+    !content_type.contains("application/x-msdos-program") &&
+    !content_type.contains("application/x-msdos-windows") &&
+    !content_type.contains("application/x-download")
+}
+
@@ -0,0 +1,113 @@
+//! SilentLock encryption for the browser.
+//!
+//! Builds the `silentlock` container format for WebAssembly so the web UI can
+//! seal files before upload and open them after download. The passphrase and
+//! plaintext never leave the browser, and the containers written here are the
+//! same ones the server writes.
+//!
+//! Build with `wasm-pack build wasm --target web --out-dir ../static/pkg`.
+
+use chrono::Utc;
+use silentlock::{decrypt_stream, encrypt_stream, CipherAlgorithm, EncryptionError, FileMetadata, Identity, Recipient};
+use wasm_bindgen::prelude::*;
+
+/// Converts an encryption error into an exception for JavaScript
+fn js_error(e: EncryptionError) -> JsError {
+    match e {
+        EncryptionError::Decryption(_) => JsError::new("Decryption failed, possibly wrong passphrase"),
+        EncryptionError::Format(_) => JsError::new("File is not a valid encrypted container"),
+        e => JsError::new(&e.to_string()),
+    }
+}
+
+/// Encrypts `data` with a passphrase into a SilentLock container
+///
+/// The filename and content type are sealed into the container's metadata.
+/// `algorithm` takes the same names as the server API and defaults to
+/// AES-256-GCM.
+#[wasm_bindgen]
+pub fn encrypt(
+    data: &[u8],
+    passphrase: &str,
+    filename: &str,
+    content_type: Option<String>,
+    algorithm: Option<String>,
+) -> Result<Vec<u8>, JsError> {
+    if passphrase.is_empty() {
+        return Err(JsError::new("A passphrase is required"));
+    }
+
+    // Parse the algorithm from its API name
+    let algorithm = match algorithm {
+        Some(name) => serde_json::from_value::<CipherAlgorithm>(serde_json::Value::String(name))
+            .map_err(|_| JsError::new("Unknown algorithm"))?,
+        None => CipherAlgorithm::default(),
+    };
+
+    let metadata = FileMetadata {
+        filename: filename.to_string(),
+        content_type: content_type.filter(|ct| !ct.is_empty()),
+        uploaded_at: Utc::now(),
+    };
+
+    // Encrypt into a buffer sized for the payload plus header and tags
+    let mut output = Vec::with_capacity(data.len() + 4096);
+    encrypt_stream(
+        &mut &data[..],
+        &mut output,
+        &[Recipient::Passphrase(passphrase.to_string())],
+        algorithm,
+        Some(&metadata),
+    )
+    .map_err(js_error)?;
+
+    Ok(output)
+}
+
+/// A file opened from a container
+#[wasm_bindgen]
+pub struct DecryptedFile {
+    data: Vec<u8>,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+#[wasm_bindgen]
+impl DecryptedFile {
+    /// The decrypted contents
+    #[wasm_bindgen(getter)]
+    pub fn data(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    /// Original filename, if the container carries metadata
+    #[wasm_bindgen(getter)]
+    pub fn filename(&self) -> Option<String> {
+        self.filename.clone()
+    }
+
+    /// Original content type, if the container carries one
+    #[wasm_bindgen(getter, js_name = contentType)]
+    pub fn content_type(&self) -> Option<String> {
+        self.content_type.clone()
+    }
+}
+
+/// Decrypts a SilentLock or passphrase-protected age container
+#[wasm_bindgen]
+pub fn decrypt(data: &[u8], passphrase: &str) -> Result<DecryptedFile, JsError> {
+    let mut output = Vec::with_capacity(data.len());
+    let metadata = decrypt_stream(&mut &data[..], &mut output, &Identity::Passphrase(passphrase.to_string()))
+        .map_err(js_error)?;
+
+    let (filename, content_type) = match metadata {
+        Some(metadata) => (Some(metadata.filename), metadata.content_type),
+        None => (None, None),
+    };
+
+    Ok(DecryptedFile {
+        data: output,
+        filename,
+        content_type,
+    })
+}